@@ -1,29 +1,537 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::mem;
 
 use crate::compiler::parser::ASTNode;
+use crate::compiler::regalloc;
+use crate::compiler::types::Type;
+use crate::vm::assembler::quote_string;
+use crate::vm::machine::VmConfig;
+use crate::vm::value::{Value, ValueType};
+
+/// A program the code generator cannot translate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompileError {
+    UndefinedVariable(String),
+    UnknownLoopLabel(String),
+    LoopControlOutsideLoop,
+    UnsupportedOperator(String),
+    UnsupportedExpression(String),
+    TooFewRegisters { available: usize, required: usize },
+    OutOfSpillSlots { available: usize },
+    TooManyVariables { available: usize },
+    UnsupportedOperands { op: String, types: Vec<Type> },
+    AssignmentTypeMismatch { name: String, expected: Type, found: Type },
+    IntegerOutOfRange { value: i128, ty: ValueType },
+    InvalidCast { from: Type, to: ValueType },
+    UnknownMethod { ty: Type, method: String },
+    WrongArgumentCount { name: String, expected: usize, found: usize },
+    UntypedEmptyArray,
+    UndefinedFunction(String),
+    ReturnOutsideFunction,
+    ReturnTypeMismatch { name: String, expected: Type, found: Type },
+    MissingReturn { name: String, ty: Type },
+    FunctionTooLarge { name: String, available: usize },
+    NotCallable(Type),
+    InvalidInstruction(String),
+    UnknownLabel(String),
+    UnallocatedRegister(u32),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::UndefinedVariable(name) => write!(f, "Undefined variable: {}", name),
+            CompileError::UnknownLoopLabel(label) => write!(f, "Unknown loop label: '{}", label),
+            CompileError::LoopControlOutsideLoop => write!(f, "break or continue outside of a loop"),
+            CompileError::UnsupportedOperator(op) => write!(f, "Unsupported operator: {}", op),
+            CompileError::UnsupportedExpression(expr) => write!(f, "Unsupported expression: {}", expr),
+            CompileError::TooFewRegisters { available, required } => {
+                write!(f, "Register allocation needs more than {} registers, VM has {}", required, available)
+            }
+            CompileError::OutOfSpillSlots { available } => {
+                write!(f, "Out of spill slots: program needs more than {} memory words", available)
+            }
+            CompileError::TooManyVariables { available } => {
+                write!(f, "Too many variables: only {} can be kept in registers", available)
+            }
+            CompileError::UnsupportedOperands { op, types } => {
+                let types: Vec<String> = types.iter().map(Type::to_string).collect();
+                write!(f, "Unsupported operand type(s) for {}: {}", op, types.join(" and "))
+            }
+            CompileError::AssignmentTypeMismatch { name, expected, found } => {
+                write!(f, "Cannot assign {} to {} of type {}", found, name, expected)
+            }
+            CompileError::IntegerOutOfRange { value, ty } => {
+                write!(f, "Integer literal {} out of range for {}", value, ty)
+            }
+            CompileError::InvalidCast { from, to } => write!(f, "Cannot cast {} to {}", from, to),
+            CompileError::UnknownMethod { ty, method } => write!(f, "No method {} on type {}", method, ty),
+            CompileError::WrongArgumentCount { name, expected, found } => {
+                write!(f, "{} takes {} argument(s) but {} were given", name, expected, found)
+            }
+            CompileError::UntypedEmptyArray => {
+                write!(f, "Cannot infer the element type of an empty array; annotate it, as in `a: [i32] = []`")
+            }
+            CompileError::UndefinedFunction(name) => write!(f, "Undefined function: {}", name),
+            CompileError::ReturnOutsideFunction => write!(f, "return outside of a function"),
+            CompileError::ReturnTypeMismatch { name, expected, found } => {
+                write!(f, "Function {} returns {}, not {}", name, expected, found)
+            }
+            CompileError::MissingReturn { name, ty } => {
+                write!(f, "Function {} can end without returning a value of type {}", name, ty)
+            }
+            CompileError::FunctionTooLarge { name, available } => {
+                write!(f, "Function {} needs more than the {} registers of a call", name, available)
+            }
+            CompileError::NotCallable(ty) => write!(f, "Cannot call a value of type {}", ty),
+            CompileError::InvalidInstruction(line) => write!(f, "Invalid instruction: {}", line),
+            CompileError::UnknownLabel(label) => write!(f, "Unknown label: {}", label),
+            CompileError::UnallocatedRegister(vreg) => {
+                write!(f, "Virtual register v{} was not allocated a location", vreg)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// A named variable: the virtual register holding it and its type, which
+/// is fixed when the variable is first bound
+#[derive(Debug, Clone)]
+struct Variable {
+    register: u32,
+    ty: Type,
+}
+
+/// A named function's signature and the label its code starts at. The
+/// code is allocated on its own, for the register window each call gets.
+#[derive(Debug, Clone)]
+struct Function {
+    name: String,
+    params: Vec<(String, Type)>,
+    return_type: Type,
+    label: String,
+    defined: bool,      // its code has been generated
+}
+
+impl Function {
+    /// Type of the function as a value
+    fn ty(&self) -> Type {
+        Type::function(self.params.iter().map(|(_, ty)| ty.clone()).collect(), self.return_type.clone())
+    }
+}
+
+/// Where a new closure takes one of its upvalues from
+#[derive(Debug, Clone, Copy)]
+enum Capture {
+    Register(u32),      // a variable of the enclosing function
+    Upvalue(usize),     // an upvalue of the enclosing closure
+}
+
+/// A variable of an enclosing function that a closure reads and writes
+/// through an upvalue
+#[derive(Debug, Clone)]
+struct Upvalue {
+    name: String,
+    ty: Type,
+    capture: Capture,
+}
+
+/// The function whose body is being generated
+struct FunctionContext {
+    name: String,
+    return_type: Option<Type>,  // None until the first return of an anonymous function without `->`
+    upvalues: Vec<Upvalue>,
+    closure: bool,              // anonymous functions capture variables; named ones see only their own
+}
+
+/// Generation state of a function enclosing the one being generated;
+/// `function` is None for the top level
+struct Scope {
+    asm: String,
+    variable_map: HashMap<String, Variable>,
+    loop_stack: Vec<LoopContext>,
+    captured: HashSet<u32>,
+    function: Option<FunctionContext>,
+}
+
+/// What a name refers to inside the function being generated
+enum Binding {
+    Variable(Variable),
+    Upvalue(usize, Type),
+}
+
+/// Jump targets of an enclosing loop, used by break and continue
+struct LoopContext {
+    label: Option<String>,
+    break_label: String,
+    continue_label: String,
+}
+
+/// What an interactive session knows from the inputs generated so far, as
+/// saved by `CodeGenerator::checkpoint`
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    variable_map: HashMap<String, Variable>,
+    register_counter: u32,
+    pinned: HashMap<u32, usize>,
+    functions: Vec<Function>,
+}
 
 pub struct CodeGenerator {
     asm: String,
-    register_counter: u8,
-    variable_map: HashMap<String, u8>,
+    register_counter: u32,
+    variable_map: HashMap<String, Variable>,
     label_counter: u32,
+    loop_stack: Vec<LoopContext>,
+    current_line: Option<usize>,    // source line of the statement being generated
+    pinned: HashMap<u32, usize>,    // variable registers kept from earlier `generate_incremental` calls
+    functions: Vec<Function>,       // every function declared so far, in order
+    function_code: String,          // code of the functions generated since the last link
+    linked: String,                 // function code of earlier `generate_incremental` calls
+    function_labels: HashSet<String>,   // labels given to functions so far
+    function: Option<FunctionContext>,  // function whose body is being generated
+    captured: HashSet<u32>,         // variable registers closures have captured
+    enclosing: Vec<Scope>,          // functions around the one being generated, outermost first
+    target: VmConfig,
+}
+
+impl Default for CodeGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CodeGenerator {
     pub fn new() -> Self {
+        Self::with_config(VmConfig::default())
+    }
+
+    /// Generate code for a VM built with `target`: its register count bounds
+    /// allocation and its data memory holds the spill slots.
+    pub fn with_config(target: VmConfig) -> Self {
         CodeGenerator {
             asm: String::new(),
             register_counter: 0,
             variable_map: HashMap::new(),
             label_counter: 0,
+            loop_stack: Vec::new(),
+            current_line: None,
+            pinned: HashMap::new(),
+            functions: Vec::new(),
+            function_code: String::new(),
+            linked: String::new(),
+            function_labels: HashSet::new(),
+            function: None,
+            captured: HashSet::new(),
+            enclosing: Vec::new(),
+            target,
+        }
+    }
+
+    pub fn generate(&mut self, nodes: &[ASTNode]) -> Result<String, CompileError> {
+        self.declare_functions(nodes);
+        for node in nodes {
+            self.generate_node(node)?;
         }
+        let pinned = pin_captured(&self.captured, &HashMap::new());
+        let (asm, _) = regalloc::allocate_pinned(
+            &self.asm,
+            self.target.num_registers,
+            self.target.memory_size,
+            &pinned,
+            &HashSet::new(),
+        )?;
+        let functions = mem::take(&mut self.function_code);
+        Ok(self.link(&functions, asm))
+    }
+
+    /// Generate code for one input of an interactive session. Variables
+    /// outlive the call: they stay in the physical registers they were given,
+    /// so the code must run on the same VM as earlier inputs. Functions are
+    /// kept too: the code of every later input starts with the code of the
+    /// functions of earlier ones, unchanged, so closures made earlier still
+    /// find their code at the addresses they hold. A function defined again
+    /// gets new code, which calls generated from then on use. On error the
+    /// generator is left as it was before the call.
+    pub fn generate_incremental(&mut self, nodes: &[ASTNode]) -> Result<String, CompileError> {
+        let saved = self.checkpoint();
+        self.asm.clear();
+        self.function_code.clear();
+        self.loop_stack.clear();
+        self.captured.clear();
+        self.declare_functions(nodes);
+
+        let result = nodes.iter().try_for_each(|node| self.generate_node(node)).and_then(|()| {
+            let live_out: HashSet<u32> = self.variable_map.values().map(|var| var.register).collect();
+            regalloc::allocate_pinned(
+                &self.asm,
+                self.target.num_registers,
+                self.target.memory_size,
+                &pin_captured(&self.captured, &self.pinned),
+                &live_out,
+            )
+        });
+        match result {
+            Ok((asm, registers)) => {
+                self.pinned = registers;
+                // Function code is never taken back out, even if the input
+                // fails when it runs, since its closures may outlive it
+                self.linked += &mem::take(&mut self.function_code);
+                let linked = self.linked.clone();
+                Ok(self.link(&linked, asm))
+            }
+            Err(err) => {
+                self.restore(saved);
+                Err(err)
+            }
+        }
+    }
+
+    /// Save the variables and functions of an interactive session
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            variable_map: self.variable_map.clone(),
+            register_counter: self.register_counter,
+            pinned: self.pinned.clone(),
+            functions: self.functions.clone(),
+        }
+    }
+
+    /// Forget the variables and functions defined since `checkpoint` was
+    /// taken, such as those of an input that failed when it ran
+    pub fn restore(&mut self, checkpoint: Checkpoint) {
+        self.variable_map = checkpoint.variable_map;
+        self.register_counter = checkpoint.register_counter;
+        self.pinned = checkpoint.pinned;
+        self.functions = checkpoint.functions;
     }
 
-    pub fn generate(&mut self, nodes: &[ASTNode]) -> String {
+    /// Declare the functions defined at the top level of `nodes`, so that
+    /// code before a definition can call it too
+    fn declare_functions(&mut self, nodes: &[ASTNode]) {
         for node in nodes {
-            self.generate_node(node);
+            let node = match node {
+                ASTNode::Located { statement, .. } => statement,
+                node => node,
+            };
+            if let ASTNode::Function { name, params, return_type, .. } = node {
+                self.declare_function(name, params, return_type.clone().unwrap_or(Type::NIL));
+            }
+        }
+    }
+
+    /// Add a function, or replace the one of the same name
+    fn declare_function(&mut self, name: &str, params: &[(String, Type)], return_type: Type) {
+        let label = self.function_label(name);
+        let function = Function {
+            name: name.to_string(),
+            params: params.to_vec(),
+            return_type,
+            label,
+            defined: false,
+        };
+        match self.functions.iter_mut().find(|f| f.name == name) {
+            Some(existing) => *existing = function,
+            None => self.functions.push(function),
+        }
+    }
+
+    /// The named function `name`
+    fn function(&self, name: &str) -> Option<&Function> {
+        self.functions.iter().find(|f| f.name == name)
+    }
+
+    /// Label for the code of a definition of the function `name`. Labels
+    /// of functions are kept apart from the labels `new_label` makes, such
+    /// as `if_else_0`, which a function could otherwise be named after, and
+    /// a function defined again gets a label of its own.
+    fn function_label(&mut self, name: &str) -> String {
+        let mut label = format!("fn_{}", name);
+        while !self.function_labels.insert(label.clone()) {
+            label = format!("fn{}_{}", self.label_counter, name);
+            self.label_counter += 1;
+        }
+        label
+    }
+
+    /// Put the code of `functions` ahead of `main`, which is where the
+    /// program then starts
+    fn link(&mut self, functions: &str, main: String) -> String {
+        if functions.is_empty() {
+            return main;
+        }
+        let start = self.new_label("main");
+        format!("JMP {}\n{}{}:\n{}", start, functions, start, main)
+    }
+
+    /// Generate a function body into its own code, with its own variables:
+    /// parameters are popped off the stack, where calls push the arguments.
+    /// Closures may also use variables of the enclosing functions, which
+    /// then become upvalues. Returns the code, labelled `label`, and what was
+    /// learnt about the function: its upvalues and, for anonymous functions,
+    /// the return type.
+    fn generate_function(
+        &mut self,
+        label: &str,
+        name: &str,
+        params: &[(String, Type)],
+        return_type: Option<Type>,
+        body: &[ASTNode],
+        closure: bool,
+    ) -> Result<(String, FunctionContext), CompileError> {
+        let function = FunctionContext { name: name.to_string(), return_type, upvalues: Vec::new(), closure };
+        self.enclosing.push(Scope {
+            asm: mem::take(&mut self.asm),
+            variable_map: mem::take(&mut self.variable_map),
+            loop_stack: mem::take(&mut self.loop_stack),
+            captured: mem::take(&mut self.captured),
+            function: self.function.replace(function),
+        });
+
+        let result = self.generate_function_body(label, params, body);
+
+        let outer = self.enclosing.pop().expect("scope pushed above");
+        let asm = mem::replace(&mut self.asm, outer.asm);
+        let captured = mem::replace(&mut self.captured, outer.captured);
+        let function = mem::replace(&mut self.function, outer.function).expect("function set above");
+        self.variable_map = outer.variable_map;
+        self.loop_stack = outer.loop_stack;
+        result?;
+
+        // Spill slots are shared by every call, so a function must fit in
+        // the registers of its window
+        let pinned = pin_captured(&captured, &HashMap::new());
+        let available = self.target.num_registers;
+        let (code, _) = regalloc::allocate_pinned(&asm, available, 0, &pinned, &HashSet::new()).map_err(|err| match err {
+            CompileError::OutOfSpillSlots { .. } => CompileError::FunctionTooLarge { name: name.to_string(), available },
+            err => err,
+        })?;
+        Ok((code, function))
+    }
+
+    fn generate_function_body(
+        &mut self,
+        label: &str,
+        params: &[(String, Type)],
+        body: &[ASTNode],
+    ) -> Result<(), CompileError> {
+        self.emit(&format!("{}:", label));
+        if let Some(line) = self.current_line {
+            self.emit(&format!(".line {}", line));
+        }
+        for (param, ty) in params.iter().rev() {
+            let reg = self.next_register();
+            self.emit(&format!("POP v{}", reg));
+            self.bind_variable(param, reg, ty.clone());
+        }
+        for stmt in body {
+            self.generate_node(stmt)?;
+        }
+        if !always_returns(body) {
+            let function = self.function.as_mut().expect("generating a function");
+            let return_type = function.return_type.get_or_insert(Type::NIL);
+            if *return_type != Type::NIL {
+                return Err(CompileError::MissingReturn { name: function.name.clone(), ty: return_type.clone() });
+            }
+            let reg = self.next_register();
+            self.emit(&format!("LOAD_K v{}, nil", reg));
+            self.emit(&format!("RET v{}", reg));
+        }
+        Ok(())
+    }
+
+    /// What `name` refers to in the function being generated. A closure
+    /// that uses a variable of an enclosing function captures it.
+    fn resolve(&mut self, name: &str) -> Option<Binding> {
+        if let Some(var) = self.variable_map.get(name) {
+            return Some(Binding::Variable(var.clone()));
+        }
+        let (index, ty) = self.resolve_upvalue(self.enclosing.len(), name)?;
+        Some(Binding::Upvalue(index, ty))
+    }
+
+    /// Index and type of the upvalue through which the function at `level`
+    /// reaches `name`, adding one to it and to the functions between it and
+    /// the variable's as needed. Level 0 is the top level and
+    /// `self.enclosing.len()` the function being generated.
+    fn resolve_upvalue(&mut self, level: usize, name: &str) -> Option<(usize, Type)> {
+        let function = self.function_at(level).filter(|function| function.closure)?;
+        if let Some(index) = function.upvalues.iter().position(|upvalue| upvalue.name == name) {
+            return Some((index, function.upvalues[index].ty.clone()));
+        }
+        let outer = level.checked_sub(1)?;
+        let (capture, ty) = match self.variables_at(outer).get(name).cloned() {
+            Some(var) => {
+                self.captured_at(outer).insert(var.register);
+                (Capture::Register(var.register), var.ty)
+            }
+            None => {
+                let (index, ty) = self.resolve_upvalue(outer, name)?;
+                (Capture::Upvalue(index), ty)
+            }
+        };
+        let upvalues = &mut self.function_at_mut(level).expect("closure found above").upvalues;
+        upvalues.push(Upvalue { name: name.to_string(), ty: ty.clone(), capture });
+        Some((upvalues.len() - 1, ty))
+    }
+
+    /// Type of what `name` refers to, without capturing anything
+    fn binding_type(&self, name: &str) -> Option<Type> {
+        let mut level = self.enclosing.len();
+        loop {
+            if let Some(var) = self.variables_at(level).get(name) {
+                return Some(var.ty.clone());
+            }
+            let function = self.function_at(level).filter(|function| function.closure)?;
+            if let Some(upvalue) = function.upvalues.iter().find(|upvalue| upvalue.name == name) {
+                return Some(upvalue.ty.clone());
+            }
+            level = level.checked_sub(1)?;
+        }
+    }
+
+    fn function_at(&self, level: usize) -> Option<&FunctionContext> {
+        match self.enclosing.get(level) {
+            Some(scope) => scope.function.as_ref(),
+            None => self.function.as_ref(),
+        }
+    }
+
+    fn function_at_mut(&mut self, level: usize) -> Option<&mut FunctionContext> {
+        match self.enclosing.get_mut(level) {
+            Some(scope) => scope.function.as_mut(),
+            None => self.function.as_mut(),
+        }
+    }
+
+    fn variables_at(&self, level: usize) -> &HashMap<String, Variable> {
+        match self.enclosing.get(level) {
+            Some(scope) => &scope.variable_map,
+            None => &self.variable_map,
+        }
+    }
+
+    fn captured_at(&mut self, level: usize) -> &mut HashSet<u32> {
+        match self.enclosing.get_mut(level) {
+            Some(scope) => &mut scope.captured,
+            None => &mut self.captured,
         }
-        self.asm.clone()
+    }
+
+    /// Push the arguments of a call, checked against the parameters of the
+    /// function called
+    fn generate_arguments(&mut self, name: &str, params: &[(String, Type)], args: &[ASTNode]) -> Result<(), CompileError> {
+        if args.len() != params.len() {
+            return Err(CompileError::WrongArgumentCount { name: name.to_string(), expected: params.len(), found: args.len() });
+        }
+        for (arg, (param, ty)) in args.iter().zip(params) {
+            let (arg_reg, arg_type) = self.generate_expression(arg, Some(ty))?;
+            check_assignment(param, Some(ty), &arg_type)?;
+            self.emit(&format!("PUSH v{}", arg_reg));
+        }
+        Ok(())
     }
 
     
@@ -35,101 +543,1151 @@ impl CodeGenerator {
     }
 
 
-    /// Get next available register
-    fn next_register(&mut self) -> u8 {
-        if self.register_counter >= 4 {
-            self.register_counter = 0; // Wrap around for simplicity
+    /// Record, for the debugger, that `name` now lives in virtual register `reg`
+    fn bind_variable(&mut self, name: &str, reg: u32, ty: Type) -> Option<Variable> {
+        self.emit(&format!(".var {} v{}", name, reg));
+        self.variable_map.insert(name.to_string(), Variable { register: reg, ty })
+    }
+
+    /// Forget the variables bound since `scope` was saved, by code that may
+    /// not have run, such as a loop body or a branch of an if, so reading
+    /// them afterwards is an error rather than a read of a stale register.
+    /// Variables the code shadowed are back in their own registers.
+    fn end_scope(&mut self, scope: HashMap<String, Variable>) {
+        let mut shadowed: Vec<_> = scope.iter()
+            .filter(|(name, var)| self.variable_map.get(*name).map(|v| v.register) != Some(var.register))
+            .map(|(name, var)| (name.clone(), var.register))
+            .collect();
+        shadowed.sort_unstable();
+        for (name, reg) in shadowed {
+            self.emit(&format!(".var {} v{}", name, reg));
+        }
+        self.variable_map = scope;
+    }
+
+    /// Create a unique label with the given prefix
+    fn new_label(&mut self, prefix: &str) -> String {
+        let label = format!("{}_{}", prefix, self.label_counter);
+        self.label_counter += 1;
+        label
+    }
+
+    /// Find the loop targeted by a break or continue, innermost first
+    fn find_loop(&self, label: &Option<String>) -> Result<&LoopContext, CompileError> {
+        let found = match label {
+            Some(name) => self.loop_stack.iter().rev().find(|ctx| ctx.label.as_ref() == Some(name)),
+            None => self.loop_stack.last(),
+        };
+        match (found, label) {
+            (Some(ctx), _) => Ok(ctx),
+            (None, Some(name)) => Err(CompileError::UnknownLoopLabel(name.clone())),
+            (None, None) => Err(CompileError::LoopControlOutsideLoop),
+        }
+    }
+
+    /// Registers of the variables bound so far
+    fn variable_registers(&self) -> HashSet<u32> {
+        self.variable_map.values().map(|var| var.register).collect()
+    }
+
+    /// Registers of the variables bound since `outer` was taken, such as
+    /// a loop's counter and the variables of its body, that closures have
+    /// captured
+    fn captured_since(&self, outer: &HashSet<u32>) -> Vec<u32> {
+        let mut captured: Vec<u32> = self.variable_map.values()
+            .map(|var| var.register)
+            .filter(|reg| !outer.contains(reg) && self.captured.contains(reg))
+            .collect();
+        captured.sort_unstable();
+        captured.dedup();
+        captured
+    }
+
+    /// Close the upvalues of loop variables at the end of an iteration, so
+    /// that closures made in it keep that iteration's values
+    fn close_upvalues(&mut self, registers: &[u32]) {
+        for reg in registers {
+            self.emit(&format!("CLOSE_UPVALUE v{}", reg));
+        }
+    }
+
+    /// Whether a virtual register currently holds a named variable
+    fn is_variable_register(&self, reg: u32) -> bool {
+        self.variable_map.values().any(|var| var.register == reg)
+    }
+
+    /// Instructions write into their first operand, so never let one
+    /// land in a variable's register: copy the value out first.
+    fn writable_register(&mut self, reg: u32) -> u32 {
+        if self.is_variable_register(reg) {
+            let copy = self.next_register();
+            self.emit(&format!("MOV v{}, v{}", copy, reg));
+            copy
+        } else {
+            reg
         }
+    }
+
+    /// Get a fresh virtual register; physical registers are assigned by
+    /// `regalloc::allocate` once the whole program has been generated
+    fn next_register(&mut self) -> u32 {
         let reg = self.register_counter;
         self.register_counter += 1;
         reg
     }
 
-    /// Generate code for a number literal and return the register it's in
-    fn generate_expression(&mut self, node: &ASTNode) -> u8 {
+    /// Type an expression has regardless of context. Integer literals
+    /// without a suffix have none: they take the type of the operand they
+    /// are combined with or the variable they are assigned to.
+    pub fn natural_type(&self, node: &ASTNode) -> Option<Type> {
+        match node {
+            ASTNode::Number { suffix, .. } => suffix.map(Type::Basic),
+            ASTNode::Float(_) => Some(Type::Basic(ValueType::F64)),
+            ASTNode::Cast { ty, .. } => Some(Type::Basic(*ty)),
+            ASTNode::Identifier(name) => self.binding_type(name).or_else(|| self.function(name).map(Function::ty)),
+            ASTNode::Boolean(_) => Some(Type::BOOL),
+            ASTNode::String(_) => Some(Type::STR),
+            ASTNode::Array(elements) => elements.iter().find_map(|element| self.natural_type(element)).map(Type::array),
+            ASTNode::Index { array, .. } => self.natural_type(array)?.element().cloned(),
+            ASTNode::Slice { array, .. } => self.natural_type(array),
+            ASTNode::Call { callee, .. } => self.natural_type(callee)?.signature().map(|(_, ret)| ret.clone()),
+            ASTNode::Lambda { params, return_type: Some(ret), .. } => {
+                Some(Type::function(params.iter().map(|(_, ty)| ty.clone()).collect(), ret.clone()))
+            }
+            ASTNode::MethodCall { method, .. } if method == "len" => Some(Type::I32),
+            ASTNode::MethodCall { method, .. } if method == "push" => Some(Type::NIL),
+            ASTNode::MethodCall { object, .. } => self.natural_type(object)?.element().cloned(),
+            ASTNode::UnaryOp { op, operand } if op == "-" => self.natural_type(operand),
+            ASTNode::UnaryOp { .. } => Some(Type::BOOL),
+            ASTNode::BinaryOp { op, left, right } => match op.as_str() {
+                "+" | "-" | "*" | "/" | "%" | "and" | "or" => {
+                    self.natural_type(left).or_else(|| self.natural_type(right))
+                }
+                _ => Some(Type::BOOL),
+            },
+            _ => None,
+        }
+    }
+
+    /// Integer type the uses of `name` in `node` call for: the type of an
+    /// operand it is combined or compared with. Gives the counter of a loop
+    /// with unsuffixed bounds, as in `for i in 0..10`, its type from context.
+    fn type_from_uses(&self, name: &str, node: &ASTNode) -> Option<Type> {
+        let uses = |nodes: &[ASTNode]| nodes.iter().find_map(|node| self.type_from_uses(name, node));
         match node {
-            ASTNode::Number(n) => {
+            ASTNode::BinaryOp { op, left, right } if op != "and" && op != "or" => {
+                let other = match (left.as_ref(), right.as_ref()) {
+                    (ASTNode::Identifier(id), other) | (other, ASTNode::Identifier(id)) if id == name => {
+                        self.natural_type(other).filter(Type::is_integer)
+                    }
+                    _ => None,
+                };
+                other.or_else(|| self.type_from_uses(name, left)).or_else(|| self.type_from_uses(name, right))
+            }
+            ASTNode::BinaryOp { left, right, .. } => {
+                self.type_from_uses(name, left).or_else(|| self.type_from_uses(name, right))
+            }
+            ASTNode::Assignment { target, value, .. } => match value.as_ref() {
+                ASTNode::Identifier(id) if id == name => self.binding_type(target).filter(Type::is_integer),
+                value => self.type_from_uses(name, value),
+            },
+            ASTNode::VariableDeclaration { value: Some(value), .. }
+            | ASTNode::UnaryOp { operand: value, .. }
+            | ASTNode::Cast { value, .. }
+            | ASTNode::Located { statement: value, .. } => self.type_from_uses(name, value),
+            ASTNode::Return(Some(value)) => self.type_from_uses(name, value),
+            ASTNode::Index { array, index } => self.type_from_uses(name, array).or_else(|| self.type_from_uses(name, index)),
+            ASTNode::IndexAssignment { array, index, value } => self.type_from_uses(name, array)
+                .or_else(|| self.type_from_uses(name, index))
+                .or_else(|| self.type_from_uses(name, value)),
+            ASTNode::Call { args, .. } | ASTNode::MethodCall { args, .. } | ASTNode::Print(args) | ASTNode::Array(args) => uses(args),
+            ASTNode::If { condition, then_branch, else_branch } => self.type_from_uses(name, condition)
+                .or_else(|| uses(then_branch))
+                .or_else(|| else_branch.as_deref().and_then(uses)),
+            ASTNode::WhileLoop { condition, body, .. } => self.type_from_uses(name, condition).or_else(|| uses(body)),
+            // An inner loop over the same name binds a counter of its own
+            ASTNode::ForLoop { variable, start, end, body, .. } => self.type_from_uses(name, start)
+                .or_else(|| self.type_from_uses(name, end))
+                .or_else(|| if variable == name { None } else { uses(body) }),
+            _ => None,
+        }
+    }
+
+    /// Load an integer constant of type `ty` into a fresh register
+    fn generate_integer(&mut self, value: i128, ty: ValueType) -> Result<(u32, Type), CompileError> {
+        let constant = ty.integer(value).ok_or(CompileError::IntegerOutOfRange { value, ty })?;
+        let reg = self.next_register();
+        match constant {
+            Value::I32(n) => self.emit(&format!("LOAD_CONST v{}, {}", reg, n)),
+            _ => self.emit(&format!("LOAD_K v{}, {}{}", reg, constant, ty)),
+        }
+        Ok((reg, Type::Basic(ty)))
+    }
+
+    fn generate_float(&mut self, x: f64) -> (u32, Type) {
+        let reg = self.next_register();
+        self.emit(&format!("LOAD_K v{}, {:?}", reg, x));
+        (reg, Type::Basic(ValueType::F64))
+    }
+
+    /// Generate code for an expression and return the register it's in,
+    /// along with its type. Unsuffixed integer literals get type `expected`
+    /// if that is an integer type, else i32; empty array literals need an
+    /// array type `expected`.
+    fn generate_expression(
+        &mut self,
+        node: &ASTNode,
+        expected: Option<&Type>,
+    ) -> Result<(u32, Type), CompileError> {
+        let literal_type = |suffix: Option<ValueType>| {
+            suffix.or(expected.and_then(Type::basic).filter(|ty| ty.is_integer())).unwrap_or(ValueType::I32)
+        };
+        let result = match node {
+            ASTNode::Number { value, suffix } => self.generate_integer(*value as i128, literal_type(*suffix))?,
+            ASTNode::Float(x) => self.generate_float(*x),
+            ASTNode::Identifier(name) => match self.resolve(name) {
+                Some(Binding::Variable(var)) => (var.register, var.ty),
+                Some(Binding::Upvalue(index, ty)) => {
+                    let reg = self.next_register();
+                    self.emit(&format!("GET_UPVALUE v{}, {}", reg, index));
+                    (reg, ty)
+                }
+                // A named function used as a value: a closure without upvalues
+                None => match self.function(name).map(Function::ty) {
+                    Some(ty) => {
+                        let reg = self.next_register();
+                        let label = &self.function(name).expect("function found above").label;
+                        self.emit(&format!("CLOSURE v{}, {}", reg, label));
+                        (reg, ty)
+                    }
+                    None => return Err(CompileError::UndefinedVariable(name.clone())),
+                },
+            },
+            ASTNode::Boolean(b) => {
+                let reg = self.next_register();
+                self.emit(&format!("LOAD_K v{}, {}", reg, b));
+                (reg, Type::BOOL)
+            }
+            ASTNode::String(text) => {
+                let reg = self.next_register();
+                self.emit(&format!("LOAD_K v{}, {}", reg, quote_string(text)));
+                (reg, Type::STR)
+            }
+            ASTNode::Array(elements) => {
+                // The element type comes from the first element that has one
+                // of its own, else from the context, else from the first
+                // element as generated
+                let mut element_type = elements.iter()
+                    .find_map(|element| self.natural_type(element))
+                    .or_else(|| expected.and_then(Type::element).cloned());
                 let reg = self.next_register();
-                self.emit(&format!("LOAD_CONST R{}, {}", reg, n));
-                reg
+                self.emit(&format!("NEW_ARRAY v{}", reg));
+                for element in elements {
+                    let (element_reg, ty) = self.generate_expression(element, element_type.as_ref())?;
+                    let element_type = element_type.get_or_insert_with(|| ty.clone());
+                    check_assignment("array element", Some(element_type), &ty)?;
+                    self.emit(&format!("ARRAY_PUSH v{}, v{}", reg, element_reg));
+                }
+                match element_type {
+                    Some(element_type) => (reg, Type::array(element_type)),
+                    None => return Err(CompileError::UntypedEmptyArray),
+                }
             }
-            ASTNode::Identifier(name) => {
-                if let Some(&reg) = self.variable_map.get(name) {
-                    reg
+            ASTNode::Index { array, index } => {
+                let (array_reg, array_type) = self.generate_expression(array, None)?;
+                let (index_reg, index_type) = self.generate_expression(index, None)?;
+                let Some(element_type) = array_type.element().cloned().filter(|_| index_type.is_integer()) else {
+                    return Err(CompileError::UnsupportedOperands { op: "[]".to_string(), types: vec![array_type, index_type] });
+                };
+                let reg = self.next_register();
+                self.emit(&format!("ARRAY_GET v{}, v{}, v{}", reg, array_reg, index_reg));
+                (reg, element_type)
+            }
+            ASTNode::Slice { array, start, end, inclusive } => {
+                let (array_reg, array_type) = self.generate_expression(array, None)?;
+                let (start_reg, start_type) = self.generate_expression(start, Some(&Type::I32))?;
+                let (end_reg, end_type) = self.generate_expression(end, Some(&Type::I32))?;
+                if array_type.element().is_none() || start_type != Type::I32 || end_type != Type::I32 {
+                    let op = if *inclusive { "[..=]" } else { "[..]" };
+                    return Err(CompileError::UnsupportedOperands {
+                        op: op.to_string(),
+                        types: vec![array_type, start_type, end_type],
+                    });
+                }
+                // ARRAY_SLICE takes an exclusive end
+                let end_reg = if *inclusive {
+                    let end_reg = self.writable_register(end_reg);
+                    let (one, _) = self.generate_integer(1, ValueType::I32)?;
+                    self.emit(&format!("ADD_I32 v{}, v{}", end_reg, one));
+                    end_reg
                 } else {
-                    panic!("Undefined variable: {}", name);
+                    end_reg
+                };
+                let reg = self.next_register();
+                self.emit(&format!("ARRAY_SLICE v{}, v{}, v{}, v{}", reg, array_reg, start_reg, end_reg));
+                (reg, array_type)
+            }
+            // Named functions are called directly, unless a variable hides them
+            ASTNode::Call { callee, args } => match callee.as_ref() {
+                ASTNode::Identifier(name) if self.binding_type(name).is_none() => {
+                    let Some(function) = self.function(name) else {
+                        return Err(CompileError::UndefinedFunction(name.clone()));
+                    };
+                    let (params, return_type, label) = (function.params.clone(), function.return_type.clone(), function.label.clone());
+                    self.generate_arguments(name, &params, args)?;
+                    let reg = self.next_register();
+                    self.emit(&format!("CALL v{}, {}", reg, label));
+                    (reg, return_type)
                 }
+                _ => {
+                    let (callee_reg, callee_type) = self.generate_expression(callee, None)?;
+                    let Some((params, return_type)) = callee_type.signature() else {
+                        return Err(CompileError::NotCallable(callee_type));
+                    };
+                    let params: Vec<(String, Type)> = params.iter().enumerate()
+                        .map(|(i, ty)| (format!("argument {}", i + 1), ty.clone()))
+                        .collect();
+                    let return_type = return_type.clone();
+                    let name = match callee.as_ref() {
+                        ASTNode::Identifier(name) => name.clone(),
+                        _ => callee_type.to_string(),
+                    };
+                    self.generate_arguments(&name, &params, args)?;
+                    let reg = self.next_register();
+                    self.emit(&format!("CALL_CLOSURE v{}, v{}", reg, callee_reg));
+                    (reg, return_type)
+                }
+            },
+            ASTNode::Lambda { params, return_type, body } => {
+                let label = self.new_label("lambda");
+                let (code, function) = self.generate_function(&label, &label, params, return_type.clone(), body, true)?;
+                self.function_code.push_str(&code);
+                let param_types = params.iter().map(|(_, ty)| ty.clone()).collect();
+                let ty = Type::function(param_types, function.return_type.unwrap_or(Type::NIL));
+                let reg = self.next_register();
+                self.emit(&format!("CLOSURE v{}, {}", reg, label));
+                for upvalue in &function.upvalues {
+                    match upvalue.capture {
+                        Capture::Register(var) => self.emit(&format!("CAPTURE v{}, v{}", reg, var)),
+                        Capture::Upvalue(index) => self.emit(&format!("CAPTURE_UPVALUE v{}, {}", reg, index)),
+                    }
+                }
+                (reg, ty)
             }
-            ASTNode::BinaryOp { op, left, right } => {
-                let left_reg = self.generate_expression(left);
-                let right_reg = self.generate_expression(right);
-                
+            ASTNode::MethodCall { object, method, args } => {
+                let (object_reg, object_type) = self.generate_expression(object, None)?;
+                let Some(element_type) = object_type.element().cloned() else {
+                    return Err(CompileError::UnknownMethod { ty: object_type, method: method.clone() });
+                };
+                let arity = match method.as_str() {
+                    "push" => 1,
+                    "pop" | "len" => 0,
+                    _ => return Err(CompileError::UnknownMethod { ty: object_type, method: method.clone() }),
+                };
+                if args.len() != arity {
+                    return Err(CompileError::WrongArgumentCount { name: method.clone(), expected: arity, found: args.len() });
+                }
+                let reg = self.next_register();
+                match method.as_str() {
+                    "push" => {
+                        let (value_reg, value_type) = self.generate_expression(&args[0], Some(&element_type))?;
+                        check_assignment("array element", Some(&element_type), &value_type)?;
+                        self.emit(&format!("ARRAY_PUSH v{}, v{}", object_reg, value_reg));
+                        self.emit(&format!("LOAD_K v{}, nil", reg));
+                        (reg, Type::NIL)
+                    }
+                    "pop" => {
+                        self.emit(&format!("ARRAY_POP v{}, v{}", reg, object_reg));
+                        (reg, element_type)
+                    }
+                    _ => {
+                        self.emit(&format!("ARRAY_LEN v{}, v{}", reg, object_reg));
+                        (reg, Type::I32)
+                    }
+                }
+            }
+            ASTNode::UnaryOp { op, operand } => {
+                // Negative literals are loaded as they are, so that the most
+                // negative value of each type can be written
+                match (op.as_str(), operand.as_ref()) {
+                    ("-", ASTNode::Number { value, suffix }) => {
+                        return self.generate_integer(-(*value as i128), literal_type(*suffix));
+                    }
+                    ("-", ASTNode::Float(x)) => return Ok(self.generate_float(-x)),
+                    _ => {}
+                }
+                let (operand_reg, ty) = self.generate_expression(operand, expected)?;
+                let reg = self.writable_register(operand_reg);
                 match op.as_str() {
-                    "+" => self.emit(&format!("ADD R{}, R{}", left_reg, right_reg)),
-                    "-" => self.emit(&format!("SUB R{}, R{}", left_reg, right_reg)),
-                    "*" => self.emit(&format!("MUL R{}, R{}", left_reg, right_reg)),
-                    "/" => self.emit(&format!("DIV R{}, R{}", left_reg, right_reg)),
-                    _ => panic!("Unsupported operator: {}", op),
+                    "-" if matches!(ty.basic(), Some(ValueType::I32 | ValueType::I64 | ValueType::F64)) => {
+                        self.emit(&format!("NEG v{}", reg));
+                        (reg, ty)
+                    }
+                    "-" => return Err(CompileError::UnsupportedOperands { op: op.clone(), types: vec![ty] }),
+                    "not" => {
+                        self.emit(&format!("NOT v{}", reg));
+                        (reg, Type::BOOL)
+                    }
+                    _ => return Err(CompileError::UnsupportedOperator(op.clone())),
                 }
-                left_reg
             }
-            _ => panic!("Unsupported expression: {:?}", node),
-        }
+            ASTNode::Cast { value, ty } => {
+                let (value_reg, value_type) = self.generate_expression(value, None)?;
+                if value_type == Type::Basic(*ty) {
+                    (value_reg, value_type)
+                } else if value_type.is_numeric() && ty.is_numeric() {
+                    let reg = self.writable_register(value_reg);
+                    self.emit(&format!("TO_{} v{}", ty.name().to_uppercase(), reg));
+                    (reg, Type::Basic(*ty))
+                } else {
+                    return Err(CompileError::InvalidCast { from: value_type, to: *ty });
+                }
+            }
+            ASTNode::BinaryOp { op, left, right } if op == "and" || op == "or" => {
+                // Short-circuit: the right operand only runs when the left
+                // one doesn't already decide the result.
+                let operand_type = self.natural_type(left)
+                    .or_else(|| self.natural_type(right))
+                    .or_else(|| expected.cloned());
+                let end_label = self.new_label(&format!("{}_end", op));
+                let (left_reg, left_type) = self.generate_expression(left, operand_type.as_ref())?;
+                let result_reg = self.writable_register(left_reg);
+                if op == "and" {
+                    self.emit(&format!("JMP_IF_ZERO v{}, {}", result_reg, end_label));
+                } else {
+                    self.emit(&format!("JMP_IF_NOT_ZERO v{}, {}", result_reg, end_label));
+                }
+                let (right_reg, right_type) = self.generate_expression(right, operand_type.as_ref())?;
+                if left_type != right_type {
+                    return Err(CompileError::UnsupportedOperands { op: op.clone(), types: vec![left_type, right_type] });
+                }
+                if right_reg != result_reg {
+                    self.emit(&format!("MOV v{}, v{}", result_reg, right_reg));
+                }
+                self.emit(&format!("{}:", end_label));
+                (result_reg, left_type)
+            }
+            ASTNode::BinaryOp { op, left, right } => {
+                let arithmetic = matches!(op.as_str(), "+" | "-" | "*" | "/" | "%");
+                let mut operand_type = self.natural_type(left).or_else(|| self.natural_type(right));
+                if arithmetic {
+                    operand_type = operand_type.or_else(|| expected.cloned());
+                }
+                let (left_reg, left_type) = self.generate_expression(left, operand_type.as_ref())?;
+                let left_reg = self.writable_register(left_reg);
+                let (right_reg, right_type) = self.generate_expression(right, operand_type.as_ref())?;
+
+                // Both operands must have the same type; arithmetic and
+                // ordering also need it to be numeric, except that strings
+                // can be concatenated with + and ordered
+                let equality = op == "==" || op == "!=";
+                let supported = match left_type.basic() {
+                    Some(ValueType::Str) => !arithmetic || op == "+",
+                    _ => equality || left_type.is_numeric(),
+                };
+                if left_type != right_type || !supported {
+                    return Err(CompileError::UnsupportedOperands { op: op.clone(), types: vec![left_type, right_type] });
+                }
+
+                let mnemonic = match op.as_str() {
+                    "+" => "ADD",
+                    "-" => "SUB",
+                    "*" => "MUL",
+                    "/" => "DIV",
+                    "%" => "MOD",
+                    "==" => "EQ",
+                    "!=" => "NE",
+                    "<" => "LT",
+                    "<=" => "LE",
+                    ">" => "GT",
+                    ">=" => "GE",
+                    _ => return Err(CompileError::UnsupportedOperator(op.clone())),
+                };
+                self.emit(&format!("{} v{}, v{}", typed_mnemonic(mnemonic, &left_type), left_reg, right_reg));
+                let result_type = if arithmetic { left_type } else { Type::BOOL };
+                (left_reg, result_type)
+            }
+            _ => return Err(CompileError::UnsupportedExpression(format!("{:?}", node))),
+        };
+        Ok(result)
     }
 
-    fn generate_node(&mut self, node: &ASTNode) {
+    fn generate_node(&mut self, node: &ASTNode) -> Result<(), CompileError> {
         match node {
-            ASTNode::VariableDeclaration { mutable: _, name, value } => {
+            ASTNode::VariableDeclaration { mutable: _, name, ty, value } => {
                 if let Some(expr) = value {
-                    let reg = self.generate_expression(expr);
-                    self.variable_map.insert(name.clone(), reg);
+                    let (reg, value_type) = self.generate_expression(expr, ty.as_ref())?;
+                    check_assignment(name, ty.as_ref(), &value_type)?;
+                    let reg = self.writable_register(reg);
+                    self.bind_variable(name, reg, value_type);
                 }
             }
             
-            ASTNode::Assignment { target, value } => {
-                let reg = self.generate_expression(value);
-                self.variable_map.insert(target.clone(), reg);
+            ASTNode::Assignment { target, ty, value } => {
+                let existing = self.resolve(target);
+                let expected = ty.as_ref().or(match &existing {
+                    Some(Binding::Variable(var)) => Some(&var.ty),
+                    Some(Binding::Upvalue(_, ty)) => Some(ty),
+                    None => None,
+                });
+                let (reg, value_type) = self.generate_expression(value, expected)?;
+                check_assignment(target, ty.as_ref(), &value_type)?;
+                // Existing variables keep their register and type so that
+                // every control-flow path agrees on where the value lives
+                // and what it is.
+                match existing {
+                    Some(Binding::Variable(var)) => {
+                        check_assignment(target, Some(&var.ty), &value_type)?;
+                        if var.register != reg {
+                            self.emit(&format!("MOV v{}, v{}", var.register, reg));
+                        }
+                    }
+                    Some(Binding::Upvalue(index, var_type)) => {
+                        check_assignment(target, Some(&var_type), &value_type)?;
+                        self.emit(&format!("SET_UPVALUE v{}, {}", reg, index));
+                    }
+                    None => {
+                        let reg = self.writable_register(reg);
+                        self.bind_variable(target, reg, value_type);
+                    }
+                }
+            }
+
+            ASTNode::IndexAssignment { array, index, value } => {
+                let (array_reg, array_type) = self.generate_expression(array, None)?;
+                let (index_reg, index_type) = self.generate_expression(index, None)?;
+                let Some(element_type) = array_type.element().cloned().filter(|_| index_type.is_integer()) else {
+                    return Err(CompileError::UnsupportedOperands { op: "[]".to_string(), types: vec![array_type, index_type] });
+                };
+                let (value_reg, value_type) = self.generate_expression(value, Some(&element_type))?;
+                check_assignment("array element", Some(&element_type), &value_type)?;
+                self.emit(&format!("ARRAY_SET v{}, v{}, v{}", array_reg, index_reg, value_reg));
+            }
+
+            ASTNode::WhileLoop { label, condition, body } => {
+                let cond_label = self.new_label("while_cond");
+                let next_label = self.new_label("while_next");
+                let end_label = self.new_label("while_end");
+                let outer = self.variable_registers();
+                let scope = self.variable_map.clone();
+
+                self.emit(&format!("{}:", cond_label));
+                let (cond_reg, _) = self.generate_expression(condition, None)?;
+                self.emit(&format!("JMP_IF_ZERO v{}, {}", cond_reg, end_label));
+
+                self.loop_stack.push(LoopContext {
+                    label: label.clone(),
+                    break_label: end_label.clone(),
+                    continue_label: next_label.clone(),
+                });
+                for stmt in body {
+                    self.generate_node(stmt)?;
+                }
+                self.loop_stack.pop();
+
+                let captured = self.captured_since(&outer);
+                self.emit(&format!("{}:", next_label));
+                self.close_upvalues(&captured);
+                self.emit(&format!("JMP {}", cond_label));
+                self.emit(&format!("{}:", end_label));
+                self.close_upvalues(&captured);
+                // The body may not have run
+                self.end_scope(scope);
+            }
+
+            ASTNode::Break(label) => {
+                let target = self.find_loop(label)?.break_label.clone();
+                self.emit(&format!("JMP {}", target));
+            }
+
+            ASTNode::Continue(label) => {
+                let target = self.find_loop(label)?.continue_label.clone();
+                self.emit(&format!("JMP {}", target));
+            }
+
+            ASTNode::If { condition, then_branch, else_branch } => {
+                let else_label = self.new_label("if_else");
+                let end_label = self.new_label("if_end");
+
+                let (cond_reg, _) = self.generate_expression(condition, None)?;
+                self.emit(&format!("JMP_IF_ZERO v{}, {}", cond_reg, else_label));
+                let scope = self.variable_map.clone();
+                for stmt in then_branch {
+                    self.generate_node(stmt)?;
+                }
+
+                if let Some(else_branch) = else_branch {
+                    let then_variables = mem::replace(&mut self.variable_map, scope.clone());
+                    self.emit(&format!("JMP {}", end_label));
+                    self.emit(&format!("{}:", else_label));
+                    for stmt in else_branch {
+                        self.generate_node(stmt)?;
+                    }
+                    // A variable both branches bind lives on, in the register
+                    // the then branch put it in
+                    let mut merged = Vec::new();
+                    for (name, var) in then_variables {
+                        let is_new = |var: &Variable| scope.get(&name).map(|old| old.register) != Some(var.register);
+                        match self.variable_map.get(&name) {
+                            Some(other) if is_new(&var) && is_new(other) => {
+                                check_assignment(&name, Some(&var.ty), &other.ty)?;
+                                self.emit(&format!("MOV v{}, v{}", var.register, other.register));
+                                merged.push((name, var));
+                            }
+                            _ => {}
+                        }
+                    }
+                    self.emit(&format!("{}:", end_label));
+                    self.end_scope(scope);
+                    for (name, var) in merged {
+                        self.bind_variable(&name, var.register, var.ty);
+                    }
+                } else {
+                    self.emit(&format!("{}:", else_label));
+                    self.end_scope(scope);
+                }
             }
             
             ASTNode::Print(args) => {
-                for arg in args {
-                    let reg = self.generate_expression(arg);
-                    self.emit(&format!("PRINT_REG R{}", reg));
+                // The arguments go on one line, separated by spaces
+                for (i, arg) in args.iter().enumerate() {
+                    let (reg, _) = self.generate_expression(arg, None)?;
+                    let mnemonic = if i + 1 < args.len() { "PRINT_PART" } else { "PRINT_REG" };
+                    self.emit(&format!("{} v{}", mnemonic, reg));
+                }
+                if args.is_empty() {
+                    let reg = self.next_register();
+                    self.emit(&format!("LOAD_K v{}, \"\"", reg));
+                    self.emit(&format!("PRINT_REG v{}", reg));
                 }
             }
             
-            ASTNode::ForLoop { variable, start, end, inclusive: _, body } => {
-                // For now, implement a simple unrolled loop for the range 1..10
-                // This is a temporary solution to get the basic functionality working
-                
-                // Allocate register for loop variable
-                let loop_var_reg = self.next_register();
-                self.variable_map.insert(variable.clone(), loop_var_reg);
-                
-                // Get start and end values (assuming they are literals for now)
-                let start_val = if let ASTNode::Number(n) = start.as_ref() { *n } else { 1 };
-                let end_val = if let ASTNode::Number(n) = end.as_ref() { *n } else { 10 };
-                
-                // Unroll the loop
-                for i in start_val..end_val {
-                    // Set loop variable to current value
-                    self.emit(&format!("LOAD_CONST R{}, {}", loop_var_reg, i));
-                    
-                    // Generate loop body for this iteration
-                    for stmt in body {
-                        self.generate_node(stmt);
-                    }
-                }
-                
-                // Remove loop variable from scope
-                self.variable_map.remove(variable);
+            ASTNode::ForLoop { label, variable, start, end, inclusive, body } => {
+                // Layout:
+                //     <counter = start, limit = end, step = 1>
+                //     JMP cond
+                //   body:
+                //     <body>
+                //   step:
+                //     inclusive: <last = counter == limit>
+                //                JMP_IF_NOT_ZERO last, end
+                //     ADD counter, step
+                //   cond:
+                //     exclusive: JMP_IF_LESS counter, limit, body
+                //     inclusive: JMP_IF_LESS limit, counter, end
+                //                JMP body
+                //   end:
+                // An inclusive loop stops before stepping past its limit,
+                // which may be the largest value of the counter's type.
+                let body_label = self.new_label("for_body");
+                let step_label = self.new_label("for_step");
+                let cond_label = self.new_label("for_cond");
+                let end_label = self.new_label("for_end");
+
+                // The counter needs its own register so incrementing it never
+                // clobbers a variable used as the start bound.
+                // Bounds without a type of their own take the one the
+                // body's uses of the counter call for
+                let counter_type = self.natural_type(start)
+                    .or_else(|| self.natural_type(end))
+                    .or_else(|| body.iter().find_map(|stmt| self.type_from_uses(variable, stmt)));
+                let (start_reg, start_type) = self.generate_expression(start, counter_type.as_ref())?;
+                let counter_reg = self.writable_register(start_reg);
+                // The limit is read once, when the loop starts, even if the
+                // body assigns to a variable used as the end bound
+                let (limit_reg, limit_type) = self.generate_expression(end, counter_type.as_ref())?;
+                let limit_reg = self.writable_register(limit_reg);
+                let integer_type = start_type.basic().filter(|ty| ty.is_integer());
+                let Some(integer_type) = integer_type.filter(|_| start_type == limit_type) else {
+                    let op = if *inclusive { "..=" } else { ".." };
+                    return Err(CompileError::UnsupportedOperands { op: op.to_string(), types: vec![start_type, limit_type] });
+                };
+                let (step_reg, _) = self.generate_integer(1, integer_type)?;
+
+                // The loop variable shadows any outer binding of the same name
+                let outer = self.variable_registers();
+                let scope = self.variable_map.clone();
+                self.bind_variable(variable, counter_reg, start_type.clone());
+                let header_line = self.current_line;
+
+                self.emit(&format!("JMP {}", cond_label));
+                self.emit(&format!("{}:", body_label));
+                self.loop_stack.push(LoopContext {
+                    label: label.clone(),
+                    break_label: end_label.clone(),
+                    continue_label: step_label.clone(),
+                });
+                for stmt in body {
+                    self.generate_node(stmt)?;
+                }
+                self.loop_stack.pop();
+                // Stepping and the bounds check belong to the loop header
+                if let Some(line) = header_line {
+                    self.emit(&format!(".line {}", line));
+                }
+                let captured = self.captured_since(&outer);
+                self.emit(&format!("{}:", step_label));
+                self.close_upvalues(&captured);
+                if *inclusive {
+                    let last_reg = self.next_register();
+                    self.emit(&format!("MOV v{}, v{}", last_reg, counter_reg));
+                    self.emit(&format!("EQ v{}, v{}", last_reg, limit_reg));
+                    self.emit(&format!("JMP_IF_NOT_ZERO v{}, {}", last_reg, end_label));
+                }
+                self.emit(&format!("{} v{}, v{}", typed_mnemonic("ADD", &start_type), counter_reg, step_reg));
+
+                self.emit(&format!("{}:", cond_label));
+                if *inclusive {
+                    self.emit(&format!("JMP_IF_LESS v{}, v{}, {}", limit_reg, counter_reg, end_label));
+                    self.emit(&format!("JMP {}", body_label));
+                } else {
+                    self.emit(&format!("JMP_IF_LESS v{}, v{}, {}", counter_reg, limit_reg, body_label));
+                }
+                self.emit(&format!("{}:", end_label));
+                self.close_upvalues(&captured);
+                self.end_scope(scope);
+            }
+
+            ASTNode::Function { name, params, return_type, body } => {
+                let return_type = return_type.clone().unwrap_or(Type::NIL);
+                // A function defined again gets code of its own
+                if self.function(name).is_none_or(|function| function.defined) {
+                    self.declare_function(name, params, return_type.clone());
+                }
+                let label = self.function(name).expect("declared above").label.clone();
+                let (code, _) = self.generate_function(&label, name, params, Some(return_type), body, false)?;
+                self.function_code.push_str(&code);
+                if let Some(function) = self.functions.iter_mut().find(|f| f.name == *name) {
+                    function.defined = true;
+                }
+            }
+
+            ASTNode::Return(value) => {
+                let Some(function) = &self.function else {
+                    return Err(CompileError::ReturnOutsideFunction);
+                };
+                let expected = function.return_type.clone();
+                let (reg, ty) = match value {
+                    Some(value) => self.generate_expression(value, expected.as_ref())?,
+                    None => {
+                        let reg = self.next_register();
+                        self.emit(&format!("LOAD_K v{}, nil", reg));
+                        (reg, Type::NIL)
+                    }
+                };
+                // An anonymous function without `->` returns what its
+                // first return does
+                let function = self.function.as_mut().expect("checked above");
+                match &function.return_type {
+                    Some(expected) if *expected != ty => {
+                        let name = function.name.clone();
+                        return Err(CompileError::ReturnTypeMismatch { name, expected: expected.clone(), found: ty });
+                    }
+                    Some(_) => {}
+                    None => function.return_type = Some(ty),
+                }
+                self.emit(&format!("RET v{}", reg));
+            }
+
+            ASTNode::Located { line, statement } => {
+                self.current_line = Some(*line);
+                // A function's code goes elsewhere; its body has lines of its own
+                if !matches!(**statement, ASTNode::Function { .. }) {
+                    self.emit(&format!(".line {}", line));
+                }
+                self.generate_node(statement)?;
             }
             
             _ => {
                 // For expressions used as statements, just generate them
-                self.generate_expression(node);
+                self.generate_expression(node, None)?;
             }
         }
+        Ok(())
     }
     
 }
+
+/// Give every captured variable register a physical register of its own
+/// for the whole code, since open upvalues refer to it, besides those in
+/// `pinned` already
+fn pin_captured(captured: &HashSet<u32>, pinned: &HashMap<u32, usize>) -> HashMap<u32, usize> {
+    let mut pinned = pinned.clone();
+    let mut captured: Vec<u32> = captured.iter().copied().filter(|reg| !pinned.contains_key(reg)).collect();
+    captured.sort_unstable();
+    let mut next = 0;
+    for reg in captured {
+        while pinned.values().any(|&physical| physical == next) {
+            next += 1;
+        }
+        pinned.insert(reg, next);
+        next += 1;
+    }
+    pinned
+}
+
+/// Mnemonic for operands of type `ty`: the typed form, such as ADD_I64 or
+/// LT_F64, where the VM has one, else the generic one
+fn typed_mnemonic(mnemonic: &str, ty: &Type) -> String {
+    let arithmetic = matches!(mnemonic, "ADD" | "SUB" | "MUL" | "DIV" | "MOD");
+    match ty.basic() {
+        Some(ty) if (arithmetic && ty.is_numeric()) || ty == ValueType::F64 => {
+            format!("{}_{}", mnemonic, ty.name().to_uppercase())
+        }
+        _ => mnemonic.to_string(),
+    }
+}
+
+/// Whether running `body` always ends in a return, so that it cannot fall
+/// off its end
+fn always_returns(body: &[ASTNode]) -> bool {
+    body.last().is_some_and(|last| match last {
+        ASTNode::Located { statement, .. } => always_returns(std::slice::from_ref(statement)),
+        ASTNode::Return(_) => true,
+        ASTNode::If { then_branch, else_branch: Some(else_branch), .. } => {
+            always_returns(then_branch) && always_returns(else_branch)
+        }
+        _ => false,
+    })
+}
+
+/// Reject a value of type `found` for a variable declared as `expected`
+fn check_assignment(name: &str, expected: Option<&Type>, found: &Type) -> Result<(), CompileError> {
+    match expected {
+        Some(expected) if expected != found => Err(CompileError::AssignmentTypeMismatch {
+            name: name.to_string(),
+            expected: expected.clone(),
+            found: found.clone(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::compiler::lexer::Lexer;
+    use crate::compiler::parser::Parser;
+    use crate::vm::assembler::assemble;
+    use crate::vm::error::VmError;
+    use crate::vm::instruction::InstructionSet;
+    use crate::vm::machine::VM;
+
+    pub(crate) fn compile(source: &str, config: VmConfig) -> Result<String, CompileError> {
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.tokenize().expect("program lexes");
+        let ast = Parser::with_lines(tokens, lexer.token_lines().to_vec()).parse().expect("program parses");
+        CodeGenerator::with_config(config).generate(&ast)
+    }
+
+    /// Compile and run `source` on a VM built with `config`, returning what
+    /// it prints
+    pub(crate) fn run(source: &str, config: VmConfig) -> String {
+        try_run(source, config).expect("program runs")
+    }
+
+    /// Like `run`, for programs that may stop with a runtime error
+    pub(crate) fn try_run(source: &str, config: VmConfig) -> Result<String, VmError> {
+        let asm = compile(source, config).expect("program compiles");
+        let module = assemble(&format!("{}\nHALT", asm)).expect("generated code assembles");
+
+        let output = Rc::new(RefCell::new(String::new()));
+        let mut vm = VM::with_config(config);
+        let sink = Rc::clone(&output);
+        vm.set_output_callback(move |line| sink.borrow_mut().push_str(&format!("{}\n", line)));
+        vm.load_module(&module);
+        vm.run()?;
+        let output = output.borrow().clone();
+        Ok(output)
+    }
+
+    #[test]
+    fn inclusive_loop_up_to_the_largest_value() {
+        let source = "for i in 2147483646..=2147483647:\n    print(i)\nprint(\"done\")\n";
+        assert_eq!(run(source, VmConfig::default()), "2147483646\n2147483647\ndone\n");
+    }
+
+    #[test]
+    fn operators_bind_by_precedence_level() {
+        let source = "\
+print(1 + 2 * 3)
+print(10 - 4 / 2)
+print(7 % 4 * 2)
+print(-2 * 3)
+print(1 + 2 < 4)
+print(2 * 3 == 6 and 1 > 2)
+print(true or false and false)
+print(not true or true)
+print(5 as f64 / 2.0)
+print((1 + 2) * 3)
+";
+        assert_eq!(run(source, VmConfig::default()), "7\n8\n6\n-6\ntrue\nfalse\ntrue\ntrue\n2.5\n9\n");
+    }
+
+    #[test]
+    fn operators_of_one_level_associate_to_the_left() {
+        let source = "print(10 - 3 - 2)\nprint(100 / 10 / 5)\nprint(2 - 3 + 4)\nprint(17 % 10 % 4)\n";
+        assert_eq!(run(source, VmConfig::default()), "5\n2\n3\n3\n");
+    }
+
+    #[test]
+    fn and_and_or_skip_their_right_operand() {
+        let source = "\
+fn f() -> bool:
+    print(\"called\")
+    return true
+print(false and f())
+print(true or f())
+print(true and f())
+print(false or f())
+";
+        assert_eq!(run(source, VmConfig::default()), "false\ntrue\ncalled\ntrue\ncalled\ntrue\n");
+    }
+
+    #[test]
+    fn float_arithmetic_and_printing() {
+        let source = "\
+a = 1.5
+b = 0.25
+print(a + b, a - b, a * b, a / b, 7.5 % 2.0, -a)
+print(1.0 / 0.0, -1.0 / 0.0, 0.0 / 0.0)
+print(2.0, 0.1 + 0.2, 1e20 * 10.0)
+print(a < 2.0, 2.0 == 2.0, 0.0 / 0.0 == 0.0 / 0.0)
+";
+        assert_eq!(
+            run(source, VmConfig::default()),
+            "1.75 1.25 0.375 6 1.5 -1.5\ninf -inf nan\n2 0.30000000000000004 1e21\ntrue true false\n"
+        );
+    }
+
+    #[test]
+    fn casts_between_integers_and_floats() {
+        let source = "print(7 as f64 / 2.0, 5u32 as f64, 2.9 as i32, -2.9 as i32, 3000000000.0 as i64, 3000000000.0 as u32)\n";
+        assert_eq!(run(source, VmConfig::default()), "3.5 5 2 -2 3000000000 3000000000\n");
+
+        // Floats without an integer value in range of the target type
+        for (source, value, instruction) in [
+            ("x = 0.0 / 0.0\nprint(x as i32)\n", "nan", InstructionSet::ToI32),
+            ("x = 1.0 / 0.0\nprint(x as i64)\n", "inf", InstructionSet::ToI64),
+            ("x = -1.0\nprint(x as u32)\n", "-1", InstructionSet::ToU32),
+        ] {
+            match try_run(source, VmConfig::default()) {
+                Err(VmError::ConversionOutOfRange { instruction: found, value: found_value, .. }) => {
+                    assert_eq!((found, found_value.as_str()), (instruction, value), "{}", source);
+                }
+                other => panic!("{}: {:?}", source, other),
+            }
+        }
+    }
+
+    #[test]
+    fn integer_overflow_traps_unless_arithmetic_wraps() {
+        let wrapping = VmConfig { wrapping_arithmetic: true, ..VmConfig::default() };
+        for (source, instruction, operands, wrapped) in [
+            ("x = 2147483647\nprint(x + 1)\n", InstructionSet::AddI32, vec![2147483647, 1], "-2147483648\n"),
+            ("x = 9223372036854775807i64\nprint(x + 1)\n", InstructionSet::AddI64, vec![9223372036854775807, 1], "-9223372036854775808\n"),
+            ("x = 0u32\nprint(x - 1)\n", InstructionSet::SubU32, vec![0, 1], "4294967295\n"),
+            ("x = 18446744073709551615u64\nprint(x * 2)\n", InstructionSet::MulU64, vec![18446744073709551615, 2], "18446744073709551614\n"),
+            ("x = -2147483648\nprint(x / -1)\n", InstructionSet::DivI32, vec![-2147483648, -1], "-2147483648\n"),
+            ("x = -2147483648\nprint(-x)\n", InstructionSet::Neg, vec![-2147483648], "-2147483648\n"),
+            ("x = -9223372036854775808i64\nprint(-x)\n", InstructionSet::Neg, vec![-9223372036854775808], "-9223372036854775808\n"),
+        ] {
+            match try_run(source, VmConfig::default()) {
+                Err(VmError::IntegerOverflow { instruction: found, operands: found_operands, .. }) => {
+                    assert_eq!((found, found_operands), (instruction, operands), "{}", source);
+                }
+                other => panic!("{}: {:?}", source, other),
+            }
+            assert_eq!(run(source, wrapping), wrapped, "{}", source);
+        }
+    }
+
+    #[test]
+    fn division_by_zero_traps_even_when_arithmetic_wraps() {
+        let wrapping = VmConfig { wrapping_arithmetic: true, ..VmConfig::default() };
+        for (source, instruction) in [
+            ("x = 0\nprint(5 / x)\n", InstructionSet::DivI32),
+            ("x = 0i64\nprint(5 % x)\n", InstructionSet::ModI64),
+            ("x = 0u64\nprint(5 % x)\n", InstructionSet::ModU64),
+        ] {
+            for config in [VmConfig::default(), wrapping] {
+                assert!(
+                    matches!(try_run(source, config), Err(VmError::DivisionByZero { instruction: found, .. }) if found == instruction),
+                    "{}",
+                    source
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn loop_limit_is_read_once() {
+        let source = "mut n = 3\nfor i in 0..n:\n    n = n + 1\nprint(n)\nmut m = 1\nfor i in 0..=m:\n    m = m + 1\nprint(m)\n";
+        assert_eq!(run(source, VmConfig::default()), "6\n3\n");
+    }
+
+    #[test]
+    fn loop_counter_takes_the_type_of_its_bounds() {
+        let source = "mut z: i64 = 0\nfor i in 0i64..10:\n    z = z + i\nprint(z)\n";
+        assert_eq!(run(source, VmConfig::default()), "45\n");
+        let source = "n = 3u32\nmut z: u32 = 0\nfor i in 0..n:\n    z = z + i\nprint(z)\n";
+        assert_eq!(run(source, VmConfig::default()), "3\n");
+    }
+
+    #[test]
+    fn loop_counter_takes_the_type_its_uses_call_for() {
+        let source = "mut z: i64 = 0\nfor i in 0..10:\n    z = z + i\nprint(z)\n";
+        assert_eq!(run(source, VmConfig::default()), "45\n");
+        let source = "mut last: u64 = 0\nfor i in 1..=3:\n    if i > 1:\n        last = i\nprint(last)\n";
+        assert_eq!(run(source, VmConfig::default()), "3\n");
+        let source = "for i in 0..2:\n    print(i)\n";
+        assert_eq!(run(source, VmConfig::default()), "0\n1\n");
+    }
+
+    #[test]
+    fn closures_keep_the_variables_of_their_iteration() {
+        let source = "\
+fs: [fn() -> i32] = []
+mut total = 0
+for i in 0..3:
+    t = i * 10
+    fs.push(|| t + i)
+mut n = 0
+while n < 3:
+    u = n * 100
+    f = fn() -> i32:
+        total = total + 1
+        return u
+    fs.push(f)
+    n = n + 1
+for j in 0..fs.len():
+    print(fs[j]())
+print(total)
+";
+        assert_eq!(run(source, VmConfig::default()), "0\n11\n22\n0\n100\n200\n3\n");
+    }
+
+    #[test]
+    fn functions_named_like_generated_labels() {
+        let source = "fn if_else_0() -> i32:\n    return 42\nif true:\n    print(if_else_0())\n";
+        assert_eq!(run(source, VmConfig::default()), "42\n");
+    }
+
+    #[test]
+    fn variables_bound_on_every_branch_outlive_the_if() {
+        let source = "\
+for n in 0..3:
+    if n == 0:
+        kind = \"zero\"
+    elif n == 1:
+        kind = \"one\"
+    else:
+        kind = \"many\"
+    print(kind)
+";
+        assert_eq!(run(source, VmConfig::default()), "zero\none\nmany\n");
+    }
+
+    #[test]
+    fn variables_bound_on_some_paths_cannot_be_read() {
+        for source in [
+            "a = 123\nif false:\n    x = 7\nprint(x)\n",
+            "if true:\n    x = 1\nelif false:\n    x = 2\nprint(x)\n",
+            "if true:\n    x = 1\nelse:\n    y = 2\nprint(x)\n",
+            "mut n = 0\nwhile n < 0:\n    x = n\n    n = n + 1\nprint(x)\n",
+            "for i in 0..0:\n    x = i\nprint(x)\n",
+        ] {
+            assert!(
+                matches!(compile(source, VmConfig::default()), Err(CompileError::UndefinedVariable(name)) if name == "x"),
+                "{}",
+                source
+            );
+        }
+        let source = "if true:\n    x = 1\nelse:\n    x = \"one\"\n";
+        assert!(matches!(compile(source, VmConfig::default()), Err(CompileError::AssignmentTypeMismatch { .. })));
+    }
+
+    #[test]
+    fn operands_and_arguments_of_the_wrong_type() {
+        let i64 = Type::Basic(ValueType::I64);
+        let mismatch = |op: &str, types: Vec<Type>| Err(CompileError::UnsupportedOperands { op: op.to_string(), types });
+        assert_eq!(compile("print(1 + \"a\")\n", VmConfig::default()), mismatch("+", vec![Type::I32, Type::STR]));
+        assert_eq!(compile("a = 1\nb = 2i64\nprint(a + b)\n", VmConfig::default()), mismatch("+", vec![Type::I32, i64.clone()]));
+        assert_eq!(compile("print(1i32 < 2i64)\n", VmConfig::default()), mismatch("<", vec![Type::I32, i64.clone()]));
+        // An unsuffixed literal takes the type of the other operand instead
+        assert_eq!(run("b = 2i64\nprint(1 + b)\n", VmConfig::default()), "3\n");
+
+        let source = "fn f(n: i64) -> i64:\n    return n\nprint(f(1i32))\n";
+        assert_eq!(
+            compile(source, VmConfig::default()),
+            Err(CompileError::AssignmentTypeMismatch { name: "n".to_string(), expected: i64, found: Type::I32 })
+        );
+        let source = "fn greet(name: string):\n    print(name)\ngreet(3)\n";
+        assert_eq!(
+            compile(source, VmConfig::default()),
+            Err(CompileError::AssignmentTypeMismatch { name: "name".to_string(), expected: Type::STR, found: Type::I32 })
+        );
+    }
+
+    #[test]
+    fn while_loops_with_break_and_continue() {
+        let source = "\
+mut n = 0
+mut odd = 0
+while true:
+    n = n + 1
+    if n > 7:
+        break
+    if n % 2 == 0:
+        continue
+    odd = odd + n
+print(n, odd)
+while false:
+    print(\"never\")
+";
+        assert_eq!(run(source, VmConfig::default()), "8 16\n");
+    }
+
+    #[test]
+    fn labeled_break_and_continue() {
+        let source = "\
+'outer: for i in 0..3:
+    'inner: for j in 0..3:
+        if j == 1:
+            continue 'inner
+        if i == 2 and j == 0:
+            break 'outer
+        if j == 2:
+            break 'inner
+        print(i, j)
+mut k = 0
+'rows: while k < 3:
+    k = k + 1
+    for j in 0..3:
+        if j == k:
+            continue 'rows
+        print(k, j)
+";
+        assert_eq!(run(source, VmConfig::default()), "0 0\n1 0\n1 0\n2 0\n2 1\n3 0\n3 1\n3 2\n");
+        assert!(matches!(
+            compile("for i in 0..3:\n    break 'outer\n", VmConfig::default()),
+            Err(CompileError::UnknownLoopLabel(label)) if label == "outer"
+        ));
+        assert!(matches!(compile("continue\n", VmConfig::default()), Err(CompileError::LoopControlOutsideLoop)));
+    }
+
+    #[test]
+    fn print_puts_its_arguments_on_one_line() {
+        let source = "a = 1\nb = 2\nprint(a, \"+\", b, \"=\", a + b)\nprint()\nprint(\"x\")\n";
+        assert_eq!(run(source, VmConfig::default()), "1 + 2 = 3\n\nx\n");
+    }
+
+    #[test]
+    fn string_concatenation() {
+        let source = "name = \"Orus\"\ngreeting = \"Hello, \" + name + \"!\\n\\tbye\"\nprint(greeting)\nprint(\"a\" + \"\" == \"a\")\n";
+        assert_eq!(run(source, VmConfig::default()), "Hello, Orus!\n\tbye\ntrue\n");
+    }
+}
@@ -1,8 +1,16 @@
+use std::fmt;
+
+use crate::vm::assembler::unescape;
+use crate::vm::value::ValueType;
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     Keyword(String),
     Identifier(String),
-    Number(i32),
+    LoopLabel(String),
+    Number(u64, Option<ValueType>),   // integer literal and its type suffix, as in 42u32
+    Float(f64),
+    Str(String),                      // string literal with its escapes resolved
     Colon,
     RangeExclusive,
     RangeInclusive,
@@ -10,9 +18,52 @@ pub enum Token {
     Newline,
     Indent,
     Dedent,
-    EOF,
+    Eof,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Keyword(kw) => write!(f, "'{}'", kw),
+            Token::Identifier(name) => write!(f, "name {}", name),
+            Token::LoopLabel(label) => write!(f, "label '{}", label),
+            Token::Number(value, _) => write!(f, "number {}", value),
+            Token::Float(x) => write!(f, "number {}", x),
+            Token::Str(text) => write!(f, "string {:?}", text),
+            Token::Colon => write!(f, "':'"),
+            Token::RangeExclusive => write!(f, "'..'"),
+            Token::RangeInclusive => write!(f, "'..='"),
+            Token::Operator(op) => write!(f, "'{}'", op),
+            Token::Newline => write!(f, "end of line"),
+            Token::Indent => write!(f, "indent"),
+            Token::Dedent => write!(f, "end of block"),
+            Token::Eof => write!(f, "end of input"),
+        }
+    }
+}
+
+/// Source text that is not a valid token
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LexError {
+    IntegerOutOfRange { line: usize, literal: String },    // does not fit in 64 bits
+    UnexpectedCharacter { line: usize, ch: char },
+    UnterminatedString { line: usize },                     // no closing quote before the end of the line
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LexError::IntegerOutOfRange { line, literal } => {
+                write!(f, "line {}: integer literal {} out of range", line, literal)
+            }
+            LexError::UnexpectedCharacter { line, ch } => write!(f, "line {}: unexpected character {:?}", line, ch),
+            LexError::UnterminatedString { line } => write!(f, "line {}: unterminated string literal", line),
+        }
+    }
 }
 
+impl std::error::Error for LexError {}
+
 pub struct Lexer {
     chars: Vec<char>,
     position: usize,
@@ -20,6 +71,9 @@ pub struct Lexer {
     pending_dedents: usize,
     at_line_start: bool,
     current_indent: usize,
+    line: usize,                // 1-based line of the next character
+    token_lines: Vec<usize>,    // source line of each token from `tokenize`
+    error: Option<LexError>,    // first error found; tokenizing goes on past it
 }
 
 impl Lexer {
@@ -31,23 +85,42 @@ impl Lexer {
             pending_dedents: 0,
             at_line_start: true,
             current_indent: 0,
+            line: 1,
+            token_lines: Vec::new(),
+            error: None,
         }
     }
 
-    pub fn tokenize(&mut self) -> Vec<Token> {
+    /// Source line of each token returned by the last `tokenize` call
+    pub fn token_lines(&self) -> &[usize] {
+        &self.token_lines
+    }
+
+    /// Split the whole source into tokens, ending with `Token::Eof`
+    pub fn tokenize(&mut self) -> Result<Vec<Token>, LexError> {
         let mut tokens = Vec::new();
+        self.token_lines.clear();
+        self.error = None;
         
-        while let Some(token) = self.next_token() {
+        loop {
+            let line = self.line;
+            let Some(token) = self.next_token() else { break };
             tokens.push(token);
+            self.token_lines.push(line);
         }
         
         // Add remaining dedents at EOF
         for _ in 1..self.indent_stack.len() {
             tokens.push(Token::Dedent);
+            self.token_lines.push(self.line);
         }
         
-        tokens.push(Token::EOF);
-        tokens
+        tokens.push(Token::Eof);
+        self.token_lines.push(self.line);
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => Ok(tokens),
+        }
     }
 
     fn next_token(&mut self) -> Option<Token> {
@@ -61,6 +134,10 @@ impl Lexer {
         if self.at_line_start {
             self.at_line_start = false;
             let indent = self.skip_whitespace();
+            if self.peek() == Some('/') && self.peek_at(1) == Some('/') {
+                // A comment-only line does not change the indentation
+                self.skip_comment();
+            }
             
             if self.peek() == Some('\n') || self.peek().is_none() {
                 // Blank line, skip it
@@ -84,6 +161,8 @@ impl Lexer {
                     self.indent_stack.pop();
                     self.pending_dedents += 1;
                 }
+                // One dedent is returned now, the rest are queued
+                self.pending_dedents -= 1;
                 return Some(Token::Dedent);
             }
         } else {
@@ -91,10 +170,7 @@ impl Lexer {
             self.skip_non_newline_whitespace();
         }
         
-        let c = match self.advance() {
-            Some(c) => c,
-            None => return None,
-        };
+        let c = self.advance()?;
         
         match c {
             '\n' => {
@@ -122,48 +198,161 @@ impl Lexer {
                     ident.push(self.advance().unwrap());
                 }
                 match ident.as_str() {
-                    "mut" | "for" | "in" | "print" => Some(Token::Keyword(ident)),
+                    "mut" | "for" | "in" | "while" | "break" | "continue" | "print" | "if" | "elif"
+                    | "else" | "and" | "or" | "not" | "true" | "false" | "as" | "fn" | "return" => {
+                        Some(Token::Keyword(ident))
+                    }
                     _ => Some(Token::Identifier(ident)),
                 }
             }
+            '\'' => {
+                // Loop label such as 'outer
+                let mut label = String::new();
+                while let Some('a'..='z' | 'A'..='Z' | '0'..='9' | '_') = self.peek() {
+                    label.push(self.advance().unwrap());
+                }
+                if label.is_empty() {
+                    self.unexpected('\'')
+                } else {
+                    Some(Token::LoopLabel(label))
+                }
+            }
             '0'..='9' => {
                 let mut num = c.to_string();
                 while let Some('0'..='9') = self.peek() {
                     num.push(self.advance().unwrap());
                 }
-                num.parse().ok().map(Token::Number)
+                let mut float = false;
+                // A fraction needs a digit after the point, so that ranges
+                // such as 0..10 stay integers
+                if self.peek() == Some('.') && matches!(self.peek_at(1), Some('0'..='9')) {
+                    float = true;
+                    num.push(self.advance().unwrap());
+                    while let Some('0'..='9') = self.peek() {
+                        num.push(self.advance().unwrap());
+                    }
+                }
+                // Exponent: e or E, an optional sign and digits
+                if let Some('e' | 'E') = self.peek() {
+                    let sign = matches!(self.peek_at(1), Some('+' | '-')) as usize;
+                    if matches!(self.peek_at(1 + sign), Some('0'..='9')) {
+                        float = true;
+                        for _ in 0..=sign {
+                            num.push(self.advance().unwrap());
+                        }
+                        while let Some('0'..='9') = self.peek() {
+                            num.push(self.advance().unwrap());
+                        }
+                    }
+                }
+                match self.number_suffix(float) {
+                    Some(ValueType::F64) => num.parse().ok().map(Token::Float),
+                    _ if float => num.parse().ok().map(Token::Float),
+                    suffix => match num.parse() {
+                        Ok(value) => Some(Token::Number(value, suffix)),
+                        Err(_) => {
+                            let err = LexError::IntegerOutOfRange { line: self.line, literal: num };
+                            self.error.get_or_insert(err);
+                            Some(Token::Number(0, suffix))
+                        }
+                    },
+                }
             }
-            '+' | '-' | '*' | '=' | '(' | ')' => Some(Token::Operator(c.to_string())),
-            '/' => {
-                if self.peek() == Some('/') {
-                    // Line comment - skip to end of line
-                    self.advance(); // consume second '/'
-                    while let Some(ch) = self.peek() {
-                        if ch == '\n' {
+            '"' => {
+                // String literal, which must end on the line it starts on
+                let mut text = String::new();
+                loop {
+                    let ch = match self.peek() {
+                        Some('\n') | None => {
+                            self.error.get_or_insert(LexError::UnterminatedString { line: self.line });
                             break;
                         }
-                        self.advance();
+                        Some(ch) => ch,
+                    };
+                    self.advance();
+                    match ch {
+                        '"' => break,
+                        '\\' => match self.peek().filter(|&next| next != '\n') {
+                            Some(next) => {
+                                self.advance();
+                                match unescape(next) {
+                                    Some(escaped) => text.push(escaped),
+                                    None => {
+                                        // Unknown escapes are kept as written
+                                        text.push('\\');
+                                        text.push(next);
+                                    }
+                                }
+                            }
+                            None => text.push('\\'),
+                        },
+                        ch => text.push(ch),
                     }
+                }
+                Some(Token::Str(text))
+            }
+            // Return type arrow: ->
+            '-' if self.peek() == Some('>') => {
+                self.advance();
+                Some(Token::Operator("->".to_string()))
+            }
+            '+' | '-' | '*' | '%' | '(' | ')' | '[' | ']' | ',' | '|' => Some(Token::Operator(c.to_string())),
+            '=' | '<' | '>' | '!' => {
+                // Two-character operators: ==, <=, >=, !=
+                if self.peek() == Some('=') {
+                    self.advance();
+                    Some(Token::Operator(format!("{}=", c)))
+                } else {
+                    Some(Token::Operator(c.to_string()))
+                }
+            }
+            '/' => {
+                if self.peek() == Some('/') {
+                    // Line comment - skip to end of line
+                    self.skip_comment();
                     self.next_token() // Skip the comment and get next token
                 } else {
                     Some(Token::Operator("/".to_string()))
                 }
             }
-            _ => self.next_token(), // Skip unknown characters
+            c => self.unexpected(c),
         }
     }
+
+    // Records a character that starts no token, and goes on after it
+    fn unexpected(&mut self, ch: char) -> Option<Token> {
+        self.error.get_or_insert(LexError::UnexpectedCharacter { line: self.line, ch });
+        self.next_token()
+    }
     
     // Moves the lexer forward by one character and returns it
     fn advance(&mut self) -> Option<char> {
         if self.position < self.chars.len() {
             let c = self.chars[self.position];
             self.position += 1;
+            if c == '\n' {
+                self.line += 1;
+            }
             Some(c)
         } else {
             None
         }
     }
 
+    // Consumes a type suffix (i32, i64, u32, u64, f64) directly after a
+    // number, unless more identifier characters follow it. Only f64 can
+    // follow a float.
+    fn number_suffix(&mut self, float: bool) -> Option<ValueType> {
+        let rest = self.chars.get(self.position..self.position + 3)?;
+        let name: String = rest.iter().collect();
+        let ty = ValueType::from_name(&name).filter(|ty| ty.is_numeric() && (!float || *ty == ValueType::F64))?;
+        if let Some('a'..='z' | 'A'..='Z' | '0'..='9' | '_') = self.peek_at(3) {
+            return None;
+        }
+        self.position += 3;
+        Some(ty)
+    }
+
     // Peeks at the next character without consuming it
     fn peek(&self) -> Option<char> {
         if self.position < self.chars.len() {
@@ -173,10 +362,15 @@ impl Lexer {
         }
     }
 
+    // Peeks `offset` characters past the next one
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.position + offset).copied()
+    }
+
     // Skips all whitespace characters except newlines
     fn skip_non_newline_whitespace(&mut self) {
         while let Some(c) = self.peek() {
-            if c == ' ' || c == '\t' {
+            if c == ' ' || c == '\t' || c == '\r' {
                 self.advance();
             } else {
                 break;
@@ -184,11 +378,21 @@ impl Lexer {
         }
     }
 
+    // Skips a line comment, up to the newline that ends it
+    fn skip_comment(&mut self) {
+        while let Some(ch) = self.peek() {
+            if ch == '\n' {
+                break;
+            }
+            self.advance();
+        }
+    }
+
     // Skips whitespace (spaces/tabs) and returns the count of characters skipped
     fn skip_whitespace(&mut self) -> usize {
         let mut count = 0;
         while let Some(c) = self.peek() {
-            if c == ' ' || c == '\t' {
+            if c == ' ' || c == '\t' || c == '\r' {
                 self.advance();
                 count += 1;
             } else {
@@ -198,4 +402,64 @@ impl Lexer {
         count
     }
     
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn str(text: &str) -> Token {
+        Token::Str(text.to_string())
+    }
+
+    #[test]
+    fn integer_literal_out_of_range() {
+        let err = Lexer::new("x = 1\ny = 99999999999999999999\nprint(y)\n").tokenize().unwrap_err();
+        assert_eq!(err, LexError::IntegerOutOfRange { line: 2, literal: "99999999999999999999".to_string() });
+    }
+
+    #[test]
+    fn largest_integer_literal() {
+        let tokens = Lexer::new("18446744073709551615u64").tokenize().unwrap();
+        assert_eq!(tokens[0], Token::Number(u64::MAX, Some(ValueType::U64)));
+    }
+
+    #[test]
+    fn string_escapes() {
+        let tokens = Lexer::new(r#"print("a\tb\n\"q\" \\ \r\0")"#).tokenize().unwrap();
+        assert_eq!(tokens[2], str("a\tb\n\"q\" \\ \r\0"));
+    }
+
+    #[test]
+    fn unknown_escapes_are_kept_as_written() {
+        let tokens = Lexer::new(r#"x = "C:\dir\x""#).tokenize().unwrap();
+        assert_eq!(tokens[2], str(r"C:\dir\x"));
+    }
+
+    #[test]
+    fn unterminated_string() {
+        let err = Lexer::new("x = 1\nprint(\"hi)\nprint(x)\n").tokenize().unwrap_err();
+        assert_eq!(err, LexError::UnterminatedString { line: 2 });
+        let err = Lexer::new("s = \"ends with a backslash\\").tokenize().unwrap_err();
+        assert_eq!(err, LexError::UnterminatedString { line: 1 });
+    }
+
+    #[test]
+    fn string_concatenation() {
+        let tokens = Lexer::new("\"Hello, \" + name + \"!\"").tokenize().unwrap();
+        assert_eq!(
+            tokens,
+            [str("Hello, "), Token::Operator("+".to_string()), Token::Identifier("name".to_string()),
+             Token::Operator("+".to_string()), str("!"), Token::Eof]
+        );
+    }
+
+    #[test]
+    fn unexpected_character() {
+        let err = Lexer::new("x = 1\ny = x $ 2\n").tokenize().unwrap_err();
+        assert_eq!(err, LexError::UnexpectedCharacter { line: 2, ch: '$' });
+        let err = Lexer::new("break ' outer").tokenize().unwrap_err();
+        assert_eq!(err, LexError::UnexpectedCharacter { line: 1, ch: '\'' });
+        // Carriage returns of Windows line endings are whitespace
+        assert!(Lexer::new("x = 1\r\nif x == 1:\r\n    print(x)\r\n").tokenize().is_ok());
+    }
+}
@@ -1,3 +1,5 @@
 pub mod lexer;
 pub mod parser;
-pub mod codegen;
\ No newline at end of file
+pub mod types;
+pub mod codegen;
+pub mod regalloc;
\ No newline at end of file
@@ -1,5 +1,8 @@
-// Add this import or definition at the top of the file
+use std::fmt;
+
 use crate::compiler::lexer::Token;
+use crate::compiler::types::Type;
+use crate::vm::value::ValueType;
 
 #[derive(Debug)]
 pub enum ASTNode {
@@ -7,10 +10,12 @@ pub enum ASTNode {
         #[allow(dead_code)]
         mutable: bool,
         name: String,
+        ty: Option<Type>,           // type annotation, as in `mut x: i64 = 0`
         value: Option<Box<ASTNode>>,
     },
     Assignment {
         target: String,
+        ty: Option<Type>,
         value: Box<ASTNode>,
     },
     BinaryOp {
@@ -18,78 +23,215 @@ pub enum ASTNode {
         left: Box<ASTNode>,
         right: Box<ASTNode>,
     },
+    UnaryOp {
+        op: String,
+        operand: Box<ASTNode>,
+    },
     Identifier(String),
-    Number(i32),
+    Float(f64),
+    Cast {
+        value: Box<ASTNode>,
+        ty: ValueType,
+    },
+    Number {
+        value: u64,
+        suffix: Option<ValueType>,
+    },
+    Boolean(bool),
+    String(String),
+    Array(Vec<ASTNode>),
+    Index {
+        array: Box<ASTNode>,
+        index: Box<ASTNode>,
+    },
+    Slice {
+        array: Box<ASTNode>,
+        start: Box<ASTNode>,
+        end: Box<ASTNode>,
+        inclusive: bool,
+    },
+    // `a.push(x)`, `a.pop()`, `a.len()`
+    MethodCall {
+        object: Box<ASTNode>,
+        method: String,
+        args: Vec<ASTNode>,
+    },
+    IndexAssignment {
+        array: Box<ASTNode>,
+        index: Box<ASTNode>,
+        value: Box<ASTNode>,
+    },
     ForLoop {
-        #[allow(dead_code)]
+        label: Option<String>,
         variable: String,
-        #[allow(dead_code)]
         start: Box<ASTNode>,
-        #[allow(dead_code)]
         end: Box<ASTNode>,
-        #[allow(dead_code)]
         inclusive: bool,
         body: Vec<ASTNode>,
     },
+    WhileLoop {
+        label: Option<String>,
+        condition: Box<ASTNode>,
+        body: Vec<ASTNode>,
+    },
+    Break(Option<String>),
+    Continue(Option<String>),
+    If {
+        condition: Box<ASTNode>,
+        then_branch: Vec<ASTNode>,
+        else_branch: Option<Vec<ASTNode>>,
+    },
     Print(Vec<ASTNode>),
+    Function {
+        name: String,
+        params: Vec<(String, Type)>,
+        return_type: Option<Type>,  // None for functions that return nothing, as nil
+        body: Vec<ASTNode>,
+    },
+    Call {
+        callee: Box<ASTNode>,
+        args: Vec<ASTNode>,
+    },
+    // Anonymous function; without a return type, it returns the type of its
+    // return statements
+    Lambda {
+        params: Vec<(String, Type)>,
+        return_type: Option<Type>,
+        body: Vec<ASTNode>,
+    },
+    Return(Option<Box<ASTNode>>),
     #[allow(dead_code)]
     Block(Vec<ASTNode>),
+    // A statement tagged with the source line it starts on
+    Located {
+        line: usize,
+        statement: Box<ASTNode>,
+    },
 }
 
+/// Tokens that do not form a valid program
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    Expected { line: usize, expected: &'static str, found: Token },
+    InvalidAssignmentTarget { line: usize },    // `=` after something other than a name or index
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Expected { line, expected, found } => {
+                write!(f, "line {}: expected {}, found {}", line, expected, found)
+            }
+            ParseError::InvalidAssignmentTarget { line } => {
+                write!(f, "line {}: only variables and array elements can be assigned to", line)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+type ParseResult<T> = Result<T, ParseError>;
+
 pub struct Parser {
     tokens: Vec<Token>,
+    lines: Vec<usize>,      // source line of each token; empty if unknown
     position: usize,
 }
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Self {
-        Parser { tokens, position: 0 }
+        Parser { tokens, lines: Vec::new(), position: 0 }
     }
 
-    pub fn parse(&mut self) -> Vec<ASTNode> {
+    /// Like `new`, but statements are wrapped in `ASTNode::Located` using
+    /// the token lines from `Lexer::token_lines`
+    pub fn with_lines(tokens: Vec<Token>, lines: Vec<usize>) -> Self {
+        Parser { tokens, lines, position: 0 }
+    }
+
+    pub fn parse(&mut self) -> ParseResult<Vec<ASTNode>> {
         let mut statements = Vec::new();
-        
-        while !self.is_at_end() {
-            // Skip newlines and other non-statement tokens
-            if let Some(Token::Newline) = self.peek() {
-                self.advance();
-                continue;
-            }
-            
-            if let Some(stmt) = self.statement() {
-                statements.push(stmt);
-            } else {
-                // If we can't parse a statement, advance to avoid infinite loop
-                if self.advance().is_none() {
-                    break;
-                }
+        loop {
+            self.skip_newlines();
+            if let Some(Token::Eof) | None = self.peek() {
+                return Ok(statements);
             }
+            statements.push(self.statement()?);
         }
-        
-        statements
     }
     
-    fn statement(&mut self) -> Option<ASTNode> {
+    /// Parse input that is exactly one expression, as typed at the REPL
+    pub fn parse_expression(&mut self) -> Option<ASTNode> {
+        self.skip_newlines();
+        let expr = self.expression().ok()?;
+        self.skip_newlines();
         match self.peek() {
-            Some(Token::Keyword(kw)) if kw == "mut" => {
-                let result = self.variable_declaration();
-                self.skip_newlines(); // Skip trailing newlines
-                result
-            },
-            Some(Token::Keyword(kw)) if kw == "for" => self.for_loop(),
-            Some(Token::Keyword(kw)) if kw == "print" => {
-                let result = self.print_statement();
-                self.skip_newlines(); // Skip trailing newlines
-                result
-            },
-            Some(Token::Identifier(_)) => {
-                let result = self.assignment();
-                self.skip_newlines(); // Skip trailing newlines
-                result
-            },
+            Some(Token::Eof) | None => Some(expr),
             _ => None,
         }
     }
+
+    fn statement(&mut self) -> ParseResult<ASTNode> {
+        let line = self.current_line();
+        let statement = match self.peek() {
+            Some(Token::Keyword(kw)) if kw == "for" => return Ok(Self::located(line, self.for_loop(None)?)),
+            Some(Token::Keyword(kw)) if kw == "while" => return Ok(Self::located(line, self.while_loop(None)?)),
+            Some(Token::LoopLabel(_)) => return Ok(Self::located(line, self.labeled_loop()?)),
+            Some(Token::Keyword(kw)) if kw == "if" => return Ok(Self::located(line, self.if_statement()?)),
+            Some(Token::Keyword(kw)) if kw == "fn" => return Ok(Self::located(line, self.function_declaration()?)),
+            Some(Token::Keyword(kw)) if kw == "mut" => self.variable_declaration()?,
+            Some(Token::Keyword(kw)) if kw == "break" || kw == "continue" => self.loop_control()?,
+            Some(Token::Keyword(kw)) if kw == "return" => self.return_statement()?,
+            Some(Token::Keyword(kw)) if kw == "print" => self.print_statement()?,
+            // A name followed by "=" or a type annotation is assigned to
+            Some(Token::Identifier(_))
+                if matches!(self.tokens.get(self.position + 1), Some(Token::Colon))
+                    || self.tokens.get(self.position + 1) == Some(&Token::Operator("=".to_string())) =>
+            {
+                self.assignment()?
+            }
+            _ => self.expression_statement()?,
+        };
+        self.end_of_statement()?;
+        Ok(Self::located(line, statement))
+    }
+
+    // A simple statement ends at the end of its line; one that ends with a
+    // block, such as an assignment of an anonymous function, ends with it
+    fn end_of_statement(&mut self) -> ParseResult<()> {
+        let after_block = matches!(self.position.checked_sub(1).map(|i| &self.tokens[i]), Some(Token::Dedent | Token::Newline));
+        match self.peek() {
+            Some(Token::Newline) => self.skip_newlines(),
+            Some(Token::Dedent | Token::Eof) | None => {}
+            _ if after_block => {}
+            _ => return Err(self.expected("end of line")),
+        }
+        Ok(())
+    }
+
+    fn current_line(&self) -> Option<usize> {
+        self.lines.get(self.position).copied()
+    }
+
+    /// Error for the token at the current position, which is not `expected`
+    fn expected(&self, expected: &'static str) -> ParseError {
+        ParseError::Expected { line: self.error_line(), expected, found: self.peek().cloned().unwrap_or(Token::Eof) }
+    }
+
+    // Without token lines, the line is found by counting newlines
+    fn error_line(&self) -> usize {
+        self.current_line().unwrap_or_else(|| {
+            1 + self.tokens[..self.position.min(self.tokens.len())].iter().filter(|t| **t == Token::Newline).count()
+        })
+    }
+
+    fn located(line: Option<usize>, statement: ASTNode) -> ASTNode {
+        match line {
+            Some(line) => ASTNode::Located { line, statement: Box::new(statement) },
+            None => statement,
+        }
+    }
     
     fn skip_newlines(&mut self) {
         while let Some(Token::Newline) = self.peek() {
@@ -97,12 +239,27 @@ impl Parser {
         }
     }
     
-    fn for_loop(&mut self) -> Option<ASTNode> {
-        self.consume_keyword("for")?;
+    // Labeled loop: <label> ":" (<for_loop> | <while_loop>)
+    fn labeled_loop(&mut self) -> ParseResult<ASTNode> {
+        let label = match self.advance() {
+            Some(Token::LoopLabel(label)) => label.clone(),
+            _ => unreachable!("statement starts with a loop label"),
+        };
+        self.consume(Token::Colon, "':'")?;
+
+        match self.peek() {
+            Some(Token::Keyword(kw)) if kw == "for" => self.for_loop(Some(label)),
+            Some(Token::Keyword(kw)) if kw == "while" => self.while_loop(Some(label)),
+            _ => Err(self.expected("'for' or 'while' after a loop label")),
+        }
+    }
+
+    fn for_loop(&mut self, label: Option<String>) -> ParseResult<ASTNode> {
+        self.advance(); // "for"
         
         let variable = self.consume_identifier()?;
         
-        self.consume_keyword("in")?;
+        self.consume(Token::Keyword("in".to_string()), "'in'")?;
         
         let start = self.expression()?;
         
@@ -115,27 +272,14 @@ impl Parser {
                 self.advance();
                 false
             }
-            _ => return None,
+            _ => return Err(self.expected("'..' or '..='")),
         };
         
         let end = self.expression()?;
-        self.consume(Token::Colon)?;
-        self.consume(Token::Newline)?;
-        
-        // Handle indentation
-        let mut body = Vec::new();
-        if self.consume(Token::Indent).is_some() {
-            while self.peek() != Some(&Token::Dedent) && !self.is_at_end() {
-                if let Some(stmt) = self.statement() {
-                    body.push(stmt);
-                } else {
-                    self.advance();
-                }
-            }
-            self.consume(Token::Dedent);
-        }
+        let body = self.block()?;
         
-        Some(ASTNode::ForLoop {
+        Ok(ASTNode::ForLoop {
+            label,
             variable,
             start: Box::new(start),
             end: Box::new(end),
@@ -143,8 +287,133 @@ impl Parser {
             body,
         })
     }
-    
-    // Implement other parsing methods...
+
+    // While loop: "while" <expression> ":" <block>
+    fn while_loop(&mut self, label: Option<String>) -> ParseResult<ASTNode> {
+        self.advance(); // "while"
+        let condition = self.expression()?;
+        let body = self.block()?;
+
+        Ok(ASTNode::WhileLoop {
+            label,
+            condition: Box::new(condition),
+            body,
+        })
+    }
+
+    // Loop control: ("break" | "continue") [<label>]
+    fn loop_control(&mut self) -> ParseResult<ASTNode> {
+        let is_break = self.advance() == Some(&Token::Keyword("break".to_string()));
+        let label = match self.peek() {
+            Some(Token::LoopLabel(label)) => {
+                let label = label.clone();
+                self.advance();
+                Some(label)
+            }
+            _ => None,
+        };
+
+        if is_break {
+            Ok(ASTNode::Break(label))
+        } else {
+            Ok(ASTNode::Continue(label))
+        }
+    }
+
+    // If statement: "if" <expression> ":" <block> {"elif" <expression> ":" <block>} ["else" ":" <block>]
+    // An elif chain is represented as nested If nodes in the else branch.
+    fn if_statement(&mut self) -> ParseResult<ASTNode> {
+        // Either "if" or, further down a chain, "elif"
+        self.advance();
+
+        let condition = self.expression()?;
+        let then_branch = self.block()?;
+
+        let else_branch = match self.peek() {
+            Some(Token::Keyword(kw)) if kw == "elif" => {
+                let line = self.current_line();
+                Some(vec![Self::located(line, self.if_statement()?)])
+            }
+            Some(Token::Keyword(kw)) if kw == "else" => {
+                self.advance();
+                Some(self.block()?)
+            }
+            _ => None,
+        };
+
+        Ok(ASTNode::If {
+            condition: Box::new(condition),
+            then_branch,
+            else_branch,
+        })
+    }
+
+    // Function declaration: "fn" <identifier> "(" <params> ")" [<return type>] <block>
+    fn function_declaration(&mut self) -> ParseResult<ASTNode> {
+        self.advance(); // "fn"
+        let name = self.consume_identifier()?;
+        self.consume(Token::Operator("(".to_string()), "'('")?;
+        let params = self.parameters(")")?;
+        let return_type = self.return_type()?;
+        let body = self.block()?;
+
+        Ok(ASTNode::Function { name, params, return_type, body })
+    }
+
+    // Params: [<identifier> ":" <type> {"," <identifier> ":" <type>}] <close>
+    fn parameters(&mut self, close: &str) -> ParseResult<Vec<(String, Type)>> {
+        let close = Token::Operator(close.to_string());
+        let mut params = Vec::new();
+        while !self.next_is(&close) {
+            if !params.is_empty() {
+                self.consume(Token::Operator(",".to_string()), "',' between parameters")?;
+            }
+            let param = self.consume_identifier()?;
+            self.consume(Token::Colon, "':' and a parameter type")?;
+            params.push((param, self.type_name()?));
+        }
+        Ok(params)
+    }
+
+    // Return type: ["->" <type>]
+    fn return_type(&mut self) -> ParseResult<Option<Type>> {
+        if !self.next_is(&Token::Operator("->".to_string())) {
+            return Ok(None);
+        }
+        self.type_name().map(Some)
+    }
+
+    // Return statement: "return" [<expression>]
+    fn return_statement(&mut self) -> ParseResult<ASTNode> {
+        self.advance(); // "return"
+        match self.peek() {
+            Some(Token::Newline | Token::Dedent | Token::Eof) | None => Ok(ASTNode::Return(None)),
+            _ => Ok(ASTNode::Return(Some(Box::new(self.expression()?)))),
+        }
+    }
+
+    // Block: ":" Newline Indent {<statement>} Dedent | ":" <statement>
+    fn block(&mut self) -> ParseResult<Vec<ASTNode>> {
+        self.consume(Token::Colon, "':'")?;
+        if !self.next_is(&Token::Newline) {
+            // A body of one statement may follow on the same line
+            return Ok(vec![self.statement()?]);
+        }
+        self.skip_newlines();
+        self.consume(Token::Indent, "indented block")?;
+
+        let mut body = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.next_is(&Token::Dedent) {
+                break;
+            }
+            body.push(self.statement()?);
+        }
+        self.skip_newlines();
+
+        Ok(body)
+    }
 
     fn advance(&mut self) -> Option<&Token> {
         if self.position < self.tokens.len() {
@@ -160,127 +429,383 @@ impl Parser {
         self.tokens.get(self.position)
     }
 
-    fn is_at_end(&self) -> bool {
-        self.position >= self.tokens.len()
+    /// Consume the next token if it is `token`
+    fn next_is(&mut self, token: &Token) -> bool {
+        if self.peek() == Some(token) {
+            self.advance();
+            true
+        } else {
+            false
+        }
     }
 
-    fn consume(&mut self, token: Token) -> Option<&Token> {
-        if self.peek() == Some(&token) {
-            self.advance()
+    /// Consume `token`, described as `expected` if it is missing
+    fn consume(&mut self, token: Token, expected: &'static str) -> ParseResult<()> {
+        if self.next_is(&token) {
+            Ok(())
         } else {
-            None
+            Err(self.expected(expected))
         }
     }
 
-    fn consume_keyword(&mut self, kw: &str) -> Option<()> {
+    fn consume_identifier(&mut self) -> ParseResult<String> {
         match self.peek() {
-            Some(Token::Keyword(k)) if k == kw => {
+            Some(Token::Identifier(name)) => {
+                let name = name.clone();
                 self.advance();
-                Some(())
+                Ok(name)
             }
-            _ => None,
+            _ => Err(self.expected("name")),
         }
     }
 
-    fn consume_identifier(&mut self) -> Option<String> {
-        match self.peek() {
-            Some(Token::Identifier(name)) => {
-                let name = name.clone();
-                self.advance();
-                Some(name)
+    // Type annotation: [":" <type>]
+    fn type_annotation(&mut self) -> ParseResult<Option<Type>> {
+        if !self.next_is(&Token::Colon) {
+            return Ok(None);
+        }
+        self.type_name().map(Some)
+    }
+
+    // Type: <type name> | "[" <type> "]" | "fn" "(" [<type> {"," <type>}] ")" [<return type>]
+    fn type_name(&mut self) -> ParseResult<Type> {
+        if self.next_is(&Token::Operator("[".to_string())) {
+            let element = self.type_name()?;
+            self.consume(Token::Operator("]".to_string()), "']'")?;
+            return Ok(Type::array(element));
+        }
+        if self.next_is(&Token::Keyword("fn".to_string())) {
+            self.consume(Token::Operator("(".to_string()), "'('")?;
+            let mut params = Vec::new();
+            while !self.next_is(&Token::Operator(")".to_string())) {
+                if !params.is_empty() {
+                    self.consume(Token::Operator(",".to_string()), "',' between parameter types")?;
+                }
+                params.push(self.type_name()?);
             }
-            _ => None,
+            let ret = self.return_type()?.unwrap_or(Type::NIL);
+            return Ok(Type::function(params, ret));
+        }
+        match self.peek() {
+            Some(Token::Identifier(name)) => match ValueType::from_name(name).filter(|ty| *ty != ValueType::Nil) {
+                Some(ty) => {
+                    self.advance();
+                    Ok(Type::Basic(ty))
+                }
+                None => Err(self.expected("type")),
+            },
+            _ => Err(self.expected("type")),
         }
     }
 
-    // Variable declaration: "mut" <identifier> ["=" <expression>]
-    fn variable_declaration(&mut self) -> Option<ASTNode> {
-        self.consume_keyword("mut")?;
+    // Variable declaration: "mut" <identifier> [<type annotation>] ["=" <expression>]
+    fn variable_declaration(&mut self) -> ParseResult<ASTNode> {
+        self.advance(); // "mut"
         let name = self.consume_identifier()?;
+        let ty = self.type_annotation()?;
         
-        let value = if self.consume(Token::Operator("=".to_string())).is_some() {
+        let value = if self.next_is(&Token::Operator("=".to_string())) {
             Some(Box::new(self.expression()?))
         } else {
             None
         };
         
-        Some(ASTNode::VariableDeclaration {
+        Ok(ASTNode::VariableDeclaration {
             mutable: true,
             name,
+            ty,
             value,
         })
     }
 
-    // Assignment: <identifier> "=" <expression>
-    fn assignment(&mut self) -> Option<ASTNode> {
+    // Assignment: <identifier> [<type annotation>] "=" <expression>
+    fn assignment(&mut self) -> ParseResult<ASTNode> {
         let target = self.consume_identifier()?;
-        self.consume(Token::Operator("=".to_string()))?;
+        let ty = self.type_annotation()?;
+        self.consume(Token::Operator("=".to_string()), "'='")?;
         let value = Box::new(self.expression()?);
         
-        Some(ASTNode::Assignment { target, value })
+        Ok(ASTNode::Assignment { target, ty, value })
     }
 
-    // Print statement: "print" "(" <expression> ")"
-    fn print_statement(&mut self) -> Option<ASTNode> {
-        self.consume_keyword("print")?;
-        self.consume(Token::Operator("(".to_string()))?;
+    // Expression statement: <expression> | <index> "=" <expression>
+    fn expression_statement(&mut self) -> ParseResult<ASTNode> {
+        let line = self.error_line();
         let expr = self.expression()?;
-        self.consume(Token::Operator(")".to_string()))?;
-        Some(ASTNode::Print(vec![expr]))
+        if !self.next_is(&Token::Operator("=".to_string())) {
+            return Ok(expr);
+        }
+        match expr {
+            ASTNode::Index { array, index } => {
+                let value = Box::new(self.expression()?);
+                Ok(ASTNode::IndexAssignment { array, index, value })
+            }
+            _ => Err(ParseError::InvalidAssignmentTarget { line }),
+        }
     }
 
-    // Expression parsing with operator precedence
-    fn expression(&mut self) -> Option<ASTNode> {
-        self.term()
+    // Print statement: "print" "(" [<arguments>] ")"
+    fn print_statement(&mut self) -> ParseResult<ASTNode> {
+        self.advance(); // "print"
+        self.consume(Token::Operator("(".to_string()), "'('")?;
+        Ok(ASTNode::Print(self.arguments(")")?))
     }
 
-    fn term(&mut self) -> Option<ASTNode> {
-        let mut left = self.factor()?;
-        
-        while let Some(Token::Operator(op)) = self.peek() {
-            if op == "+" || op == "-" {
-                let op = op.clone();
-                self.advance();
-                let right = self.factor()?;
-                left = ASTNode::BinaryOp {
-                    op,
-                    left: Box::new(left),
-                    right: Box::new(right),
-                };
-            } else {
+    // Expression parsing by precedence climbing. Binding strength, loosest first:
+    //   or < and < comparisons < "+" "-" < "*" "/" "%" < "as" < unary "-" "not" < postfix < primary
+    fn expression(&mut self) -> ParseResult<ASTNode> {
+        self.binary_expression(1)
+    }
+
+    /// Precedence of the binary operator at the current position, if any
+    fn binary_precedence(&self) -> Option<(String, u8)> {
+        let op = match self.peek() {
+            Some(Token::Operator(op)) => op.as_str(),
+            Some(Token::Keyword(kw)) if kw == "and" || kw == "or" => kw.as_str(),
+            _ => return None,
+        };
+        let precedence = match op {
+            "or" => 1,
+            "and" => 2,
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => 3,
+            "+" | "-" => 4,
+            "*" | "/" | "%" => 5,
+            _ => return None,
+        };
+        Some((op.to_string(), precedence))
+    }
+
+    // All binary operators are left-associative
+    fn binary_expression(&mut self, min_precedence: u8) -> ParseResult<ASTNode> {
+        let mut left = self.cast()?;
+
+        while let Some((op, precedence)) = self.binary_precedence() {
+            if precedence < min_precedence {
                 break;
             }
+            self.advance();
+            let right = self.binary_expression(precedence + 1)?;
+            left = ASTNode::BinaryOp {
+                op,
+                left: Box::new(left),
+                right: Box::new(right),
+            };
         }
-        
-        Some(left)
+
+        Ok(left)
     }
 
-    fn factor(&mut self) -> Option<ASTNode> {
-        let mut left = self.primary()?;
-        
-        while let Some(Token::Operator(op)) = self.peek() {
-            if op == "*" || op == "/" {
-                let op = op.clone();
-                self.advance();
-                let right = self.primary()?;
-                left = ASTNode::BinaryOp {
-                    op,
-                    left: Box::new(left),
-                    right: Box::new(right),
+    // Cast: <unary> {"as" <type name>}
+    fn cast(&mut self) -> ParseResult<ASTNode> {
+        let mut value = self.unary()?;
+        while self.next_is(&Token::Keyword("as".to_string())) {
+            let ty = match self.peek() {
+                Some(Token::Identifier(name)) => ValueType::from_name(name).ok_or_else(|| self.expected("type"))?,
+                _ => return Err(self.expected("type")),
+            };
+            self.advance();
+            value = ASTNode::Cast { value: Box::new(value), ty };
+        }
+        Ok(value)
+    }
+
+    // Unary: ("-" | "not" | "!") <unary> | <postfix>
+    fn unary(&mut self) -> ParseResult<ASTNode> {
+        let op = match self.peek() {
+            Some(Token::Operator(op)) if op == "-" => "-",
+            Some(Token::Operator(op)) if op == "!" => "not",
+            Some(Token::Keyword(kw)) if kw == "not" => "not",
+            _ => return self.postfix(),
+        };
+        self.advance();
+        let operand = self.unary()?;
+
+        Ok(ASTNode::UnaryOp {
+            op: op.to_string(),
+            operand: Box::new(operand),
+        })
+    }
+
+    // Postfix: <primary> {"[" <expression> "]" | "[" <expression> <range> <expression> "]"
+    //                     | "." <identifier> "(" [<arguments>] ")" | "(" [<arguments>] ")"}
+    fn postfix(&mut self) -> ParseResult<ASTNode> {
+        let mut expr = self.primary()?;
+        loop {
+            if self.next_is(&Token::Operator("(".to_string())) {
+                let args = self.arguments(")")?;
+                expr = ASTNode::Call { callee: Box::new(expr), args };
+            } else if self.next_is(&Token::Operator("[".to_string())) {
+                let index = self.expression()?;
+                let inclusive = match self.peek() {
+                    Some(Token::RangeExclusive) => Some(false),
+                    Some(Token::RangeInclusive) => Some(true),
+                    _ => None,
                 };
+                expr = match inclusive {
+                    Some(inclusive) => {
+                        self.advance();
+                        let end = self.expression()?;
+                        ASTNode::Slice { array: Box::new(expr), start: Box::new(index), end: Box::new(end), inclusive }
+                    }
+                    None => ASTNode::Index { array: Box::new(expr), index: Box::new(index) },
+                };
+                self.consume(Token::Operator("]".to_string()), "']'")?;
+            } else if self.next_is(&Token::Operator(".".to_string())) {
+                let method = self.consume_identifier()?;
+                self.consume(Token::Operator("(".to_string()), "'('")?;
+                let args = self.arguments(")")?;
+                expr = ASTNode::MethodCall { object: Box::new(expr), method, args };
             } else {
-                break;
+                return Ok(expr);
             }
         }
-        
-        Some(left)
     }
 
-    fn primary(&mut self) -> Option<ASTNode> {
-        match self.advance() {
-            Some(Token::Number(n)) => Some(ASTNode::Number(*n)),
-            Some(Token::Identifier(name)) => Some(ASTNode::Identifier(name.clone())),
-            _ => None,
+    // Arguments: [<expression> {"," <expression>}] <close>, where <close> is
+    // the closing bracket
+    fn arguments(&mut self, close: &str) -> ParseResult<Vec<ASTNode>> {
+        let close = Token::Operator(close.to_string());
+        let mut args = Vec::new();
+        self.skip_newlines();
+        while !self.next_is(&close) {
+            if !args.is_empty() {
+                self.consume(Token::Operator(",".to_string()), "','")?;
+                self.skip_newlines();
+            }
+            args.push(self.expression()?);
+            self.skip_newlines();
         }
+        Ok(args)
     }
-}
\ No newline at end of file
+
+    fn primary(&mut self) -> ParseResult<ASTNode> {
+        let expr = match self.peek() {
+            Some(Token::Number(value, suffix)) => ASTNode::Number { value: *value, suffix: *suffix },
+            Some(Token::Float(x)) => ASTNode::Float(*x),
+            Some(Token::Str(text)) => ASTNode::String(text.clone()),
+            Some(Token::Identifier(name)) => ASTNode::Identifier(name.clone()),
+            Some(Token::Keyword(kw)) if kw == "true" => ASTNode::Boolean(true),
+            Some(Token::Keyword(kw)) if kw == "false" => ASTNode::Boolean(false),
+            Some(Token::Operator(op)) if op == "(" => {
+                self.advance();
+                let expr = self.expression()?;
+                self.consume(Token::Operator(")".to_string()), "')'")?;
+                return Ok(expr);
+            }
+            Some(Token::Operator(op)) if op == "[" => {
+                self.advance();
+                return self.arguments("]").map(ASTNode::Array);
+            }
+            // Anonymous function: "fn" "(" <params> ")" [<return type>] <block>
+            Some(Token::Keyword(kw)) if kw == "fn" => {
+                self.advance();
+                self.consume(Token::Operator("(".to_string()), "'('")?;
+                let params = self.parameters(")")?;
+                let return_type = self.return_type()?;
+                let body = self.block()?;
+                return Ok(ASTNode::Lambda { params, return_type, body });
+            }
+            // Lambda: "|" <params> "|" <expression>, returning the expression
+            Some(Token::Operator(op)) if op == "|" => {
+                self.advance();
+                let params = self.parameters("|")?;
+                let value = self.expression()?;
+                return Ok(ASTNode::Lambda { params, return_type: None, body: vec![ASTNode::Return(Some(Box::new(value)))] });
+            }
+            _ => return Err(self.expected("expression")),
+        };
+        self.advance();
+        Ok(expr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::lexer::Lexer;
+
+    fn parse(source: &str) -> ParseResult<Vec<ASTNode>> {
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.tokenize().expect("program lexes");
+        Parser::with_lines(tokens, lexer.token_lines().to_vec()).parse()
+    }
+
+    fn expected(line: usize, expected: &'static str, found: Token) -> ParseError {
+        ParseError::Expected { line, expected, found }
+    }
+
+    #[test]
+    fn if_without_a_condition() {
+        assert_eq!(parse("x = 1\nif x == :\n    print(1)\nprint(2)\n").unwrap_err(), expected(2, "expression", Token::Colon));
+        assert_eq!(parse("if:\n    print(1)\n").unwrap_err(), expected(1, "expression", Token::Colon));
+    }
+
+    #[test]
+    fn if_without_a_colon() {
+        assert_eq!(parse("x = 1\nif x == 1\n    print(1)\n").unwrap_err(), expected(2, "':'", Token::Newline));
+        assert_eq!(
+            parse("if x:\n    print(1)\nelse\n    print(2)\n").unwrap_err(),
+            expected(3, "':'", Token::Newline)
+        );
+    }
+
+    #[test]
+    fn if_without_an_indented_block() {
+        assert_eq!(
+            parse("if true:\nprint(1)\n").unwrap_err(),
+            expected(2, "indented block", Token::Keyword("print".to_string()))
+        );
+        assert_eq!(parse("if true:\n").unwrap_err(), expected(2, "indented block", Token::Eof));
+    }
+
+    #[test]
+    fn loop_headers_without_a_colon() {
+        assert_eq!(parse("x = 0\nwhile x < 3\n    x = x + 1\n").unwrap_err(), expected(2, "':'", Token::Newline));
+        assert_eq!(parse("for i in 0..3\n    print(i)\n").unwrap_err(), expected(1, "':'", Token::Newline));
+        assert_eq!(
+            parse("'outer while true:\n    break\n").unwrap_err(),
+            expected(1, "':'", Token::Keyword("while".to_string()))
+        );
+    }
+
+    #[test]
+    fn print_arguments() {
+        let ast = parse("print(1, \"a\", x)\nprint()\n").unwrap();
+        let counts: Vec<usize> = ast.iter()
+            .map(|stmt| match stmt {
+                ASTNode::Located { statement, .. } => match &**statement {
+                    ASTNode::Print(args) => args.len(),
+                    other => panic!("{:?}", other),
+                },
+                other => panic!("{:?}", other),
+            })
+            .collect();
+        assert_eq!(counts, [3, 0]);
+        assert_eq!(parse("print(1 2)\n").unwrap_err(), expected(1, "','", Token::Number(2, None)));
+    }
+
+    #[test]
+    fn one_statement_body_on_the_header_line() {
+        let ast = parse("fn f() -> i32: return 1\nif f() == 1: print(\"one\")\nprint(2)\n").unwrap();
+        assert_eq!(ast.len(), 3);
+        assert_eq!(parse("if true: print(1) print(2)\n").unwrap_err(), expected(1, "end of line", Token::Keyword("print".to_string())));
+    }
+
+    #[test]
+    fn statements_end_at_the_end_of_their_line() {
+        assert_eq!(
+            parse("print(1) print(2)\n").unwrap_err(),
+            expected(1, "end of line", Token::Keyword("print".to_string()))
+        );
+        assert_eq!(parse("x = 1\n1 = x\n").unwrap_err(), ParseError::InvalidAssignmentTarget { line: 2 });
+        assert_eq!(parse("f = fn() -> i32:\n    return 1\nprint(f())\n").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn comment_lines_keep_the_block_open() {
+        let ast = parse("if true:\n    x = 1\n// note\n    print(x)\n").unwrap();
+        let ASTNode::Located { statement, .. } = &ast[0] else { panic!("{:?}", ast) };
+        let ASTNode::If { then_branch, .. } = &**statement else { panic!("{:?}", statement) };
+        assert_eq!(then_branch.len(), 2);
+    }
+}
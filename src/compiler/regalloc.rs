@@ -0,0 +1,498 @@
+//! Linear-scan register allocation.
+//!
+//! `CodeGenerator` emits assembly over an unbounded supply of virtual
+//! registers (`v0`, `v1`, ...). This pass computes their liveness over the
+//! control-flow graph, maps them onto the VM's physical registers and spills
+//! to data memory when it runs out. The result is plain assembly that
+//! `assemble` accepts. Which registers an instruction reads and writes
+//! comes from the `instruction_set!` table. Calls need no special care:
+//! the callee runs in its own register window, so none of the caller's
+//! registers need saving.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::compiler::codegen::CompileError;
+use crate::vm::assembler::split_operands;
+use crate::vm::instruction::{Access, InstructionSet, OperandKind, OPCODES};
+
+/// Physical registers kept back to load and store spilled operands: one for
+/// each register operand of the instruction that has the most, so that all
+/// of them can be spilled at once
+const SCRATCH_REGISTERS: usize = max_register_operands();
+
+const fn max_register_operands() -> usize {
+    let mut max = 0;
+    let mut i = 0;
+    while i < OPCODES.len() {
+        let operands = OPCODES[i].operands;
+        let mut count = 0;
+        let mut j = 0;
+        while j < operands.len() {
+            if matches!(operands[j], OperandKind::Register(_)) {
+                count += 1;
+            }
+            j += 1;
+        }
+        if count > max {
+            max = count;
+        }
+        i += 1;
+    }
+    max
+}
+
+fn reads(kind: &OperandKind) -> bool {
+    matches!(kind, OperandKind::Register(Access::Read | Access::ReadWrite))
+}
+
+fn writes(kind: &OperandKind) -> bool {
+    matches!(kind, OperandKind::Register(Access::Write | Access::ReadWrite))
+}
+
+enum Line {
+    Label(String),
+    Instruction { instruction: InstructionSet, operands: Vec<String> },
+    Directive(String),      // debug info such as `.line 3`, no effect on execution
+}
+
+/// Where a virtual register lives after allocation
+#[derive(Debug, Clone, Copy)]
+enum Location {
+    Register(usize),
+    Spill(usize),
+}
+
+struct Interval {
+    vreg: u32,
+    start: usize,
+    end: usize,
+}
+
+fn virtual_register(operand: &str) -> Option<u32> {
+    operand.strip_prefix('v')?.parse().ok()
+}
+
+/// Split assembly into lines; every instruction must be known and have as
+/// many operands as its kinds in the `instruction_set!` table tell
+fn parse(asm: &str) -> Result<Vec<Line>, CompileError> {
+    let mut lines = Vec::new();
+    for line in asm.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("//") {
+            continue;
+        }
+        if line.ends_with(':') {
+            lines.push(Line::Label(line.trim_end_matches(':').trim().to_string()));
+            continue;
+        }
+        if line.starts_with('.') {
+            lines.push(Line::Directive(line.to_string()));
+            continue;
+        }
+        let parts = split_operands(line);
+        let instruction = InstructionSet::from_mnemonic(parts[0])
+            .filter(|instruction| instruction.operand_count() == parts.len() - 1)
+            .ok_or_else(|| CompileError::InvalidInstruction(line.to_string()))?;
+        let operands = parts[1..].iter().map(|operand| operand.to_string()).collect();
+        lines.push(Line::Instruction { instruction, operands });
+    }
+    Ok(lines)
+}
+
+/// Virtual registers read and written by each line
+fn uses_and_defs(lines: &[Line]) -> Vec<(HashSet<u32>, HashSet<u32>)> {
+    lines
+        .iter()
+        .map(|line| {
+            let mut uses = HashSet::new();
+            let mut defs = HashSet::new();
+            if let Line::Instruction { instruction, operands } = line {
+                for (operand, kind) in operands.iter().zip(instruction.operands()) {
+                    let Some(vreg) = virtual_register(operand) else { continue };
+                    if reads(kind) {
+                        uses.insert(vreg);
+                    }
+                    if writes(kind) {
+                        defs.insert(vreg);
+                    }
+                }
+            }
+            (uses, defs)
+        })
+        .collect()
+}
+
+/// Control-flow successors of each line
+fn successors(lines: &[Line]) -> Result<Vec<Vec<usize>>, CompileError> {
+    let labels: HashMap<&str, usize> = lines
+        .iter()
+        .enumerate()
+        .filter_map(|(i, line)| match line {
+            Line::Label(name) => Some((name.as_str(), i)),
+            _ => None,
+        })
+        .collect();
+    let target = |label: &String| labels.get(label.as_str()).copied().ok_or_else(|| CompileError::UnknownLabel(label.clone()));
+
+    lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| {
+            let mut next = match line {
+                Line::Label(_) | Line::Directive(_) => vec![i + 1],
+                Line::Instruction { instruction, operands } => match instruction {
+                    InstructionSet::Halt | InstructionSet::Ret => vec![],
+                    InstructionSet::Jump => vec![target(&operands[0])?],
+                    InstructionSet::JumpIfZero | InstructionSet::JumpIfNotZero | InstructionSet::JumpIfLess => {
+                        vec![i + 1, target(&operands[operands.len() - 1])?]
+                    }
+                    _ => vec![i + 1],
+                },
+            };
+            next.retain(|&s| s < lines.len());
+            Ok(next)
+        })
+        .collect()
+}
+
+/// Live ranges of every virtual register, from iterative backward liveness.
+/// A register live around a loop back-edge stays live for the whole loop,
+/// and those in `live_out` stay live to the end of the code.
+fn live_intervals(lines: &[Line], live_out: &HashSet<u32>) -> Result<Vec<Interval>, CompileError> {
+    let use_def = uses_and_defs(lines);
+    let succs = successors(lines)?;
+    let mut live_in: Vec<HashSet<u32>> = vec![HashSet::new(); lines.len()];
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in (0..lines.len()).rev() {
+            let (uses, defs) = &use_def[i];
+            let live_out: HashSet<u32> = succs[i].iter().flat_map(|&s| live_in[s].iter().copied()).collect();
+            let mut new_in: HashSet<u32> = live_out.difference(defs).copied().collect();
+            new_in.extend(uses);
+            if new_in != live_in[i] {
+                live_in[i] = new_in;
+                changed = true;
+            }
+        }
+    }
+
+    let mut ranges: HashMap<u32, (usize, usize)> = HashMap::new();
+    for (i, (_, defs)) in use_def.iter().enumerate() {
+        for &vreg in live_in[i].iter().chain(defs) {
+            let range = ranges.entry(vreg).or_insert((i, i));
+            range.0 = range.0.min(i);
+            range.1 = range.1.max(i);
+        }
+    }
+
+    for (vreg, range) in ranges.iter_mut() {
+        if live_out.contains(vreg) {
+            range.1 = lines.len();
+        }
+    }
+
+    let mut intervals: Vec<Interval> = ranges
+        .into_iter()
+        .map(|(vreg, (start, end))| Interval { vreg, start, end })
+        .collect();
+    intervals.sort_by_key(|iv| (iv.start, iv.vreg));
+    Ok(intervals)
+}
+
+/// Assign each interval a physical register or a spill slot. When every
+/// register is taken, the interval that ends last is spilled. Pinned
+/// registers are held for the whole code and never handed out.
+fn linear_scan(
+    intervals: &[Interval],
+    num_registers: usize,
+    max_spill_slots: usize,
+    pinned: &HashMap<u32, usize>,
+) -> Result<HashMap<u32, Location>, CompileError> {
+    let mut free: BTreeSet<usize> = (0..num_registers).collect();
+    let mut active: Vec<(usize, u32)> = Vec::new(); // (end, vreg)
+    let mut locations = HashMap::new();
+    for (&vreg, &reg) in pinned {
+        free.remove(&reg);
+        locations.insert(vreg, Location::Register(reg));
+    }
+    let mut spill_slots = 0;
+    let mut new_spill_slot = || {
+        if spill_slots >= max_spill_slots {
+            return Err(CompileError::OutOfSpillSlots { available: max_spill_slots });
+        }
+        spill_slots += 1;
+        Ok(Location::Spill(spill_slots - 1))
+    };
+
+    for interval in intervals.iter().filter(|iv| !pinned.contains_key(&iv.vreg)) {
+        active.retain(|&(end, vreg)| {
+            if end < interval.start {
+                if let Some(Location::Register(reg)) = locations.get(&vreg) {
+                    free.insert(*reg);
+                }
+                false
+            } else {
+                true
+            }
+        });
+
+        if let Some(reg) = free.pop_first() {
+            locations.insert(interval.vreg, Location::Register(reg));
+            active.push((interval.end, interval.vreg));
+            continue;
+        }
+
+        let (victim_idx, &(victim_end, victim)) = active
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, (end, _))| *end)
+            .expect("no allocatable registers");
+        if victim_end > interval.end {
+            let reg = locations[&victim];
+            locations.insert(victim, new_spill_slot()?);
+            locations.insert(interval.vreg, reg);
+            active.swap_remove(victim_idx);
+            active.push((interval.end, interval.vreg));
+        } else {
+            locations.insert(interval.vreg, new_spill_slot()?);
+        }
+    }
+
+    Ok(locations)
+}
+
+/// Rewrite virtual-register assembly to use at most `num_registers` physical
+/// registers, spilling into memory words `0..max_spill_slots`.
+pub fn allocate(asm: &str, num_registers: usize, max_spill_slots: usize) -> Result<String, CompileError> {
+    allocate_pinned(asm, num_registers, max_spill_slots, &HashMap::new(), &HashSet::new()).map(|(asm, _)| asm)
+}
+
+/// Like `allocate`, for code that shares registers with code run before or
+/// after it. `pinned` virtual registers keep the physical register given,
+/// and `live_out` ones must still be in a register when the code ends.
+/// Returns the physical register of every `live_out` virtual register.
+pub fn allocate_pinned(
+    asm: &str,
+    num_registers: usize,
+    max_spill_slots: usize,
+    pinned: &HashMap<u32, usize>,
+    live_out: &HashSet<u32>,
+) -> Result<(String, HashMap<u32, usize>), CompileError> {
+    if num_registers <= SCRATCH_REGISTERS {
+        return Err(CompileError::TooFewRegisters { available: num_registers, required: SCRATCH_REGISTERS });
+    }
+    let allocatable = num_registers - SCRATCH_REGISTERS;
+    if pinned.values().any(|&reg| reg >= allocatable) {
+        return Err(CompileError::TooManyVariables { available: allocatable });
+    }
+
+    let lines = parse(asm)?;
+    let intervals = live_intervals(&lines, live_out)?;
+    let locations = linear_scan(&intervals, allocatable, max_spill_slots, pinned)?;
+
+    let mut registers = HashMap::new();
+    for &vreg in live_out {
+        match locations.get(&vreg) {
+            Some(Location::Register(reg)) => {
+                registers.insert(vreg, *reg);
+            }
+            _ => return Err(CompileError::TooManyVariables { available: allocatable }),
+        }
+    }
+
+    let mut out = String::new();
+    for line in &lines {
+        let (instruction, operands) = match line {
+            Line::Label(name) => {
+                out.push_str(&format!("{}:\n", name));
+                continue;
+            }
+            Line::Directive(directive) => {
+                // `.var name vN` becomes `.var name Rn`, or `.var name @slot`
+                // when spilled; variables that are never live are dropped
+                let parts: Vec<&str> = directive.split_whitespace().collect();
+                match (parts.as_slice(), parts.last().and_then(|op| virtual_register(op))) {
+                    ([".var", name, _], Some(vreg)) => match locations.get(&vreg) {
+                        Some(Location::Register(reg)) => out.push_str(&format!(".var {} R{}\n", name, reg)),
+                        Some(Location::Spill(slot)) => out.push_str(&format!(".var {} @{}\n", name, slot)),
+                        None => {}
+                    },
+                    _ => {
+                        out.push_str(directive);
+                        out.push('\n');
+                    }
+                }
+                continue;
+            }
+            Line::Instruction { instruction, operands } => (*instruction, operands),
+        };
+
+        let mut reloads = Vec::new();
+        let mut stores = Vec::new();
+        let mut scratch: HashMap<u32, usize> = HashMap::new();
+        let mut physical = Vec::new();
+        for (operand, kind) in operands.iter().zip(instruction.operands()) {
+            let Some(vreg) = virtual_register(operand) else {
+                physical.push(operand.clone());
+                continue;
+            };
+            let reg = match locations.get(&vreg) {
+                Some(Location::Register(reg)) => *reg,
+                Some(Location::Spill(slot)) => {
+                    let next = allocatable + scratch.len();
+                    let reg = *scratch.entry(vreg).or_insert(next);
+                    let reload = format!("LOAD R{}, {}", reg, slot);
+                    if reads(kind) && !reloads.contains(&reload) {
+                        reloads.push(reload);
+                    }
+                    let store = format!("STORE R{}, {}", reg, slot);
+                    if writes(kind) && !stores.contains(&store) {
+                        stores.push(store);
+                    }
+                    reg
+                }
+                None => return Err(CompileError::UnallocatedRegister(vreg)),
+            };
+            physical.push(format!("R{}", reg));
+        }
+
+        // Moves that allocation turned into no-ops are dropped
+        if instruction == InstructionSet::Mov && physical[0] == physical[1] && reloads.is_empty() {
+            continue;
+        }
+
+        for reload in &reloads {
+            out.push_str(reload);
+            out.push('\n');
+        }
+        out.push_str(instruction.mnemonic());
+        if !physical.is_empty() {
+            out.push(' ');
+            out.push_str(&physical.join(", "));
+        }
+        out.push('\n');
+        for store in &stores {
+            out.push_str(store);
+            out.push('\n');
+        }
+    }
+    Ok((out, registers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::compiler::codegen::tests::{compile, run};
+    use crate::vm::assembler::assemble;
+    use crate::vm::machine::{VmConfig, VM};
+
+    #[test]
+    fn scratch_registers_cover_every_register_operand() {
+        assert_eq!(SCRATCH_REGISTERS, 4);
+    }
+
+    #[test]
+    fn spilled_operands_of_three_register_instructions() {
+        // A single allocatable register spills every operand of ARRAY_SLICE
+        // and ARRAY_SET
+        let config = VmConfig { num_registers: SCRATCH_REGISTERS + 1, ..VmConfig::default() };
+        let source = "a = [1, 2, 3, 4, 5]\ns = 1\ne = 3\nb = a[s..e]\na[s] = e\nprint(b)\nprint(a)\n";
+        assert_eq!(run(source, config), "[2, 3]\n[1, 3, 3, 4, 5]\n");
+    }
+
+    #[test]
+    fn spilled_code_stays_within_the_register_file() {
+        let asm = "NEW_ARRAY v0\nLOAD_CONST v1, 0\nLOAD_CONST v2, 1\nARRAY_SLICE v3, v0, v1, v2\nARRAY_SET v0, v1, v2\nPRINT_REG v3\nPRINT_REG v0\nPRINT_REG v1\nPRINT_REG v2\nHALT\n";
+        let num_registers = SCRATCH_REGISTERS + 1;
+        let out = allocate(asm, num_registers, 16).unwrap();
+        for operand in out.split(|c: char| c.is_whitespace() || c == ',') {
+            if let Some(reg) = operand.strip_prefix('R').and_then(|n| n.parse::<usize>().ok()) {
+                assert!(reg < num_registers, "R{} in:\n{}", reg, out);
+            }
+        }
+    }
+
+    /// Allocate `asm` onto `num_registers` registers, run it on a VM with
+    /// that many and return what it prints
+    fn run_allocated(asm: &str, num_registers: usize) -> (String, String) {
+        let allocated = allocate(asm, num_registers, 16).expect("allocation succeeds");
+        let module = assemble(&allocated).expect("allocated code assembles");
+        let output = Rc::new(RefCell::new(String::new()));
+        let mut vm = VM::with_config(VmConfig { num_registers, ..VmConfig::default() });
+        let sink = Rc::clone(&output);
+        vm.set_output_callback(move |line| sink.borrow_mut().push_str(&format!("{}\n", line)));
+        vm.load_module(&module);
+        vm.run().expect("allocated code runs");
+        let output = output.borrow().clone();
+        (allocated, output)
+    }
+
+    #[test]
+    fn too_few_registers_spill_to_memory() {
+        // Four values live at once, and room for two of them
+        let asm = "LOAD_CONST v0, 1\nLOAD_CONST v1, 2\nLOAD_CONST v2, 3\nLOAD_CONST v3, 4\n\
+                   ADD v0, v1\nADD v2, v3\nADD v0, v2\nPRINT_REG v0\nPRINT_REG v1\nPRINT_REG v3\nHALT\n";
+        let (allocated, output) = run_allocated(asm, SCRATCH_REGISTERS + 2);
+        assert!(allocated.contains("STORE ") && allocated.contains("LOAD "), "{}", allocated);
+        assert_eq!(output, "10\n2\n4\n");
+
+        let (allocated, output) = run_allocated(asm, 16);
+        assert!(!allocated.contains("STORE "), "{}", allocated);
+        assert_eq!(output, "10\n2\n4\n");
+
+        // Whole programs print the same with a register file a fraction of
+        // the size; functions cannot spill, and add needs three
+        let source = "\
+fn add(a: i32, b: i32) -> i32:
+    return a + b
+a = 1
+b = 2
+c = 3
+d = 4
+e = add(a, b)
+f = add(c, d)
+mut total = 0
+for i in 0..4:
+    total = total + i * a + b * c + d * e + f
+print(a, b, c, d, e, f, total)
+";
+        let expected = "1 2 3 4 3 7 106\n";
+        assert_eq!(run(source, VmConfig::default()), expected);
+        let small = VmConfig { num_registers: SCRATCH_REGISTERS + 3, ..VmConfig::default() };
+        assert!(compile(source, small).unwrap().contains("STORE "));
+        assert_eq!(run(source, small), expected);
+    }
+
+    #[test]
+    fn values_live_across_calls_keep_their_registers() {
+        let asm = "JMP main\nf:\nLOAD_CONST v0, 40\nRET v0\nmain:\n\
+                   LOAD_CONST v1, 2\nCALL v2, f\nADD v1, v2\nPRINT_REG v1\nHALT\n";
+        let (allocated, output) = run_allocated(asm, 16);
+        assert_eq!(output, "42\n");
+        // v1 is written before the call and read after it in the same
+        // register, which the call's result does not take
+        let lines: Vec<&str> = allocated.lines().collect();
+        let load = lines.iter().find(|line| line.starts_with("LOAD_CONST") && line.ends_with(", 2")).unwrap();
+        let call = lines.iter().find(|line| line.starts_with("CALL")).unwrap();
+        let add = lines.iter().find(|line| line.starts_with("ADD")).unwrap();
+        let value_reg = load.split([' ', ',']).nth(1).unwrap();
+        let result_reg = call.split([' ', ',']).nth(1).unwrap();
+        assert_eq!(*add, format!("ADD {}, {}", value_reg, result_reg), "{}", allocated);
+        assert_ne!(value_reg, result_reg);
+        assert!(!allocated.contains("STORE "), "{}", allocated);
+    }
+
+    #[test]
+    fn malformed_assembly() {
+        assert_eq!(
+            allocate("LOAD_CONST v0, 1\nFROB v0\n", 8, 0),
+            Err(CompileError::InvalidInstruction("FROB v0".to_string()))
+        );
+        assert_eq!(allocate("MOV v0\n", 8, 0), Err(CompileError::InvalidInstruction("MOV v0".to_string())));
+        assert_eq!(allocate("JMP nowhere\n", 8, 0), Err(CompileError::UnknownLabel("nowhere".to_string())));
+    }
+}
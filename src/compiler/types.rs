@@ -0,0 +1,86 @@
+//! Static types, as the parser reads them from annotations and the code
+//! generator checks them.
+
+use std::fmt;
+
+use crate::vm::value::ValueType;
+
+/// Type of a variable or expression. Basic types are the VM's value types;
+/// arrays also carry their element type, as in `[i32]`, and functions their
+/// parameter and return types, as in `fn(i32, i32) -> bool`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Type {
+    Basic(ValueType),
+    Array(Box<Type>),
+    Function { params: Vec<Type>, ret: Box<Type> },
+}
+
+impl Type {
+    pub const I32: Type = Type::Basic(ValueType::I32);
+    pub const BOOL: Type = Type::Basic(ValueType::Bool);
+    pub const NIL: Type = Type::Basic(ValueType::Nil);
+    pub const STR: Type = Type::Basic(ValueType::Str);
+
+    pub fn array(element: Type) -> Self {
+        Type::Array(Box::new(element))
+    }
+
+    pub fn function(params: Vec<Type>, ret: Type) -> Self {
+        Type::Function { params, ret: Box::new(ret) }
+    }
+
+    /// The value type, unless this is an array or function type
+    pub fn basic(&self) -> Option<ValueType> {
+        match self {
+            Type::Basic(ty) => Some(*ty),
+            _ => None,
+        }
+    }
+
+    /// Element type of an array type
+    pub fn element(&self) -> Option<&Type> {
+        match self {
+            Type::Array(element) => Some(element),
+            _ => None,
+        }
+    }
+
+    /// Parameter and return types of a function type
+    pub fn signature(&self) -> Option<(&[Type], &Type)> {
+        match self {
+            Type::Function { params, ret } => Some((params, ret)),
+            _ => None,
+        }
+    }
+
+    pub fn is_integer(&self) -> bool {
+        self.basic().is_some_and(ValueType::is_integer)
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        self.basic().is_some_and(ValueType::is_numeric)
+    }
+}
+
+impl From<ValueType> for Type {
+    fn from(ty: ValueType) -> Self {
+        Type::Basic(ty)
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Basic(ty) => write!(f, "{}", ty),
+            Type::Array(element) => write!(f, "[{}]", element),
+            Type::Function { params, ret } => {
+                let params: Vec<String> = params.iter().map(Type::to_string).collect();
+                write!(f, "fn({})", params.join(", "))?;
+                if **ret != Type::NIL {
+                    write!(f, " -> {}", ret)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
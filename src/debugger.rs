@@ -0,0 +1,363 @@
+//! Interactive, gdb-like debugger for compiled programs.
+//!
+//! Commands refer to source lines, assembly labels or raw addresses
+//! (`*12`). The mapping between them comes from the module's debug info.
+
+use std::io::{self, BufRead, Write};
+
+use crate::vm::bytecode::{decode, BytecodeError, DebugInfo, Module, VarLocation};
+use crate::vm::assembler::quote_string;
+use crate::vm::disassembler::{listing, ListingLine};
+use crate::vm::error::{ExitStatus, VmError};
+use crate::vm::instruction::OperandKind;
+use crate::vm::machine::VM;
+use crate::vm::value::Value;
+
+/// `step` and `next` give up on a source line after this many instructions
+const MAX_LINE_STEPS: u64 = 1_000_000;
+
+const HELP: &str = "\
+break <line|label|*pc>   set a breakpoint (alias b)
+delete <line|label|*pc>  remove a breakpoint
+watch <var>              stop when a variable changes
+step                     run to the next source line, into calls (alias s)
+next                     run to the next source line, over calls (alias n)
+stepi                    execute one instruction (alias si)
+continue                 run to the next breakpoint or the end (alias c)
+regs                     show the program counter and the current call's registers
+print <var|Rn>           show a variable or register (alias p)
+disasm                   show the program, marking the current instruction
+backtrace                show where the program is stopped and the calls leading there (alias bt)
+quit                     leave the debugger (alias q)";
+
+pub struct Debugger {
+    vm: VM,
+    debug: DebugInfo,
+    source: Vec<String>,
+    listing: Vec<ListingLine>,
+    registers_used: usize,      // registers shown by `regs`
+}
+
+impl Debugger {
+    /// Debug `module` on `vm`; `source` is the program text, if available,
+    /// used to show the current line
+    pub fn new(mut vm: VM, module: &Module, source: Option<&str>) -> Result<Self, BytecodeError> {
+        let listing = listing(module)?;
+        let registers_used = decode(&module.code, &module.constants)?
+            .iter()
+            .flat_map(|d| d.instruction.operands().iter().zip(d.operands))
+            .filter(|(kind, _)| matches!(kind, OperandKind::Register(_)))
+            .map(|(_, &reg)| reg as usize + 1)
+            .max()
+            .unwrap_or(0);
+        vm.load_module(module);
+        Ok(Debugger {
+            vm,
+            debug: module.debug_info.clone().unwrap_or_default(),
+            source: source.map(|s| s.lines().map(str::to_string).collect()).unwrap_or_default(),
+            listing,
+            registers_used,
+        })
+    }
+
+    /// Read and execute commands until `quit` or the end of `input`
+    pub fn run(&mut self, input: impl BufRead, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "{} instructions loaded. Type `help` for commands.", self.listing.iter().filter(|l| l.pc.is_some()).count())?;
+        write!(out, "(orus) ")?;
+        out.flush()?;
+        for line in input.lines() {
+            if !self.execute(line?.trim(), out)? {
+                return Ok(());
+            }
+            write!(out, "(orus) ")?;
+            out.flush()?;
+        }
+        writeln!(out)
+    }
+
+    /// Execute one command; returns false when the session should end
+    pub fn execute(&mut self, command: &str, out: &mut impl Write) -> io::Result<bool> {
+        let (name, arg) = match command.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (command, ""),
+        };
+        match name {
+            "" => {}
+            "break" | "b" => match self.resolve(arg) {
+                Some(pc) => {
+                    self.vm.add_breakpoint(pc);
+                    writeln!(out, "Breakpoint at {}", self.describe(pc))?;
+                }
+                None => writeln!(out, "No line, label or address {:?}", arg)?,
+            },
+            "delete" => match self.resolve(arg) {
+                Some(pc) if self.vm.remove_breakpoint(pc) => writeln!(out, "Deleted breakpoint at pc {}", pc)?,
+                _ => writeln!(out, "No breakpoint at {:?}", arg)?,
+            },
+            "watch" => match self.debug.variable_at(arg, self.vm.pc) {
+                Some(VarLocation::Register(reg)) => {
+                    self.vm.add_watchpoint(self.vm.frame_base() + reg);
+                    writeln!(out, "Watching {} (R{})", arg, reg)?;
+                }
+                Some(VarLocation::Memory(_)) => writeln!(out, "{} lives in memory and cannot be watched", arg)?,
+                None => writeln!(out, "No variable {:?} here", arg)?,
+            },
+            "step" | "s" => {
+                let result = self.next_line(false);
+                self.report(result, out)?;
+            }
+            "next" | "n" => {
+                let result = self.next_line(true);
+                self.report(result, out)?;
+            }
+            "stepi" | "si" => {
+                let result = self.vm.step();
+                self.report(result, out)?;
+            }
+            "continue" | "c" => {
+                // Leave a breakpoint we are stopped on before running again
+                let result = if self.at_breakpoint() {
+                    match self.vm.step() {
+                        Ok(ExitStatus::Running) => self.vm.run(),
+                        other => other,
+                    }
+                } else {
+                    self.vm.run()
+                };
+                self.report(result, out)?;
+            }
+            "regs" => {
+                writeln!(out, "pc = {}", self.vm.pc)?;
+                let window = &self.vm.registers[self.vm.frame_base()..];
+                for (reg, value) in window.iter().enumerate().take(self.registers_used) {
+                    writeln!(out, "R{:<3} = {}", reg, self.show(*value))?;
+                }
+            }
+            "print" | "p" => match self.read_value(arg) {
+                Ok(value) => writeln!(out, "{} = {}", arg, self.show(value))?,
+                Err(message) => writeln!(out, "{}", message)?,
+            },
+            "disasm" => {
+                let breakpoints: Vec<usize> = self.vm.breakpoints().collect();
+                for line in &self.listing {
+                    match line.pc {
+                        Some(pc) => {
+                            let marker = if pc == self.vm.pc { "=>" } else { "  " };
+                            let stop = if breakpoints.contains(&pc) { '*' } else { ' ' };
+                            writeln!(out, "{}{}{:4}  {}", marker, stop, pc, line.text)?;
+                        }
+                        None => writeln!(out, "         {}", line.text)?,
+                    }
+                }
+            }
+            "backtrace" | "bt" => {
+                writeln!(out, "#0  {}", self.describe(self.vm.pc))?;
+                for (depth, frame) in self.vm.frames.iter().rev().enumerate() {
+                    writeln!(out, "#{:<2} {}", depth + 1, self.describe(frame.call_pc))?;
+                }
+            }
+            "help" | "h" => writeln!(out, "{}", HELP)?,
+            "quit" | "q" => return Ok(false),
+            _ => writeln!(out, "Unknown command {:?}; type `help` for commands", name)?,
+        }
+        Ok(true)
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        self.vm.breakpoints().any(|pc| pc == self.vm.pc)
+    }
+
+    /// Address named by a breakpoint argument
+    fn resolve(&self, arg: &str) -> Option<usize> {
+        if let Some(pc) = arg.strip_prefix('*') {
+            return pc.parse().ok();
+        }
+        match arg.parse::<usize>() {
+            Ok(line) => self.debug.line_pc(line),
+            Err(_) => self.debug.label_pc(arg),
+        }
+    }
+
+    /// Value of a variable at the current pc, or else of a register `Rn`, in
+    /// the current call's window; the error is the message to show
+    fn read_value(&self, name: &str) -> Result<Value, String> {
+        let location = match self.debug.variable_at(name, self.vm.pc) {
+            Some(location) => location,
+            None => match name.strip_prefix(['R', 'r']).and_then(|reg| reg.parse().ok()) {
+                Some(reg) => VarLocation::Register(reg),
+                None => return Err(format!("No variable {:?} here", name)),
+            },
+        };
+        let num_registers = self.vm.config().num_registers;
+        match location {
+            VarLocation::Register(reg) if reg >= num_registers => {
+                Err(format!("No register R{}: each call has {} registers", reg, num_registers))
+            }
+            VarLocation::Register(reg) => Ok(self.vm.registers[self.vm.frame_base() + reg]),
+            VarLocation::Memory(addr) => {
+                self.vm.memory.get(addr).copied().ok_or_else(|| format!("No memory address {}", addr))
+            }
+        }
+    }
+
+    /// A value as the debugger shows it; strings are quoted
+    fn show(&self, value: Value) -> String {
+        match value {
+            Value::Str(obj) => quote_string(self.vm.heap.string(obj)),
+            other => self.vm.format_value(other),
+        }
+    }
+
+    /// Step until execution reaches a different source line. With
+    /// `over_calls`, calls made on the way run to completion; without it,
+    /// entering or leaving a call stops at the line execution moves to.
+    fn next_line(&mut self, over_calls: bool) -> Result<ExitStatus, VmError> {
+        let line = self.debug.line_at(self.vm.pc);
+        let depth = self.vm.frames.len();
+        for _ in 0..MAX_LINE_STEPS {
+            let status = self.vm.step()?;
+            if status != ExitStatus::Running {
+                return Ok(status);
+            }
+            let new_line = self.debug.line_at(self.vm.pc);
+            let stop = if over_calls {
+                self.vm.frames.len() <= depth && new_line != line
+            } else {
+                new_line.is_some() && (new_line != line || self.vm.frames.len() != depth)
+            };
+            if stop {
+                return Ok(ExitStatus::Running);
+            }
+            if self.at_breakpoint() {
+                return Ok(ExitStatus::Breakpoint);
+            }
+        }
+        Ok(ExitStatus::Running)
+    }
+
+    fn report(&self, result: Result<ExitStatus, VmError>, out: &mut impl Write) -> io::Result<()> {
+        match result {
+            Ok(ExitStatus::Running) => writeln!(out, "{}", self.describe(self.vm.pc)),
+            Ok(ExitStatus::Breakpoint) => writeln!(out, "Breakpoint, {}", self.describe(self.vm.pc)),
+            Ok(ExitStatus::Watchpoint { register, old, new }) => {
+                writeln!(out, "R{} changed: {} -> {}", register, self.show(old), self.show(new))?;
+                writeln!(out, "{}", self.describe(self.vm.pc))
+            }
+            Ok(ExitStatus::Halted) | Ok(ExitStatus::EndOfProgram) => {
+                writeln!(out, "Program finished after {} instructions", self.vm.instruction_count)
+            }
+            Err(err) => writeln!(out, "Runtime error: {}\n{}", err, self.describe(err.pc())),
+        }
+    }
+
+    /// `pc 12 in loop_0, line 3: print(x)`, leaving out what is unknown
+    fn describe(&self, pc: usize) -> String {
+        let mut text = format!("pc {}", pc);
+        if let Some(label) = self.debug.label_at(pc) {
+            text.push_str(&format!(" in {}", label));
+        }
+        if let Some(line) = self.debug.line_at(pc) {
+            text.push_str(&format!(", line {}", line));
+            if let Some(source) = line.checked_sub(1).and_then(|i| self.source.get(i)) {
+                text.push_str(&format!(": {}", source.trim()));
+            }
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::codegen::tests::compile;
+    use crate::vm::assembler::assemble;
+    use crate::vm::machine::VmConfig;
+
+    /// Run debugger `commands` on `asm`, returning what the debugger printed
+    fn debug(asm: &str, commands: &str) -> String {
+        let module = assemble(asm).expect("test program assembles");
+        let mut vm = VM::new();
+        vm.set_output(Box::new(io::sink()));
+        let mut debugger = Debugger::new(vm, &module, None).expect("module decodes");
+        let mut out = Vec::new();
+        debugger.run(commands.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    const PROGRAM: &str = "\
+fn double(n: i32) -> i32:
+    m = n * 2
+    return m
+
+mut total = 0
+for i in 0..3:
+    total = total + double(i)
+print(total)
+";
+
+    /// Run debugger `commands` on the compiled `PROGRAM`, showing its source
+    fn debug_program(commands: &str) -> String {
+        let asm = compile(PROGRAM, VmConfig::default()).expect("program compiles");
+        let module = assemble(&format!("{}\nHALT", asm)).expect("generated code assembles");
+        let mut vm = VM::new();
+        vm.set_output(Box::new(io::sink()));
+        let mut debugger = Debugger::new(vm, &module, Some(PROGRAM)).expect("module decodes");
+        let mut out = Vec::new();
+        debugger.run(commands.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// What the debugger printed after the prompt for each command
+    fn replies(out: &str) -> Vec<&str> {
+        out.split("(orus) ").skip(1).map(str::trim_end).collect()
+    }
+
+    #[test]
+    fn break_by_line() {
+        let out = debug_program("break 7\ncontinue\nprint i\ncontinue\nprint i\nbreak 4\n");
+        let replies = replies(&out);
+        assert_eq!(replies[0], "Breakpoint at pc 29 in for_body_0, line 7: total = total + double(i)");
+        assert_eq!(replies[1], "Breakpoint, pc 29 in for_body_0, line 7: total = total + double(i)");
+        assert_eq!(replies[2], "i = 0");
+        assert_eq!(replies[4], "i = 1");
+        assert_eq!(replies[5], "No line, label or address \"4\"");
+    }
+
+    #[test]
+    fn next_runs_calls_and_step_enters_them() {
+        let out = debug_program("break 7\ncontinue\nnext\nnext\nstep\nstep\nbacktrace\nstepi\nstep\nstep\n");
+        let replies = replies(&out);
+        assert_eq!(replies[2], "pc 43 in for_step_1, line 6: for i in 0..3:");
+        assert_eq!(replies[3], "pc 29 in for_body_0, line 7: total = total + double(i)");
+        assert_eq!(replies[4], "pc 2 in fn_double, line 1: fn double(n: i32) -> i32:");
+        assert_eq!(replies[5], "pc 4 in fn_double, line 2: m = n * 2");
+        assert_eq!(
+            replies[6],
+            "#0  pc 4 in fn_double, line 2: m = n * 2\n#1  pc 34 in for_body_0, line 7: total = total + double(i)"
+        );
+        assert_eq!(replies[7], "pc 7 in fn_double, line 2: m = n * 2");
+        assert_eq!(replies[8], "pc 13 in fn_double, line 3: return m");
+        assert_eq!(replies[9], "pc 37 in for_body_0, line 7: total = total + double(i)");
+    }
+
+    #[test]
+    fn watch_stops_when_a_variable_changes() {
+        let out = debug_program("watch total\nbreak 6\ncontinue\nwatch total\ncontinue\ncontinue\ncontinue\n");
+        let replies = replies(&out);
+        assert_eq!(replies[0], "No variable \"total\" here");
+        assert_eq!(replies[3], "Watching total (R0)");
+        // Adding double(0) leaves total as it was
+        assert_eq!(replies[4], "R0 changed: 0 -> 2\npc 43 in for_step_1, line 6: for i in 0..3:");
+        assert_eq!(replies[5], "R0 changed: 2 -> 6\npc 43 in for_step_1, line 6: for i in 0..3:");
+        assert!(replies[6].starts_with("Program finished after"), "{}", out);
+    }
+
+    #[test]
+    fn print_registers_of_the_current_window_only() {
+        let asm = "JMP main\nf:\nLOAD_CONST R0, 7\nRET R0\nmain:\nLOAD_CONST R0, 1\nCALL R1, f\nHALT";
+        let out = debug(asm, "break f\ncontinue\nprint R0\nprint R256\nprint x\n");
+        assert!(out.contains("R0 = nil\n"), "{}", out);
+        assert!(out.contains("No register R256: each call has 256 registers\n"), "{}", out);
+        assert!(out.contains("No variable \"x\" here\n"), "{}", out);
+    }
+}
@@ -0,0 +1,10 @@
+//! Orus: a small language compiled to assembly for a register-based VM.
+//!
+//! The `compiler` module turns source into assembly text, and the `vm`
+//! module assembles and executes it. `debugger` and `repl` drive the VM
+//! interactively.
+
+pub mod compiler;
+pub mod debugger;
+pub mod repl;
+pub mod vm;
@@ -1,42 +1,176 @@
-mod compiler;
-mod vm;
-
 use std::env;
 use std::fs;
-use compiler::{lexer::Lexer, parser::Parser, codegen::CodeGenerator};
-use vm::{assembler::assemble, machine::VM};
+use std::io;
+use std::path::Path;
+use std::process;
+
+use rust_vm::debugger::Debugger;
+use rust_vm::repl::Repl;
+use rust_vm::compiler::{lexer::Lexer, parser::Parser, codegen::CodeGenerator};
+use rust_vm::vm::{assembler::assemble, bytecode::Module, disassembler::disassemble_module, machine::{VmConfig, VM}, tracer::PrettyTracer};
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    
-    let source = if args.len() > 1 {
-        let path = &args[1];
-        fs::read_to_string(path).expect("Failed to read .orus file")
-    } else {
-        // Default program - simple without indentation issues
-        r#"mut sum = 5
-print(sum)"#.to_string()
+    // Usage: rust_vm [--trace] [--wrapping] [--heap-limit=<bytes>] [--instruction-limit=<n>] [file.orus | file.orusc]   (no file starts the REPL)
+    //        rust_vm compile file.orus [-o file.orusc]
+    //        rust_vm disasm (file.orus | file.orusc)
+    //        rust_vm debug (file.orus | file.orusc)
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("compile") => return compile_command(&args[1..]),
+        Some("disasm") => return disasm_command(&args[1..]),
+        Some("debug") => return debug_command(&args[1..]),
+        _ => {}
+    }
+
+    let trace = args.iter().any(|arg| arg == "--trace");
+    let wrapping = args.iter().any(|arg| arg == "--wrapping");
+    let heap_limit = match args.iter().find_map(|arg| arg.strip_prefix("--heap-limit=")) {
+        Some(limit) => limit.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid heap limit {:?}; expected a number of bytes", limit);
+            process::exit(1);
+        }),
+        None => VmConfig::default().heap_limit,
+    };
+    let instruction_limit = args.iter().find_map(|arg| arg.strip_prefix("--instruction-limit=")).map(|limit| {
+        limit.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid instruction limit {:?}; expected a number of instructions", limit);
+            process::exit(1);
+        })
+    });
+    let path = args.iter().find(|arg| !arg.starts_with("--"));
+
+    let mut vm = VM::with_config(VmConfig { wrapping_arithmetic: wrapping, heap_limit, instruction_limit, ..VmConfig::default() });
+    if trace {
+        vm.set_tracer(Box::new(PrettyTracer::stderr()));
+    }
+
+    let Some(path) = path else {
+        let stdin = io::stdin();
+        if let Err(err) = Repl::new(vm).run(stdin.lock(), &mut io::stdout()) {
+            eprintln!("REPL error: {}", err);
+            process::exit(1);
+        }
+        return;
     };
 
+    let module = load(path);
+    vm.load_module(&module);
+    let result = vm.run();
+    if trace {
+        eprintln!("Total instructions executed: {}", vm.instruction_count);
+        let heap = vm.heap.stats();
+        eprintln!(
+            "Heap: {} objects ({} bytes) live, {} allocated, {} freed in {} collections, peak {} bytes",
+            heap.objects, heap.bytes, heap.allocations, heap.freed, heap.collections, heap.peak_bytes
+        );
+    }
+    if let Err(err) = result {
+        // Point at the source line when the module has debug info
+        match module.debug_info.as_ref().and_then(|debug| debug.line_at(err.pc())) {
+            Some(line) => eprintln!("Runtime error on line {}: {}", line, err),
+            None => eprintln!("Runtime error: {}", err),
+        }
+        process::exit(1);
+    }
+}
+
+/// `compile file.orus [-o file.orusc]`: write the compiled module to disk
+fn compile_command(args: &[String]) {
+    let input = args.iter().find(|arg| !arg.starts_with('-')).unwrap_or_else(|| {
+        eprintln!("Usage: rust_vm compile file.orus [-o file.orusc]");
+        process::exit(1);
+    });
+    let output = match args.iter().position(|arg| arg == "-o") {
+        Some(i) => args.get(i + 1).cloned().unwrap_or_else(|| {
+            eprintln!("Missing file name after -o");
+            process::exit(1);
+        }),
+        None => Path::new(input).with_extension("orusc").to_string_lossy().into_owned(),
+    };
+
+    let source = fs::read_to_string(input).expect("Failed to read .orus file");
+    let module = compile(&source);
+    fs::write(&output, module.to_bytes()).expect("Failed to write .orusc file");
+}
+
+/// `disasm file`: print the assembly of a source or bytecode file
+fn disasm_command(args: &[String]) {
+    let Some(path) = args.first() else {
+        eprintln!("Usage: rust_vm disasm (file.orus | file.orusc)");
+        process::exit(1);
+    };
+    match disassemble_module(&load(path)) {
+        Ok(asm) => print!("{}", asm),
+        Err(err) => {
+            eprintln!("Disassembly error: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+/// `debug file`: run a program under the interactive debugger
+fn debug_command(args: &[String]) {
+    let Some(path) = args.first() else {
+        eprintln!("Usage: rust_vm debug (file.orus | file.orusc)");
+        process::exit(1);
+    };
+    let module = load(path);
+    // Source lines are shown when debugging a source file
+    let source = fs::read_to_string(path).ok().filter(|_| !path.ends_with(".orusc"));
+    let mut debugger = Debugger::new(VM::new(), &module, source.as_deref()).unwrap_or_else(|err| {
+        eprintln!("Bytecode error: {}", err);
+        process::exit(1);
+    });
+    let stdin = io::stdin();
+    if let Err(err) = debugger.run(stdin.lock(), &mut io::stdout()) {
+        eprintln!("Debugger error: {}", err);
+        process::exit(1);
+    }
+}
+
+/// Load a `.orusc` file directly, or compile a source file
+fn load(path: &str) -> Module {
+    let bytes = fs::read(path).expect("Failed to read program file");
+    if Module::is_bytecode(&bytes) {
+        Module::from_bytes(&bytes).unwrap_or_else(|err| {
+            eprintln!("Bytecode error: {}", err);
+            process::exit(1);
+        })
+    } else {
+        let source = String::from_utf8(bytes).expect("Failed to read .orus file");
+        compile(&source)
+    }
+}
+
+/// Compile source text down to a loadable module, exiting on errors
+fn compile(source: &str) -> Module {
     // Tokenize
-    let mut lexer = Lexer::new(&source);
-    let tokens = lexer.tokenize();
-    
+    let mut lexer = Lexer::new(source);
+    let tokens = lexer.tokenize().unwrap_or_else(|err| {
+        eprintln!("Syntax error: {}", err);
+        process::exit(1);
+    });
+
     // Parse
-    let mut parser = Parser::new(tokens);
-    let ast = parser.parse();
-    
+    let mut parser = Parser::with_lines(tokens, lexer.token_lines().to_vec());
+    let ast = parser.parse().unwrap_or_else(|err| {
+        eprintln!("Syntax error: {}", err);
+        process::exit(1);
+    });
+
     // Generate code
     let mut codegen = CodeGenerator::new();
-    let asm = codegen.generate(&ast);
+    let asm = codegen.generate(&ast).unwrap_or_else(|err| {
+        eprintln!("Compile error: {}", err);
+        process::exit(1);
+    });
 
-    
     // Add HALT to end the program
     let asm_with_halt = format!("{}\nHALT", asm);
-    
-    // Assemble and run
-    let program = assemble(&asm_with_halt);
-    let mut vm = VM::new();
-    vm.load_program(&program);
-    vm.run();
+
+    // Assemble
+    assemble(&asm_with_halt).unwrap_or_else(|err| {
+        eprintln!("Assembly error: {}", err);
+        process::exit(1);
+    })
 }
@@ -0,0 +1,245 @@
+//! Interactive read-eval-print loop.
+//!
+//! Every input is compiled and run on its own, on one long-lived VM. The
+//! code generator pins variables to registers across inputs, so values
+//! defined earlier stay available.
+
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+use crate::compiler::codegen::{CodeGenerator, CompileError};
+use crate::compiler::lexer::{LexError, Lexer};
+use crate::compiler::parser::{ASTNode, ParseError, Parser};
+use crate::compiler::types::Type;
+use crate::vm::assembler::{assemble, AsmError};
+use crate::vm::error::VmError;
+use crate::vm::machine::VM;
+
+const HELP: &str = "\
+Enter statements or expressions; expression values are printed.
+A line ending in ':' starts a block, finished by an empty line.
+:history     list previous inputs
+!<n>         run input <n> from the history again
+:help        show this help
+:quit        leave the REPL";
+
+/// Why an input could not be evaluated
+#[derive(Debug)]
+pub enum ReplError {
+    Lex(LexError),
+    Parse(ParseError),
+    Compile(CompileError),
+    Assembly(AsmError),
+    Runtime(VmError),
+}
+
+impl fmt::Display for ReplError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplError::Lex(err) => write!(f, "Syntax error: {}", err),
+            ReplError::Parse(err) => write!(f, "Syntax error: {}", err),
+            ReplError::Compile(err) => write!(f, "Compile error: {}", err),
+            ReplError::Assembly(err) => write!(f, "Assembly error: {}", err),
+            ReplError::Runtime(err) => write!(f, "Runtime error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ReplError {}
+
+pub struct Repl {
+    codegen: CodeGenerator,
+    vm: VM,
+    history: Vec<String>,
+}
+
+impl Repl {
+    /// A REPL running on `vm`; code is generated for its configuration
+    pub fn new(vm: VM) -> Self {
+        Repl { codegen: CodeGenerator::with_config(vm.config()), vm, history: Vec::new() }
+    }
+
+    /// Read and evaluate inputs until `:quit` or the end of `input`
+    pub fn run(&mut self, input: impl BufRead, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "Orus REPL. Type :help for help, :quit to exit.")?;
+        let mut lines = input.lines();
+        loop {
+            write!(out, ">>> ")?;
+            out.flush()?;
+            let Some(line) = lines.next() else { break };
+            let line = line?;
+            let entry = match line.trim() {
+                "" => continue,
+                ":quit" | ":q" => return Ok(()),
+                ":help" => {
+                    writeln!(out, "{}", HELP)?;
+                    continue;
+                }
+                ":history" => {
+                    for (i, entry) in self.history.iter().enumerate() {
+                        writeln!(out, "{:4}  {}", i + 1, entry.replace('\n', "\n      "))?;
+                    }
+                    continue;
+                }
+                recall if recall.starts_with('!') => {
+                    let index = recall[1..].parse::<usize>().ok().filter(|&i| i >= 1);
+                    match index.and_then(|i| self.history.get(i - 1)) {
+                        Some(entry) => {
+                            writeln!(out, "{}", entry)?;
+                            entry.clone()
+                        }
+                        None => {
+                            writeln!(out, "No history entry {}", &recall[1..])?;
+                            continue;
+                        }
+                    }
+                }
+                trimmed if trimmed.ends_with(':') => {
+                    // A block: keep reading until an empty line
+                    let mut block = line.clone();
+                    loop {
+                        write!(out, "... ")?;
+                        out.flush()?;
+                        match lines.next() {
+                            Some(next) => {
+                                let next = next?;
+                                if next.trim().is_empty() {
+                                    break;
+                                }
+                                block.push('\n');
+                                block.push_str(&next);
+                            }
+                            None => break,
+                        }
+                    }
+                    block
+                }
+                _ => line,
+            };
+
+            self.history.push(entry.clone());
+            if let Err(err) = self.eval(&entry) {
+                writeln!(out, "{}", err)?;
+            }
+        }
+        writeln!(out)
+    }
+
+    /// Compile and run one input. A lone expression prints its value,
+    /// unless it is a call that returns nil. Variables and functions the
+    /// input defines are kept only if it runs without error.
+    pub fn eval(&mut self, source: &str) -> Result<(), ReplError> {
+        let source = format!("{}\n", source);
+        let mut lexer = Lexer::new(&source);
+        let tokens = lexer.tokenize().map_err(ReplError::Lex)?;
+
+        let ast = match Parser::new(tokens.clone()).parse_expression() {
+            Some(expr @ (ASTNode::Call { .. } | ASTNode::MethodCall { .. }))
+                if self.codegen.natural_type(&expr) == Some(Type::NIL) =>
+            {
+                vec![expr]
+            }
+            Some(expr) => vec![ASTNode::Print(vec![expr])],
+            None => Parser::new(tokens).parse().map_err(ReplError::Parse)?,
+        };
+
+        let checkpoint = self.codegen.checkpoint();
+        let asm = self.codegen.generate_incremental(&ast).map_err(ReplError::Compile)?;
+        let module = assemble(&format!("{}\nHALT", asm)).map_err(|err| {
+            self.codegen.restore(checkpoint.clone());
+            ReplError::Assembly(err)
+        })?;
+        self.vm.load_module(&module);
+        match self.vm.run() {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                self.codegen.restore(checkpoint);
+                Err(ReplError::Runtime(err))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::compiler::codegen::CompileError;
+
+    /// A REPL whose program output is collected in the returned buffer
+    fn repl() -> (Repl, Rc<RefCell<String>>) {
+        let output = Rc::new(RefCell::new(String::new()));
+        let mut vm = VM::new();
+        let sink = Rc::clone(&output);
+        vm.set_output_callback(move |line| sink.borrow_mut().push_str(&format!("{}\n", line)));
+        (Repl::new(vm), output)
+    }
+
+    #[test]
+    fn variables_of_a_failed_input_are_forgotten() {
+        let (mut repl, output) = repl();
+        repl.eval("x = 1").unwrap();
+        assert!(matches!(repl.eval("q = x / 0"), Err(ReplError::Runtime(_))));
+        assert!(matches!(
+            repl.eval("q"),
+            Err(ReplError::Compile(CompileError::UndefinedVariable(name))) if name == "q"
+        ));
+        repl.eval("q = x + 1").unwrap();
+        repl.eval("q").unwrap();
+        assert_eq!(*output.borrow(), "2\n");
+    }
+
+    #[test]
+    fn variables_of_a_branch_that_did_not_run_are_undefined() {
+        let (mut repl, output) = repl();
+        repl.eval("a = 123").unwrap();
+        repl.eval("if false:\n    x = 7").unwrap();
+        assert!(matches!(
+            repl.eval("x"),
+            Err(ReplError::Compile(CompileError::UndefinedVariable(name))) if name == "x"
+        ));
+        repl.eval("a").unwrap();
+        assert_eq!(*output.borrow(), "123\n");
+    }
+
+    #[test]
+    fn closures_survive_later_definitions() {
+        let (mut repl, output) = repl();
+        repl.eval("fn f() -> i32: return 1").unwrap();
+        repl.eval("g = |x: i32| x + 100").unwrap();
+        repl.eval("g(1)").unwrap();
+        repl.eval("fn f() -> i32:\n    a = 1\n    b = 2\n    return (a + b) * 10").unwrap();
+        repl.eval("g(1)").unwrap();
+        repl.eval("f()").unwrap();
+
+        // Closures made by an input that then fails keep their code too
+        repl.eval("fs: [fn() -> i32] = []").unwrap();
+        assert!(matches!(repl.eval("fs.push(|| 7)\nz = 1 / 0"), Err(ReplError::Runtime(_))));
+        repl.eval("fn k() -> i32:\n    return 2").unwrap();
+        repl.eval("fs[0]()").unwrap();
+        assert_eq!(*output.borrow(), "101\n101\n30\n7\n");
+    }
+
+    #[test]
+    fn syntax_errors_are_reported() {
+        let (mut repl, output) = repl();
+        assert_eq!(
+            repl.eval("if 1 ==:\n    print(1)").unwrap_err().to_string(),
+            "Syntax error: line 1: expected expression, found ':'"
+        );
+        assert!(output.borrow().is_empty());
+    }
+
+    #[test]
+    fn calls_returning_nil_are_not_echoed() {
+        let (mut repl, output) = repl();
+        repl.eval("a = [1]").unwrap();
+        repl.eval("a.push(3)").unwrap();
+        repl.eval("fn hello():\n    print(\"hello\")").unwrap();
+        repl.eval("hello()").unwrap();
+        repl.eval("a.len()").unwrap();
+        assert_eq!(*output.borrow(), "hello\n2\n");
+    }
+}
@@ -1,79 +1,299 @@
-use super::instruction::InstructionSet;
+use std::collections::HashMap;
+use std::fmt;
 
+use super::instruction::{InstructionSet, OperandKind};
+use super::bytecode::{Constant, DebugInfo, Module, VarLocation, VariableInfo};
+use super::value::ValueType;
 
-pub fn assemble(asm: &str) -> Vec<i32> {
+/// An assembly error; `line` is 1-based
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmError {
+    UnknownInstruction { line: usize, mnemonic: String },
+    UnknownLabel { line: usize, label: String },
+    InvalidOperand { line: usize, operand: String },
+    MissingOperand { line: usize, mnemonic: String },
+    UnexpectedOperand { line: usize, operand: String },
+    UnknownDirective { line: usize, directive: String },
+    DuplicateLabel { line: usize, label: String },
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmError::UnknownInstruction { line, mnemonic } => {
+                write!(f, "line {}: unknown instruction {}", line, mnemonic)
+            }
+            AsmError::UnknownLabel { line, label } => write!(f, "line {}: unknown label {}", line, label),
+            AsmError::InvalidOperand { line, operand } => {
+                write!(f, "line {}: invalid operand {}", line, operand)
+            }
+            AsmError::MissingOperand { line, mnemonic } => {
+                write!(f, "line {}: missing operand for {}", line, mnemonic)
+            }
+            AsmError::UnexpectedOperand { line, operand } => {
+                write!(f, "line {}: unexpected operand {}", line, operand)
+            }
+            AsmError::UnknownDirective { line, directive } => {
+                write!(f, "line {}: unknown directive {}", line, directive)
+            }
+            AsmError::DuplicateLabel { line, label } => write!(f, "line {}: duplicate label {}", line, label),
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// The split-up parts of one instruction line
+struct Operands<'a> {
+    parts: Vec<&'a str>,
+    line: usize,
+}
+
+impl Operands<'_> {
+    fn part(&self, i: usize) -> Result<&str, AsmError> {
+        self.parts.get(i).copied().ok_or_else(|| AsmError::MissingOperand {
+            line: self.line,
+            mnemonic: self.parts[0].to_string(),
+        })
+    }
+
+    fn invalid(&self, operand: &str) -> AsmError {
+        AsmError::InvalidOperand { line: self.line, operand: operand.to_string() }
+    }
+
+    // Register names are case-insensitive: R0 or r0
+    fn register(&self, i: usize) -> Result<i32, AsmError> {
+        let part = self.part(i)?;
+        part.strip_prefix(['R', 'r'])
+            .and_then(|n| n.parse::<i32>().ok())
+            .ok_or_else(|| self.invalid(part))
+    }
+
+    fn immediate(&self, i: usize) -> Result<i32, AsmError> {
+        let part = self.part(i)?;
+        part.parse::<i32>().map_err(|_| self.invalid(part))
+    }
+
+    // Constant literals: integers with an optional type suffix (5, 5i64, 7u32),
+    // floats (1.5, 1e-3, inf, NaN), true, false, nil and quoted strings
+    fn constant(&self, i: usize) -> Result<Constant, AsmError> {
+        let part = self.part(i)?;
+        match part {
+            "true" => return Ok(Constant::Bool(true)),
+            "false" => return Ok(Constant::Bool(false)),
+            "nil" => return Ok(Constant::Nil),
+            _ => {}
+        }
+        if part.starts_with('"') {
+            return unquote_string(part).map(Constant::Str).ok_or_else(|| self.invalid(part));
+        }
+        for ty in [ValueType::I64, ValueType::U32, ValueType::U64, ValueType::I32] {
+            if let Some(digits) = part.strip_suffix(ty.name()) {
+                let value = match ty {
+                    ValueType::I64 => digits.parse().ok().map(Constant::I64),
+                    ValueType::U32 => digits.parse().ok().map(Constant::U32),
+                    ValueType::U64 => digits.parse().ok().map(Constant::U64),
+                    _ => digits.parse().ok().map(Constant::I32),
+                };
+                return value.ok_or_else(|| self.invalid(part));
+            }
+        }
+        part.parse::<i32>().map(Constant::I32)
+            .or_else(|_| part.parse::<f64>().map(Constant::F64))
+            .map_err(|_| self.invalid(part))
+    }
+
+    fn label(&self, i: usize, labels: &HashMap<String, i32>) -> Result<i32, AsmError> {
+        let label = self.part(i)?;
+        labels.get(label).copied().ok_or_else(|| AsmError::UnknownLabel {
+            line: self.line,
+            label: label.to_string(),
+        })
+    }
+}
+
+/// Split an instruction line at spaces and commas, keeping quoted strings
+/// whole
+pub fn split_operands(line: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = None;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        if c == ' ' || c == ',' {
+            if let Some(s) = start.take() {
+                parts.push(&line[s..i]);
+            }
+            continue;
+        }
+        if start.is_none() {
+            start = Some(i);
+            in_string = c == '"';
+        }
+    }
+    if let Some(s) = start {
+        parts.push(&line[s..]);
+    }
+    parts
+}
+
+/// String literal in assembly syntax: double-quoted, with `\n`, `\t`,
+/// `\r`, `\0`, `\\` and `\"` escapes
+pub fn quote_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '\0' => out.push_str("\\0"),
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Contents of a string literal written by `quote_string`, or None if it
+/// is not one
+pub fn unquote_string(literal: &str) -> Option<String> {
+    let inner = literal.strip_prefix('"')?.strip_suffix('"')?;
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.push(unescape(chars.next()?)?),
+            '"' => return None,
+            c => out.push(c),
+        }
+    }
+    Some(out)
+}
+
+/// Character for the escape sequence `\<c>`
+pub fn unescape(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        '0' => Some('\0'),
+        '\\' => Some('\\'),
+        '"' => Some('"'),
+        _ => None,
+    }
+}
+
+/// Assemble source text into a module. LOAD_K takes a literal of any value type, which
+/// is added to the module's constant pool. Labels are kept as debug info,
+/// along with the directives `.line <source line>` and
+/// `.var <name> <Rn | @addr>`, which apply from the next instruction on.
+pub fn assemble(asm: &str) -> Result<Module, AsmError> {
     let mut program = Vec::new();
-    let mut labels = std::collections::HashMap::new();
+    let mut constants = Vec::new();
+    let mut labels = HashMap::new();
+    let mut debug_info = DebugInfo::default();
     let mut current_addr = 0;
 
     // First pass: Identify labels and their addresses
-    for line in asm.lines() {
+    for (line_no, line) in asm.lines().enumerate() {
         let line = line.trim();
         if line.is_empty() || line.starts_with("//") {
             continue;
         }
         if line.ends_with(':') {
             let label = line.trim_end_matches(':').trim();
-            labels.insert(label.to_string(), current_addr);
+            if labels.insert(label.to_string(), current_addr).is_some() {
+                return Err(AsmError::DuplicateLabel { line: line_no + 1, label: label.to_string() });
+            }
+            debug_info.labels.push((label.to_string(), current_addr as usize));
             continue;
         }
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        match parts[0] {
-            "LOAD_CONST" => current_addr += 3,
-            "ADD" | "SUB" | "MUL" | "DIV" | "MOD" | "MOV" | "JMP_IF_NOT_ZERO" => current_addr += 3,
-            "PRINT_REG" => current_addr += 2,
-            "JMP" => current_addr += 2,
-            "HALT" => current_addr += 1,
-            _ => panic!("Unknown instruction: {}", parts[0]),
+        if line.starts_with('.') {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let operands = Operands { parts, line: line_no + 1 };
+            let pc = current_addr as usize;
+            match operands.parts[0] {
+                ".line" => {
+                    let source_line = operands.immediate(1)?;
+                    debug_info.lines.push((pc, source_line as usize));
+                }
+                ".var" => {
+                    let name = operands.part(1)?.to_string();
+                    let location = match operands.part(2)?.strip_prefix('@') {
+                        Some(addr) => VarLocation::Memory(addr.parse().map_err(|_| operands.invalid(addr))?),
+                        None => VarLocation::Register(operands.register(2)? as usize),
+                    };
+                    debug_info.variables.push(VariableInfo { name, pc, location });
+                }
+                directive => {
+                    return Err(AsmError::UnknownDirective { line: line_no + 1, directive: directive.to_string() })
+                }
+            }
+            continue;
         }
+        let mnemonic = line.split_whitespace().next().unwrap_or_default();
+        let instruction = InstructionSet::from_mnemonic(mnemonic)
+            .ok_or_else(|| AsmError::UnknownInstruction { line: line_no + 1, mnemonic: mnemonic.to_string() })?;
+        current_addr += 1 + instruction.operand_count() as i32;
     }
 
     // Second pass: Generate program
-    for line in asm.lines() {
+    for (line_no, line) in asm.lines().enumerate() {
         let line = line.trim();
-        if line.is_empty() || line.starts_with("//") || line.ends_with(':') {
+        if line.is_empty() || line.starts_with("//") || line.starts_with('.') || line.ends_with(':') {
             continue;
         }
-        let parts: Vec<&str> = line.split(|c| c == ' ' || c == ',')
-            .filter(|p| !p.is_empty())
-            .collect();
-
-        match parts[0] {
-            "LOAD_CONST" => {
-                let reg = parts[1].trim_start_matches("R").parse::<i32>().unwrap();
-                let value = parts[2].parse::<i32>().unwrap();
-                program.extend([InstructionSet::LoadConst as i32, reg, value]);
-            }
-            "ADD" => {
-                let reg1 = parts[1].trim_start_matches("R").parse::<i32>().unwrap();
-                let reg2 = parts[2].trim_start_matches("R").parse::<i32>().unwrap();
-                program.extend([InstructionSet::Add as i32, reg1, reg2]);
-            }
-            "SUB" => {
-                let reg1 = parts[1].trim_start_matches("R").parse::<i32>().unwrap();
-                let reg2 = parts[2].trim_start_matches("R").parse::<i32>().unwrap();
-                program.extend([InstructionSet::Sub as i32, reg1, reg2]);
-            }
-            "MUL" => {
-                let reg1 = parts[1].trim_start_matches("R").parse::<i32>().unwrap();
-                let reg2 = parts[2].trim_start_matches("R").parse::<i32>().unwrap();
-                program.extend([InstructionSet::Mul as i32, reg1, reg2]);
-            }
-            "JMP_IF_NOT_ZERO" => {
-                let reg = parts[1].trim_start_matches("R").parse::<i32>().unwrap();
-                let label = parts[2];
-                let addr = *labels.get(label).expect("Unknown label") as i32;
-                program.extend([InstructionSet::JumpIfNotZero as i32, reg, addr]);
-            }
-            "PRINT_REG" => {
-                let reg = parts[1].trim_start_matches("R").parse::<i32>().unwrap();
-                program.extend([InstructionSet::PrintReg as i32, reg]);
-            }
-            "HALT" => {
-                program.push(InstructionSet::Halt as i32);
-            }
-            _ => panic!("Unknown instruction: {}", parts[0]),
+        let operands = Operands { parts: split_operands(line), line: line_no + 1 };
+        let instruction = InstructionSet::from_mnemonic(operands.parts[0]).expect("checked in the first pass");
+
+        let kinds = instruction.operands();
+        if let Some(extra) = operands.parts.get(kinds.len() + 1) {
+            return Err(AsmError::UnexpectedOperand { line: line_no + 1, operand: extra.to_string() });
+        }
+
+        program.push(instruction as i32);
+        for (i, kind) in kinds.iter().enumerate() {
+            let word = match kind {
+                OperandKind::Register(_) => operands.register(i + 1)?,
+                OperandKind::Immediate => operands.immediate(i + 1)?,
+                OperandKind::Address => operands.label(i + 1, &labels)?,
+                OperandKind::Constant => {
+                    let constant = operands.constant(i + 1)?;
+                    let index = match constants.iter().position(|c: &Constant| c.same(&constant)) {
+                        Some(index) => index,
+                        None => {
+                            constants.push(constant);
+                            constants.len() - 1
+                        }
+                    };
+                    index as i32
+                }
+            };
+            program.push(word);
         }
     }
-    program
+    Ok(Module { constants, code: program, debug_info: Some(debug_info) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_labels() {
+        let err = assemble("a:\nJMP a\na:\nHALT").unwrap_err();
+        assert_eq!(err, AsmError::DuplicateLabel { line: 3, label: "a".to_string() });
+    }
 }
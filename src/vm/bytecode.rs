@@ -0,0 +1,412 @@
+//! The `.orusc` compiled bytecode format.
+//!
+//! All integers are little-endian.
+//!
+//! ```text
+//! header     magic "ORUS" | version: u16 | flags: u16
+//! constants  count: u32 | count x (tag: u8, payload)
+//!            tag 0 i32 | 1 f64 (bits as u64) | 2 bool: u8 | 3 nil: nothing
+//!            | 4 i64 | 5 u32 | 6 u64 | 7 string: (length: u32, UTF-8 bytes)
+//! code       length: u32 | length x i32
+//! debug      (only when flags has FLAG_DEBUG_INFO)
+//!            label count: u32 | (name length: u32, name bytes, pc: u32) ...
+//!            line count: u32  | (pc: u32, source line: u32) ...
+//!            variable count: u32 | (name length: u32, name bytes, pc: u32,
+//!                                   location tag: u8, index: u32) ...
+//! ```
+
+use std::collections::HashSet;
+use std::fmt;
+
+use super::instruction::{InstructionSet, OperandKind};
+
+pub const MAGIC: [u8; 4] = *b"ORUS";
+pub const FORMAT_VERSION: u16 = 1;
+
+const FLAG_DEBUG_INFO: u16 = 1;
+
+const TAG_I32: u8 = 0;
+const TAG_F64: u8 = 1;
+const TAG_BOOL: u8 = 2;
+const TAG_NIL: u8 = 3;
+const TAG_I64: u8 = 4;
+const TAG_U32: u8 = 5;
+const TAG_U64: u8 = 6;
+const TAG_STR: u8 = 7;
+
+const TAG_REGISTER: u8 = 0;
+const TAG_MEMORY: u8 = 1;
+
+/// A constant pool entry, loaded with LOAD_K. Strings are copied to the
+/// VM's heap when the module is loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+    I32(i32),
+    I64(i64),
+    U32(u32),
+    U64(u64),
+    F64(f64),
+    Bool(bool),
+    Nil,
+    Str(String),
+}
+
+impl Constant {
+    /// Identity for constant pool deduplication; unlike `==`, tells 0.0
+    /// from -0.0 and matches NaN with itself
+    pub fn same(&self, other: &Constant) -> bool {
+        match (self, other) {
+            (Constant::F64(a), Constant::F64(b)) => a.to_bits() == b.to_bits(),
+            _ => self == other,
+        }
+    }
+}
+
+/// Where a source variable's value is kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VarLocation {
+    Register(usize),
+    Memory(usize),      // data memory word, for spilled variables
+}
+
+/// A source variable bound at `pc`; it holds until the next binding of the same name
+#[derive(Debug, Clone, PartialEq)]
+pub struct VariableInfo {
+    pub name: String,
+    pub pc: usize,
+    pub location: VarLocation,
+}
+
+/// Symbols for mapping program addresses back to assembly and source
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DebugInfo {
+    pub labels: Vec<(String, usize)>,   // label name and the address it marks
+    pub lines: Vec<(usize, usize)>,     // first address generated for a source line, and that line
+    pub variables: Vec<VariableInfo>,
+}
+
+impl DebugInfo {
+    /// Source line of the code at `pc`
+    pub fn line_at(&self, pc: usize) -> Option<usize> {
+        self.lines.iter().filter(|(start, _)| *start <= pc).max_by_key(|(start, _)| *start).map(|(_, line)| *line)
+    }
+
+    /// First address generated for a source line
+    pub fn line_pc(&self, line: usize) -> Option<usize> {
+        self.lines.iter().filter(|(_, l)| *l == line).map(|(pc, _)| *pc).min()
+    }
+
+    pub fn label_pc(&self, name: &str) -> Option<usize> {
+        self.labels.iter().find(|(label, _)| label == name).map(|(_, pc)| *pc)
+    }
+
+    /// The closest label at or before `pc`
+    pub fn label_at(&self, pc: usize) -> Option<&str> {
+        self.labels.iter().filter(|(_, addr)| *addr <= pc).max_by_key(|(_, addr)| *addr).map(|(name, _)| name.as_str())
+    }
+
+    /// Location of the binding of `name` in effect at `pc`
+    pub fn variable_at(&self, name: &str, pc: usize) -> Option<VarLocation> {
+        self.variables
+            .iter()
+            .filter(|var| var.name == name && var.pc <= pc)
+            .max_by_key(|var| var.pc)
+            .map(|var| var.location)
+    }
+}
+
+/// A compiled program: what the assembler produces and the VM loads
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Module {
+    pub constants: Vec<Constant>,   // loaded with LOAD_K
+    pub code: Vec<i32>,
+    pub debug_info: Option<DebugInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BytecodeError {
+    BadMagic,
+    UnsupportedVersion { found: u16, supported: u16 },
+    Truncated,
+    InvalidConstantTag(u8),
+    InvalidSymbol,
+    InvalidString,
+    InvalidLocationTag(u8),
+    TrailingBytes,
+    InvalidOpcode { pc: usize, opcode: i32 },
+    TruncatedInstruction { pc: usize, instruction: InstructionSet },
+    InvalidJumpTarget { pc: usize, target: i32 },
+    InvalidConstantIndex { pc: usize, index: i32 },
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BytecodeError::BadMagic => write!(f, "not an .orusc file (bad magic number)"),
+            BytecodeError::UnsupportedVersion { found, supported } => {
+                write!(f, "unsupported bytecode version {} (this VM supports version {})", found, supported)
+            }
+            BytecodeError::Truncated => write!(f, "bytecode file is truncated"),
+            BytecodeError::InvalidConstantTag(tag) => write!(f, "invalid constant tag {}", tag),
+            BytecodeError::InvalidSymbol => write!(f, "debug symbol is not valid UTF-8"),
+            BytecodeError::InvalidString => write!(f, "string constant is not valid UTF-8"),
+            BytecodeError::InvalidLocationTag(tag) => write!(f, "invalid variable location tag {}", tag),
+            BytecodeError::TrailingBytes => write!(f, "unexpected bytes after the last section"),
+            BytecodeError::InvalidOpcode { pc, opcode } => write!(f, "unknown instruction {} at PC={}", opcode, pc),
+            BytecodeError::TruncatedInstruction { pc, instruction } => {
+                write!(f, "code ends inside {} at PC={}", instruction.mnemonic(), pc)
+            }
+            BytecodeError::InvalidJumpTarget { pc, target } => {
+                write!(f, "jump target {} is not an instruction boundary at PC={}", target, pc)
+            }
+            BytecodeError::InvalidConstantIndex { pc, index } => {
+                write!(f, "constant index {} out of bounds at PC={}", index, pc)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BytecodeError {}
+
+impl Module {
+    /// Encode the module in the `.orusc` format
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        let flags = if self.debug_info.is_some() { FLAG_DEBUG_INFO } else { 0 };
+        out.extend_from_slice(&flags.to_le_bytes());
+
+        write_u32(&mut out, self.constants.len());
+        for constant in &self.constants {
+            match constant {
+                Constant::I32(n) => {
+                    out.push(TAG_I32);
+                    out.extend_from_slice(&n.to_le_bytes());
+                }
+                Constant::I64(n) => {
+                    out.push(TAG_I64);
+                    out.extend_from_slice(&n.to_le_bytes());
+                }
+                Constant::U32(n) => {
+                    out.push(TAG_U32);
+                    out.extend_from_slice(&n.to_le_bytes());
+                }
+                Constant::U64(n) => {
+                    out.push(TAG_U64);
+                    out.extend_from_slice(&n.to_le_bytes());
+                }
+                Constant::F64(x) => {
+                    out.push(TAG_F64);
+                    out.extend_from_slice(&x.to_bits().to_le_bytes());
+                }
+                Constant::Bool(b) => {
+                    out.push(TAG_BOOL);
+                    out.push(*b as u8);
+                }
+                Constant::Nil => out.push(TAG_NIL),
+                Constant::Str(s) => {
+                    out.push(TAG_STR);
+                    write_u32(&mut out, s.len());
+                    out.extend_from_slice(s.as_bytes());
+                }
+            }
+        }
+
+        write_u32(&mut out, self.code.len());
+        for word in &self.code {
+            out.extend_from_slice(&word.to_le_bytes());
+        }
+
+        if let Some(debug) = &self.debug_info {
+            write_u32(&mut out, debug.labels.len());
+            for (name, pc) in &debug.labels {
+                write_u32(&mut out, name.len());
+                out.extend_from_slice(name.as_bytes());
+                write_u32(&mut out, *pc);
+            }
+            write_u32(&mut out, debug.lines.len());
+            for (pc, line) in &debug.lines {
+                write_u32(&mut out, *pc);
+                write_u32(&mut out, *line);
+            }
+            write_u32(&mut out, debug.variables.len());
+            for var in &debug.variables {
+                write_u32(&mut out, var.name.len());
+                out.extend_from_slice(var.name.as_bytes());
+                write_u32(&mut out, var.pc);
+                let (tag, index) = match var.location {
+                    VarLocation::Register(reg) => (TAG_REGISTER, reg),
+                    VarLocation::Memory(addr) => (TAG_MEMORY, addr),
+                };
+                out.push(tag);
+                write_u32(&mut out, index);
+            }
+        }
+        out
+    }
+
+    /// Decode a module, validating the header before anything else
+    pub fn from_bytes(bytes: &[u8]) -> Result<Module, BytecodeError> {
+        let mut reader = Reader { bytes, pos: 0 };
+
+        if reader.take(4).map_err(|_| BytecodeError::BadMagic)? != MAGIC {
+            return Err(BytecodeError::BadMagic);
+        }
+        let version = reader.u16()?;
+        if version != FORMAT_VERSION {
+            return Err(BytecodeError::UnsupportedVersion { found: version, supported: FORMAT_VERSION });
+        }
+        let flags = reader.u16()?;
+
+        let mut constants = Vec::new();
+        for _ in 0..reader.u32()? {
+            match reader.u8()? {
+                TAG_I32 => constants.push(Constant::I32(reader.i32()?)),
+                TAG_I64 => constants.push(Constant::I64(reader.u64()? as i64)),
+                TAG_U32 => constants.push(Constant::U32(reader.u32()? as u32)),
+                TAG_U64 => constants.push(Constant::U64(reader.u64()?)),
+                TAG_F64 => constants.push(Constant::F64(f64::from_bits(reader.u64()?))),
+                TAG_BOOL => constants.push(Constant::Bool(reader.u8()? != 0)),
+                TAG_NIL => constants.push(Constant::Nil),
+                TAG_STR => constants.push(Constant::Str(reader.string(BytecodeError::InvalidString)?)),
+                tag => return Err(BytecodeError::InvalidConstantTag(tag)),
+            }
+        }
+
+        let mut code = Vec::new();
+        for _ in 0..reader.u32()? {
+            code.push(reader.i32()?);
+        }
+
+        let debug_info = if flags & FLAG_DEBUG_INFO != 0 {
+            let mut debug = DebugInfo::default();
+            for _ in 0..reader.u32()? {
+                let name = reader.string(BytecodeError::InvalidSymbol)?;
+                debug.labels.push((name, reader.u32()?));
+            }
+            for _ in 0..reader.u32()? {
+                debug.lines.push((reader.u32()?, reader.u32()?));
+            }
+            for _ in 0..reader.u32()? {
+                let name = reader.string(BytecodeError::InvalidSymbol)?;
+                let pc = reader.u32()?;
+                let location = match reader.u8()? {
+                    TAG_REGISTER => VarLocation::Register(reader.u32()?),
+                    TAG_MEMORY => VarLocation::Memory(reader.u32()?),
+                    tag => return Err(BytecodeError::InvalidLocationTag(tag)),
+                };
+                debug.variables.push(VariableInfo { name, pc, location });
+            }
+            Some(debug)
+        } else {
+            None
+        };
+
+        if reader.pos != bytes.len() {
+            return Err(BytecodeError::TrailingBytes);
+        }
+
+        let module = Module { constants, code, debug_info };
+        module.validate()?;
+        Ok(module)
+    }
+
+    /// Check that the code decodes cleanly against the opcode table
+    pub fn validate(&self) -> Result<(), BytecodeError> {
+        decode(&self.code, &self.constants).map(|_| ())
+    }
+
+    /// Whether `bytes` start with the `.orusc` magic number
+    pub fn is_bytecode(bytes: &[u8]) -> bool {
+        bytes.starts_with(&MAGIC)
+    }
+}
+
+/// One instruction decoded from a code segment
+#[derive(Debug, Clone, Copy)]
+pub struct Decoded<'a> {
+    pub pc: usize,
+    pub instruction: InstructionSet,
+    pub operands: &'a [i32],
+}
+
+/// Split `code` into instructions, checking every opcode, that no instruction
+/// is cut short, that jumps land on instruction boundaries (or just past the
+/// end) and that constant indices are inside `constants`
+pub fn decode<'a>(code: &'a [i32], constants: &[Constant]) -> Result<Vec<Decoded<'a>>, BytecodeError> {
+    let mut decoded = Vec::new();
+    let mut pc = 0;
+    while pc < code.len() {
+        let instruction = InstructionSet::from_i32(code[pc])
+            .ok_or(BytecodeError::InvalidOpcode { pc, opcode: code[pc] })?;
+        let end = pc + 1 + instruction.operand_count();
+        if end > code.len() {
+            return Err(BytecodeError::TruncatedInstruction { pc, instruction });
+        }
+        decoded.push(Decoded { pc, instruction, operands: &code[pc + 1..end] });
+        pc = end;
+    }
+
+    let mut boundaries: HashSet<usize> = decoded.iter().map(|d| d.pc).collect();
+    boundaries.insert(code.len());
+    for d in &decoded {
+        for (kind, &word) in d.instruction.operands().iter().zip(d.operands) {
+            let index = usize::try_from(word).ok();
+            match kind {
+                OperandKind::Address if index.is_none_or(|addr| !boundaries.contains(&addr)) => {
+                    return Err(BytecodeError::InvalidJumpTarget { pc: d.pc, target: word });
+                }
+                OperandKind::Constant if index.is_none_or(|i| i >= constants.len()) => {
+                    return Err(BytecodeError::InvalidConstantIndex { pc: d.pc, index: word });
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(decoded)
+}
+
+fn write_u32(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(&(value as u32).to_le_bytes());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], BytecodeError> {
+        let end = self.pos.checked_add(len).ok_or(BytecodeError::Truncated)?;
+        let slice = self.bytes.get(self.pos..end).ok_or(BytecodeError::Truncated)?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, BytecodeError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<usize, BytecodeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn u64(&mut self) -> Result<u64, BytecodeError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Length-prefixed UTF-8 text; `invalid` is the error for bad UTF-8
+    fn string(&mut self, invalid: BytecodeError) -> Result<String, BytecodeError> {
+        let len = self.u32()?;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| invalid)
+    }
+
+    fn i32(&mut self) -> Result<i32, BytecodeError> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
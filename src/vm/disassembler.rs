@@ -0,0 +1,153 @@
+use std::collections::BTreeMap;
+
+use super::instruction::OperandKind;
+use super::bytecode::{decode, BytecodeError, Constant, DebugInfo, Module, VarLocation};
+use super::assembler::quote_string;
+
+/// One line of disassembly
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListingLine {
+    pub pc: Option<usize>,      // address of the instruction; None for labels and directives
+    pub text: String,
+}
+
+/// Disassemble a program without debug info, reading LOAD_K operands from
+/// `constants`. Jump targets get synthesized labels.
+pub fn disassemble(program: &[i32], constants: &[Constant]) -> Result<String, BytecodeError> {
+    disassemble_module(&Module { code: program.to_vec(), constants: constants.to_vec(), debug_info: None })
+}
+
+/// Disassemble a module, resolving LOAD_K through its constant pool and
+/// keeping labels, `.line` and `.var` from its debug info where available
+pub fn disassemble_module(module: &Module) -> Result<String, BytecodeError> {
+    let mut out = String::new();
+    for line in listing(module)? {
+        out.push_str(&line.text);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+/// Disassemble a module line by line, keeping each instruction's address
+pub fn listing(module: &Module) -> Result<Vec<ListingLine>, BytecodeError> {
+    let program = &module.code;
+    let decoded = decode(program, &module.constants)?;
+    let empty = DebugInfo::default();
+    let debug = module.debug_info.as_ref().unwrap_or(&empty);
+
+    // Symbols are kept only where they can be placed: on an instruction,
+    // or just past the last one
+    let placeable = |addr: usize| addr == program.len() || decoded.iter().any(|d| d.pc == addr);
+    let mut symbols: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    let mut labels: BTreeMap<usize, String> = BTreeMap::new();
+    for (name, addr) in &debug.labels {
+        if placeable(*addr) {
+            labels.entry(*addr).or_insert_with(|| name.clone());
+        }
+    }
+    for d in &decoded {
+        for (kind, &word) in d.instruction.operands().iter().zip(d.operands) {
+            let target = word as usize;
+            if *kind == OperandKind::Address && !labels.contains_key(&target) {
+                let mut name = format!("L{}", target);
+                while labels.values().any(|existing| *existing == name) {
+                    name.push('_');
+                }
+                labels.insert(target, name);
+            }
+        }
+    }
+    for (addr, name) in &labels {
+        symbols.entry(*addr).or_default().push(format!("{}:", name));
+    }
+    for (addr, line) in debug.lines.iter().filter(|(addr, _)| placeable(*addr)) {
+        symbols.entry(*addr).or_default().push(format!(".line {}", line));
+    }
+    for var in debug.variables.iter().filter(|var| placeable(var.pc)) {
+        let location = match var.location {
+            VarLocation::Register(reg) => format!("R{}", reg),
+            VarLocation::Memory(addr) => format!("@{}", addr),
+        };
+        symbols.entry(var.pc).or_default().push(format!(".var {} {}", var.name, location));
+    }
+
+    let mut lines = Vec::new();
+    let mut push_symbols = |lines: &mut Vec<ListingLine>, addr: usize| {
+        for text in symbols.remove(&addr).unwrap_or_default() {
+            lines.push(ListingLine { pc: None, text });
+        }
+    };
+    for d in &decoded {
+        push_symbols(&mut lines, d.pc);
+        let operands: Vec<String> = d.instruction.operands().iter().zip(d.operands)
+            .map(|(kind, &word)| match kind {
+                OperandKind::Register(_) => format!("R{}", word),
+                OperandKind::Immediate => word.to_string(),
+                OperandKind::Address => labels[&(word as usize)].clone(),
+                OperandKind::Constant => constant_literal(&module.constants[word as usize]),
+            })
+            .collect();
+        let mut text = d.instruction.mnemonic().to_string();
+        if !operands.is_empty() {
+            text.push(' ');
+            text.push_str(&operands.join(", "));
+        }
+        lines.push(ListingLine { pc: Some(d.pc), text });
+    }
+    push_symbols(&mut lines, program.len());
+    Ok(lines)
+}
+
+/// Constant in the syntax LOAD_K accepts; floats always keep a decimal
+/// point or exponent and integers other than i32 keep their suffix, so
+/// they read back with the same type
+fn constant_literal(constant: &Constant) -> String {
+    match constant {
+        Constant::I32(n) => n.to_string(),
+        Constant::I64(n) => format!("{}i64", n),
+        Constant::U32(n) => format!("{}u32", n),
+        Constant::U64(n) => format!("{}u64", n),
+        Constant::F64(x) => format!("{:?}", x),
+        Constant::Bool(b) => b.to_string(),
+        Constant::Nil => "nil".to_string(),
+        Constant::Str(s) => quote_string(s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::codegen::tests::compile;
+    use crate::vm::assembler::assemble;
+    use crate::vm::machine::VmConfig;
+
+    /// Assembling the disassembly of a module gives the module back
+    fn assert_round_trip(source: &str) {
+        let asm = compile(source, VmConfig::default()).expect("program compiles");
+        let module = assemble(&format!("{}\nHALT", asm)).expect("generated code assembles");
+        let listing = disassemble_module(&module).expect("module disassembles");
+        assert_eq!(assemble(&listing).expect("disassembly assembles"), module, "{}", listing);
+    }
+
+    #[test]
+    fn round_trip_functions() {
+        assert_round_trip("fn fib(n: i32) -> i32:\n    if n < 2:\n        return n\n    return fib(n - 1) + fib(n - 2)\nprint(fib(10))\n");
+    }
+
+    #[test]
+    fn round_trip_closures() {
+        assert_round_trip("fn adder(n: i32) -> fn(i32) -> i32:\n    return |x: i32| x + n\nadd = adder(2)\nprint(add(3))\n");
+    }
+
+    #[test]
+    fn round_trip_arrays_strings_and_floats() {
+        assert_round_trip("a = [1, 2, 3]\na.push(4)\nprint(a[1..3])\ns = \"a, \\\"b\\\"\\n\" + \"c\"\nprint(s)\nx = 2.5 * 1e-3\nprint(x)\ny = 5000000000i64\nprint(y)\n");
+    }
+
+    #[test]
+    fn bare_program_with_constants() {
+        let module = assemble("LOAD_K R0, \"hi\"\nLOAD_K R1, 2.5\nPRINT_REG R0\nHALT").unwrap();
+        let listing = disassemble(&module.code, &module.constants).unwrap();
+        assert_eq!(listing, "LOAD_K R0, \"hi\"\nLOAD_K R1, 2.5\nPRINT_REG R0\nHALT\n");
+    }
+}
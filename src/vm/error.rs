@@ -0,0 +1,136 @@
+use std::fmt;
+
+use super::instruction::InstructionSet;
+use super::value::Value;
+
+/// Why `VM::step` or `VM::run` returned control to the host
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitStatus {
+    Running,        // an instruction was executed and the VM can continue
+    Halted,         // a HALT instruction was executed
+    EndOfProgram,   // the program counter ran past the last instruction
+    Breakpoint,     // paused before executing the instruction at `pc`
+    Watchpoint { register: usize, old: Value, new: Value },    // paused after a watched register changed
+}
+
+/// A runtime fault. `pc` is the address of the faulting instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    InvalidRegister { pc: usize, instruction: InstructionSet, register: usize },
+    DivisionByZero { pc: usize, instruction: InstructionSet },
+    IntegerOverflow { pc: usize, instruction: InstructionSet, operands: Vec<i128> },
+    PcOutOfBounds { pc: usize, instruction: InstructionSet },
+    JumpOutOfBounds { pc: usize, instruction: InstructionSet, target: usize },
+    MemoryOutOfBounds { pc: usize, instruction: InstructionSet, address: usize },
+    ConstantOutOfBounds { pc: usize, instruction: InstructionSet, index: usize },
+    IndexOutOfBounds { pc: usize, instruction: InstructionSet, index: i128, len: usize },
+    SliceOutOfBounds { pc: usize, instruction: InstructionSet, start: i128, end: i128, len: usize },
+    EmptyArray { pc: usize, instruction: InstructionSet },
+    UpvalueOutOfBounds { pc: usize, instruction: InstructionSet, index: usize },
+    TypeError { pc: usize, instruction: InstructionSet, operands: Vec<&'static str> },
+    ConversionOutOfRange { pc: usize, instruction: InstructionSet, value: String },
+    StackOverflow { pc: usize, instruction: InstructionSet },
+    StackUnderflow { pc: usize, instruction: InstructionSet },
+    HeapLimitExceeded { pc: usize, instruction: InstructionSet, limit: usize },
+    Output { pc: usize, instruction: InstructionSet, message: String },
+    UnknownOpcode { pc: usize, opcode: i32 },
+    IterationLimit { pc: usize, instruction: InstructionSet, limit: u64 },
+}
+
+impl VmError {
+    /// Address of the instruction that caused the fault
+    pub fn pc(&self) -> usize {
+        match self {
+            VmError::InvalidRegister { pc, .. }
+            | VmError::DivisionByZero { pc, .. }
+            | VmError::IntegerOverflow { pc, .. }
+            | VmError::PcOutOfBounds { pc, .. }
+            | VmError::JumpOutOfBounds { pc, .. }
+            | VmError::MemoryOutOfBounds { pc, .. }
+            | VmError::ConstantOutOfBounds { pc, .. }
+            | VmError::IndexOutOfBounds { pc, .. }
+            | VmError::SliceOutOfBounds { pc, .. }
+            | VmError::EmptyArray { pc, .. }
+            | VmError::UpvalueOutOfBounds { pc, .. }
+            | VmError::TypeError { pc, .. }
+            | VmError::ConversionOutOfRange { pc, .. }
+            | VmError::StackOverflow { pc, .. }
+            | VmError::StackUnderflow { pc, .. }
+            | VmError::HeapLimitExceeded { pc, .. }
+            | VmError::Output { pc, .. }
+            | VmError::UnknownOpcode { pc, .. }
+            | VmError::IterationLimit { pc, .. } => *pc,
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::InvalidRegister { pc, instruction, register } => {
+                write!(f, "Invalid register index R{} in {} at PC={}", register, instruction.mnemonic(), pc)
+            }
+            VmError::DivisionByZero { pc, instruction } => {
+                write!(f, "Division by zero in {} at PC={}", instruction.mnemonic(), pc)
+            }
+            VmError::IntegerOverflow { pc, instruction, operands } => {
+                let operands: Vec<String> = operands.iter().map(i128::to_string).collect();
+                write!(f, "Integer overflow in {} at PC={}: {}", instruction.mnemonic(), pc, operands.join(" and "))
+            }
+            VmError::PcOutOfBounds { pc, instruction } => {
+                write!(f, "Program counter out of bounds while decoding {} at PC={}", instruction.mnemonic(), pc)
+            }
+            VmError::JumpOutOfBounds { pc, instruction, target } => {
+                write!(f, "Jump address {} out of bounds in {} at PC={}", target, instruction.mnemonic(), pc)
+            }
+            VmError::MemoryOutOfBounds { pc, instruction, address } => {
+                write!(f, "Memory address {} out of bounds in {} at PC={}", address, instruction.mnemonic(), pc)
+            }
+            VmError::ConstantOutOfBounds { pc, instruction, index } => {
+                write!(f, "Constant index {} out of bounds in {} at PC={}", index, instruction.mnemonic(), pc)
+            }
+            VmError::IndexOutOfBounds { pc, instruction, index, len } => {
+                write!(f, "Index {} out of bounds for array of length {} in {} at PC={}", index, len, instruction.mnemonic(), pc)
+            }
+            VmError::SliceOutOfBounds { pc, instruction, start, end, len } => {
+                write!(
+                    f,
+                    "Slice {}..{} out of bounds for array of length {} in {} at PC={}",
+                    start, end, len, instruction.mnemonic(), pc
+                )
+            }
+            VmError::EmptyArray { pc, instruction } => {
+                write!(f, "Pop from empty array in {} at PC={}", instruction.mnemonic(), pc)
+            }
+            VmError::UpvalueOutOfBounds { pc, instruction, index } => {
+                write!(f, "Upvalue index {} out of bounds in {} at PC={}", index, instruction.mnemonic(), pc)
+            }
+            VmError::TypeError { pc, instruction, operands } => {
+                write!(f, "Unsupported operand type(s) for {} at PC={}: {}", instruction.mnemonic(), pc, operands.join(" and "))
+            }
+            VmError::ConversionOutOfRange { pc, instruction, value } => {
+                write!(f, "Value {} out of range for {} at PC={}", value, instruction.mnemonic(), pc)
+            }
+            VmError::StackOverflow { pc, instruction } => {
+                write!(f, "Stack overflow in {} at PC={}", instruction.mnemonic(), pc)
+            }
+            VmError::StackUnderflow { pc, instruction } => {
+                write!(f, "Stack underflow in {} at PC={}", instruction.mnemonic(), pc)
+            }
+            VmError::HeapLimitExceeded { pc, instruction, limit } => {
+                write!(f, "Heap limit of {} bytes exceeded in {} at PC={}", limit, instruction.mnemonic(), pc)
+            }
+            VmError::Output { pc, instruction, message } => {
+                write!(f, "Failed to write output in {} at PC={}: {}", instruction.mnemonic(), pc, message)
+            }
+            VmError::UnknownOpcode { pc, opcode } => {
+                write!(f, "Unknown instruction {} at PC={}", opcode, pc)
+            }
+            VmError::IterationLimit { pc, instruction, limit } => {
+                write!(f, "Reached the limit of {} instructions in {} at PC={}", limit, instruction.mnemonic(), pc)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
@@ -1,238 +1,648 @@
-use super::machine::{VM, MAX_PROGRAM_SIZE, NUM_REGISTERS};
+use std::cmp::Ordering;
+use std::io::Write;
+
+use super::machine::VM;
 use super::instruction::InstructionSet;
+use super::error::VmError;
+use super::heap::{ObjRef, Object};
+use super::value::{Value, ValueType};
 
-pub fn execute_instruction(vm: &mut VM, instruction: InstructionSet) {
+pub fn execute_instruction(vm: &mut VM, instruction: InstructionSet) -> Result<(), VmError> {
     match instruction {
         InstructionSet::LoadConst => execute_load_const(vm),
-        InstructionSet::Add => execute_add(vm),
-        InstructionSet::Sub => execute_sub(vm),
-        InstructionSet::Mul => execute_mul(vm),
-        InstructionSet::Div => execute_div(vm),
-        InstructionSet::Mod => execute_mod(vm),
+        InstructionSet::Add => execute_arithmetic(vm, instruction, Arith::Add, None),
+        InstructionSet::Sub => execute_arithmetic(vm, instruction, Arith::Sub, None),
+        InstructionSet::Mul => execute_arithmetic(vm, instruction, Arith::Mul, None),
+        InstructionSet::Div => execute_arithmetic(vm, instruction, Arith::Div, None),
+        InstructionSet::Mod => execute_arithmetic(vm, instruction, Arith::Mod, None),
         InstructionSet::Mov => execute_mov(vm),
-        InstructionSet::PrintReg => execute_print_reg(vm),
+        InstructionSet::PrintReg => execute_print(vm, instruction, "\n"),
         InstructionSet::Halt => execute_halt(vm),
         InstructionSet::Jump => execute_jump(vm),
         InstructionSet::JumpIfNotZero => execute_jump_if_not_zero(vm),
+        InstructionSet::JumpIfLess => execute_jump_if_less(vm),
+        InstructionSet::JumpIfZero => execute_jump_if_zero(vm),
+        InstructionSet::Eq => execute_equality(vm, instruction, true, None),
+        InstructionSet::Ne => execute_equality(vm, instruction, false, None),
+        InstructionSet::Lt => execute_compare(vm, instruction, Ordering::is_lt, None),
+        InstructionSet::Le => execute_compare(vm, instruction, Ordering::is_le, None),
+        InstructionSet::Gt => execute_compare(vm, instruction, Ordering::is_gt, None),
+        InstructionSet::Ge => execute_compare(vm, instruction, Ordering::is_ge, None),
+        InstructionSet::Neg => execute_neg(vm),
+        InstructionSet::Not => execute_not(vm),
+        InstructionSet::Load => execute_load(vm),
+        InstructionSet::Store => execute_store(vm),
+        InstructionSet::Push => execute_push(vm),
+        InstructionSet::Pop => execute_pop(vm),
+        InstructionSet::LoadK => execute_load_k(vm),
+        InstructionSet::AddI32 => execute_arithmetic(vm, instruction, Arith::Add, Some(ValueType::I32)),
+        InstructionSet::SubI32 => execute_arithmetic(vm, instruction, Arith::Sub, Some(ValueType::I32)),
+        InstructionSet::MulI32 => execute_arithmetic(vm, instruction, Arith::Mul, Some(ValueType::I32)),
+        InstructionSet::DivI32 => execute_arithmetic(vm, instruction, Arith::Div, Some(ValueType::I32)),
+        InstructionSet::ModI32 => execute_arithmetic(vm, instruction, Arith::Mod, Some(ValueType::I32)),
+        InstructionSet::AddI64 => execute_arithmetic(vm, instruction, Arith::Add, Some(ValueType::I64)),
+        InstructionSet::SubI64 => execute_arithmetic(vm, instruction, Arith::Sub, Some(ValueType::I64)),
+        InstructionSet::MulI64 => execute_arithmetic(vm, instruction, Arith::Mul, Some(ValueType::I64)),
+        InstructionSet::DivI64 => execute_arithmetic(vm, instruction, Arith::Div, Some(ValueType::I64)),
+        InstructionSet::ModI64 => execute_arithmetic(vm, instruction, Arith::Mod, Some(ValueType::I64)),
+        InstructionSet::AddU32 => execute_arithmetic(vm, instruction, Arith::Add, Some(ValueType::U32)),
+        InstructionSet::SubU32 => execute_arithmetic(vm, instruction, Arith::Sub, Some(ValueType::U32)),
+        InstructionSet::MulU32 => execute_arithmetic(vm, instruction, Arith::Mul, Some(ValueType::U32)),
+        InstructionSet::DivU32 => execute_arithmetic(vm, instruction, Arith::Div, Some(ValueType::U32)),
+        InstructionSet::ModU32 => execute_arithmetic(vm, instruction, Arith::Mod, Some(ValueType::U32)),
+        InstructionSet::AddU64 => execute_arithmetic(vm, instruction, Arith::Add, Some(ValueType::U64)),
+        InstructionSet::SubU64 => execute_arithmetic(vm, instruction, Arith::Sub, Some(ValueType::U64)),
+        InstructionSet::MulU64 => execute_arithmetic(vm, instruction, Arith::Mul, Some(ValueType::U64)),
+        InstructionSet::DivU64 => execute_arithmetic(vm, instruction, Arith::Div, Some(ValueType::U64)),
+        InstructionSet::ModU64 => execute_arithmetic(vm, instruction, Arith::Mod, Some(ValueType::U64)),
+        InstructionSet::AddF64 => execute_arithmetic(vm, instruction, Arith::Add, Some(ValueType::F64)),
+        InstructionSet::SubF64 => execute_arithmetic(vm, instruction, Arith::Sub, Some(ValueType::F64)),
+        InstructionSet::MulF64 => execute_arithmetic(vm, instruction, Arith::Mul, Some(ValueType::F64)),
+        InstructionSet::DivF64 => execute_arithmetic(vm, instruction, Arith::Div, Some(ValueType::F64)),
+        InstructionSet::ModF64 => execute_arithmetic(vm, instruction, Arith::Mod, Some(ValueType::F64)),
+        InstructionSet::EqF64 => execute_equality(vm, instruction, true, Some(ValueType::F64)),
+        InstructionSet::NeF64 => execute_equality(vm, instruction, false, Some(ValueType::F64)),
+        InstructionSet::LtF64 => execute_compare(vm, instruction, Ordering::is_lt, Some(ValueType::F64)),
+        InstructionSet::LeF64 => execute_compare(vm, instruction, Ordering::is_le, Some(ValueType::F64)),
+        InstructionSet::GtF64 => execute_compare(vm, instruction, Ordering::is_gt, Some(ValueType::F64)),
+        InstructionSet::GeF64 => execute_compare(vm, instruction, Ordering::is_ge, Some(ValueType::F64)),
+        InstructionSet::ToI32 => execute_convert(vm, instruction, ValueType::I32),
+        InstructionSet::ToI64 => execute_convert(vm, instruction, ValueType::I64),
+        InstructionSet::ToU32 => execute_convert(vm, instruction, ValueType::U32),
+        InstructionSet::ToU64 => execute_convert(vm, instruction, ValueType::U64),
+        InstructionSet::ToF64 => execute_convert(vm, instruction, ValueType::F64),
+        InstructionSet::NewArray => execute_new_array(vm),
+        InstructionSet::ArrayGet => execute_array_get(vm),
+        InstructionSet::ArraySet => execute_array_set(vm),
+        InstructionSet::ArrayPush => execute_array_push(vm),
+        InstructionSet::ArrayPop => execute_array_pop(vm),
+        InstructionSet::ArrayLen => execute_array_len(vm),
+        InstructionSet::ArraySlice => execute_array_slice(vm),
+        InstructionSet::Call => execute_call(vm),
+        InstructionSet::Ret => execute_ret(vm),
+        InstructionSet::Closure => execute_closure(vm),
+        InstructionSet::Capture => execute_capture(vm),
+        InstructionSet::CaptureUpvalue => execute_capture_upvalue(vm),
+        InstructionSet::GetUpvalue => execute_get_upvalue(vm),
+        InstructionSet::SetUpvalue => execute_set_upvalue(vm),
+        InstructionSet::CloseUpvalue => execute_close_upvalue(vm),
+        InstructionSet::CallClosure => execute_call_closure(vm),
+        InstructionSet::PrintPart => execute_print(vm, instruction, " "),
     }
 }
-fn execute_load_const(vm: &mut VM) {
-    let reg_idx = match vm.get_register() {
-        Some(reg) => reg,
-        None => return,
-    };
-    let value = match vm.get_immediate() {
-        Some(val) => val,
-        None => return,
-    };
+fn execute_load_const(vm: &mut VM) -> Result<(), VmError> {
+    let reg_idx = vm.get_register()?;
+    let value = vm.get_immediate()?;
 
-    println!("LOAD_CONST R{}, {}", reg_idx, value);
+    vm.set_register(reg_idx, Value::I32(value));
+    Ok(())
+}
 
-    if reg_idx >= NUM_REGISTERS {
-        eprintln!("Error: Invalid register index {}", reg_idx);
-        vm.running = false;
-        return;
-    }
+fn execute_load_k(vm: &mut VM) -> Result<(), VmError> {
+    let reg_idx = vm.get_register()?;
+    let value = vm.get_constant()?;
 
-    vm.registers[reg_idx] = value;
+    vm.set_register(reg_idx, value);
+    Ok(())
 }
 
-fn execute_add(vm: &mut VM) {
-    let reg1_idx = match vm.get_register() {
-        Some(reg) => reg,
-        None => return,
-    };
-    let reg2_idx = match vm.get_register() {
-        Some(reg) => reg,
-        None => return,
-    };
+/// Error for operands an instruction has no meaning for
+fn type_error(vm: &VM, instruction: InstructionSet, operands: &[Value]) -> VmError {
+    VmError::TypeError {
+        pc: vm.instruction_pc,
+        instruction,
+        operands: operands.iter().map(|value| value.type_name()).collect(),
+    }
+}
 
-    println!("ADD R{}, R{}", reg1_idx, reg2_idx);
+fn overflow_error(vm: &VM, instruction: InstructionSet, operands: &[Value]) -> VmError {
+    VmError::IntegerOverflow {
+        pc: vm.instruction_pc,
+        instruction,
+        operands: operands.iter().filter_map(|value| value.as_integer()).collect(),
+    }
+}
 
-    if reg1_idx >= NUM_REGISTERS || reg2_idx >= NUM_REGISTERS {
-        eprintln!("Error: Invalid register index");
-        vm.running = false;
-        return;
+/// Typed instructions such as ADD_I64 accept only operands of their type
+fn check_operand_type(
+    vm: &VM,
+    instruction: InstructionSet,
+    operand_type: Option<ValueType>,
+    operands: &[Value],
+) -> Result<(), VmError> {
+    match operand_type {
+        Some(ty) if operands.iter().any(|value| value.value_type() != ty) => {
+            Err(type_error(vm, instruction, operands))
+        }
+        _ => Ok(()),
     }
+}
 
-    vm.registers[reg1_idx] += vm.registers[reg2_idx];
+/// The operation performed by an arithmetic instruction
+#[derive(Clone, Copy)]
+enum Arith {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
 }
 
-fn execute_sub(vm: &mut VM) {
-    let reg1_idx = match vm.get_register() {
-        Some(reg) => reg,
-        None => return,
-    };
-    let reg2_idx = match vm.get_register() {
-        Some(reg) => reg,
-        None => return,
-    };
+/// Why integer arithmetic has no result
+enum Fault {
+    DivisionByZero,
+    Overflow,
+}
 
-    println!("SUB R{}, R{}", reg1_idx, reg2_idx);
+// Integer arithmetic on two values of one primitive type. Overflow is a
+// fault unless `wrapping` is set; division by zero always is.
+macro_rules! integer_arithmetic {
+    ($op:expr, $a:expr, $b:expr, $wrapping:expr) => {{
+        let (a, b) = ($a, $b);
+        if matches!($op, Arith::Div | Arith::Mod) && b == 0 {
+            Err(Fault::DivisionByZero)
+        } else if $wrapping {
+            Ok(match $op {
+                Arith::Add => a.wrapping_add(b),
+                Arith::Sub => a.wrapping_sub(b),
+                Arith::Mul => a.wrapping_mul(b),
+                Arith::Div => a.wrapping_div(b),
+                Arith::Mod => a.wrapping_rem(b),
+            })
+        } else {
+            match $op {
+                Arith::Add => a.checked_add(b),
+                Arith::Sub => a.checked_sub(b),
+                Arith::Mul => a.checked_mul(b),
+                Arith::Div => a.checked_div(b),
+                Arith::Mod => a.checked_rem(b),
+            }
+            .ok_or(Fault::Overflow)
+        }
+    }};
+}
 
-    if reg1_idx >= NUM_REGISTERS || reg2_idx >= NUM_REGISTERS {
-        eprintln!("Error: Invalid register index");
-        vm.running = false;
-        return;
+fn float_arithmetic(op: Arith, a: f64, b: f64) -> f64 {
+    match op {
+        Arith::Add => a + b,
+        Arith::Sub => a - b,
+        Arith::Mul => a * b,
+        Arith::Div => a / b,
+        Arith::Mod => a % b,
     }
-
-    vm.registers[reg1_idx] -= vm.registers[reg2_idx];
 }
 
-fn execute_mul(vm: &mut VM) {
-    let reg1_idx = match vm.get_register() {
-        Some(reg) => reg,
-        None => return,
-    };
-    let reg2_idx = match vm.get_register() {
-        Some(reg) => reg,
-        None => return,
+/// Shared body of the arithmetic instructions: reg1 = reg1 op reg2, on two
+/// operands of the same numeric type. ADD also concatenates two strings
+/// into a new heap string.
+fn execute_arithmetic(
+    vm: &mut VM,
+    instruction: InstructionSet,
+    op: Arith,
+    operand_type: Option<ValueType>,
+) -> Result<(), VmError> {
+    let reg1_idx = vm.get_register()?;
+    let reg2_idx = vm.get_register()?;
+
+    let (a, b) = (vm.registers[reg1_idx], vm.registers[reg2_idx]);
+    check_operand_type(vm, instruction, operand_type, &[a, b])?;
+    let wrapping = vm.wrapping_arithmetic;
+    let result = match (a, b) {
+        (Value::I32(x), Value::I32(y)) => integer_arithmetic!(op, x, y, wrapping).map(Value::I32),
+        (Value::I64(x), Value::I64(y)) => integer_arithmetic!(op, x, y, wrapping).map(Value::I64),
+        (Value::U32(x), Value::U32(y)) => integer_arithmetic!(op, x, y, wrapping).map(Value::U32),
+        (Value::U64(x), Value::U64(y)) => integer_arithmetic!(op, x, y, wrapping).map(Value::U64),
+        (Value::F64(x), Value::F64(y)) => Ok(Value::F64(float_arithmetic(op, x, y))),
+        (Value::Str(x), Value::Str(y)) if matches!(op, Arith::Add) => {
+            let concatenated = format!("{}{}", vm.heap.string(x), vm.heap.string(y));
+            Ok(Value::Str(vm.alloc(Object::Str(concatenated))?))
+        }
+        _ => return Err(type_error(vm, instruction, &[a, b])),
     };
+    match result {
+        Ok(result) => {
+            vm.set_register(reg1_idx, result);
+            Ok(())
+        }
+        Err(Fault::DivisionByZero) => Err(VmError::DivisionByZero { pc: vm.instruction_pc, instruction }),
+        Err(Fault::Overflow) => Err(overflow_error(vm, instruction, &[a, b])),
+    }
+}
+
+fn execute_mov(vm: &mut VM) -> Result<(), VmError> {
+    let dest_reg = vm.get_register()?;
+    let src_reg = vm.get_register()?;
 
-    println!("MUL R{}, R{}", reg1_idx, reg2_idx);
+    vm.set_register(dest_reg, vm.registers[src_reg]);
+    Ok(())
+}
 
-    if reg2_idx >= NUM_REGISTERS || reg1_idx >= NUM_REGISTERS {
-        eprintln!("Error: Invalid register index");
-        vm.running = false;
-        return;
+/// Order of two numbers of the same type, or of two strings by their
+/// contents; None when a float is NaN
+fn order(vm: &VM, instruction: InstructionSet, a: Value, b: Value) -> Result<Option<Ordering>, VmError> {
+    match (a, b) {
+        (Value::I32(a), Value::I32(b)) => Ok(Some(a.cmp(&b))),
+        (Value::I64(a), Value::I64(b)) => Ok(Some(a.cmp(&b))),
+        (Value::U32(a), Value::U32(b)) => Ok(Some(a.cmp(&b))),
+        (Value::U64(a), Value::U64(b)) => Ok(Some(a.cmp(&b))),
+        (Value::F64(a), Value::F64(b)) => Ok(a.partial_cmp(&b)),
+        (Value::Str(a), Value::Str(b)) => Ok(Some(vm.heap.string(a).cmp(vm.heap.string(b)))),
+        _ => Err(type_error(vm, instruction, &[a, b])),
     }
+}
 
-    vm.registers[reg1_idx] = vm.registers[reg1_idx].wrapping_mul(vm.registers[reg2_idx]);
+/// Whether two values are equal: strings when their contents are, arrays
+/// when their elements are
+fn values_equal(vm: &VM, a: Value, b: Value) -> bool {
+    match (a, b) {
+        (Value::Str(x), Value::Str(y)) => vm.heap.string(x) == vm.heap.string(y),
+        (Value::Array(x), Value::Array(y)) => {
+            let (xs, ys) = (vm.heap.array(x), vm.heap.array(y));
+            x == y || (xs.len() == ys.len() && xs.iter().zip(ys).all(|(a, b)| values_equal(vm, *a, *b)))
+        }
+        _ => a == b,
+    }
 }
 
-fn execute_div(vm: &mut VM) {
-    let reg1_idx = match vm.get_register() {
-        Some(reg) => reg,
-        None => return,
-    };
-    let reg2_idx = match vm.get_register() {
-        Some(reg) => reg,
-        None => return,
-    };
+/// EQ and NE: values of different types are never equal, strings and
+/// arrays are compared by contents, and NaN is not equal to itself
+fn execute_equality(
+    vm: &mut VM,
+    instruction: InstructionSet,
+    equal: bool,
+    operand_type: Option<ValueType>,
+) -> Result<(), VmError> {
+    let reg1_idx = vm.get_register()?;
+    let reg2_idx = vm.get_register()?;
+
+    let (a, b) = (vm.registers[reg1_idx], vm.registers[reg2_idx]);
+    check_operand_type(vm, instruction, operand_type, &[a, b])?;
+    vm.set_register(reg1_idx, Value::Bool(values_equal(vm, a, b) == equal));
+    Ok(())
+}
 
-    println!("DIV R{}, R{}", reg1_idx, reg2_idx);
+/// Shared body of the ordering comparisons: reg1 = true if `cmp` holds.
+/// Comparisons involving NaN are false.
+fn execute_compare(
+    vm: &mut VM,
+    instruction: InstructionSet,
+    cmp: fn(Ordering) -> bool,
+    operand_type: Option<ValueType>,
+) -> Result<(), VmError> {
+    let reg1_idx = vm.get_register()?;
+    let reg2_idx = vm.get_register()?;
+
+    let (a, b) = (vm.registers[reg1_idx], vm.registers[reg2_idx]);
+    check_operand_type(vm, instruction, operand_type, &[a, b])?;
+    let ordering = order(vm, instruction, a, b)?;
+    vm.set_register(reg1_idx, Value::Bool(ordering.is_some_and(cmp)));
+    Ok(())
+}
 
-    if reg1_idx >= NUM_REGISTERS || reg2_idx >= NUM_REGISTERS {
-        eprintln!("Error: Invalid register index");
-        vm.running = false;
-        return;
+fn execute_neg(vm: &mut VM) -> Result<(), VmError> {
+    let reg_idx = vm.get_register()?;
+
+    let value = vm.registers[reg_idx];
+    let result = match value {
+        Value::I32(n) if vm.wrapping_arithmetic => Some(Value::I32(n.wrapping_neg())),
+        Value::I64(n) if vm.wrapping_arithmetic => Some(Value::I64(n.wrapping_neg())),
+        Value::I32(n) => n.checked_neg().map(Value::I32),
+        Value::I64(n) => n.checked_neg().map(Value::I64),
+        Value::F64(x) => Some(Value::F64(-x)),
+        other => return Err(type_error(vm, InstructionSet::Neg, &[other])),
+    };
+    match result {
+        Some(result) => {
+            vm.set_register(reg_idx, result);
+            Ok(())
+        }
+        None => Err(overflow_error(vm, InstructionSet::Neg, &[value])),
     }
+}
 
-    if vm.registers[reg2_idx] == 0 {
-        eprintln!("Error: Division by zero");
-        vm.running = false;
-        return;
+/// TO_I32 etc.: convert a number to `target`. Floats are truncated toward
+/// zero; values the target type cannot represent, NaN included, are errors.
+fn execute_convert(vm: &mut VM, instruction: InstructionSet, target: ValueType) -> Result<(), VmError> {
+    let reg_idx = vm.get_register()?;
+
+    let value = vm.registers[reg_idx];
+    let converted = match (value, value.as_integer()) {
+        (_, Some(n)) if target == ValueType::F64 => Some(Value::F64(n as f64)),
+        (_, Some(n)) => target.integer(n),
+        (Value::F64(_), _) if target == ValueType::F64 => Some(value),
+        (Value::F64(x), _) if x.is_nan() => None,
+        // Casting to i128 saturates infinities, which every target rejects
+        (Value::F64(x), _) => target.integer(x.trunc() as i128),
+        _ => return Err(type_error(vm, instruction, &[value])),
+    };
+    match converted {
+        Some(converted) => {
+            vm.set_register(reg_idx, converted);
+            Ok(())
+        }
+        None => Err(VmError::ConversionOutOfRange { pc: vm.instruction_pc, instruction, value: value.to_string() }),
     }
+}
+
+fn execute_not(vm: &mut VM) -> Result<(), VmError> {
+    let reg_idx = vm.get_register()?;
 
-    vm.registers[reg1_idx] /= vm.registers[reg2_idx];
+    vm.set_register(reg_idx, Value::Bool(!vm.registers[reg_idx].is_truthy()));
+    Ok(())
 }
 
-fn execute_mod(vm: &mut VM) {
-    let reg1_idx = match vm.get_register() {
-        Some(reg) => reg,
-        None => return,
-    };
-    let reg2_idx = match vm.get_register() {
-        Some(reg) => reg,
-        None => return,
-    };
+fn execute_load(vm: &mut VM) -> Result<(), VmError> {
+    let reg_idx = vm.get_register()?;
+    let addr = vm.get_memory_address()?;
+
+    vm.set_register(reg_idx, vm.memory[addr]);
+    Ok(())
+}
 
-    println!("MOD R{}, R{}", reg1_idx, reg2_idx);
+fn execute_store(vm: &mut VM) -> Result<(), VmError> {
+    let reg_idx = vm.get_register()?;
+    let addr = vm.get_memory_address()?;
 
-    if reg1_idx >= NUM_REGISTERS || reg2_idx >= NUM_REGISTERS {
-        eprintln!("Error: Invalid register index");
-        vm.running = false;
-        return;
+    vm.memory[addr] = vm.registers[reg_idx];
+    Ok(())
+}
+
+fn execute_push(vm: &mut VM) -> Result<(), VmError> {
+    let reg_idx = vm.get_register()?;
+
+    if vm.stack.len() >= vm.stack_size {
+        return Err(VmError::StackOverflow { pc: vm.instruction_pc, instruction: InstructionSet::Push });
     }
 
-    if vm.registers[reg2_idx] == 0 {
-        eprintln!("Error: Modulo by zero");
-        vm.running = false;
-        return;
+    vm.stack.push(vm.registers[reg_idx]);
+    Ok(())
+}
+
+fn execute_pop(vm: &mut VM) -> Result<(), VmError> {
+    let reg_idx = vm.get_register()?;
+
+    match vm.stack.pop() {
+        Some(value) => {
+            vm.set_register(reg_idx, value);
+            Ok(())
+        }
+        None => Err(VmError::StackUnderflow { pc: vm.instruction_pc, instruction: InstructionSet::Pop }),
     }
+}
+
+/// Print a register's value followed by `end`
+fn execute_print(vm: &mut VM, instruction: InstructionSet, end: &str) -> Result<(), VmError> {
+    let reg_idx = vm.get_register()?;
 
-    vm.registers[reg1_idx] %= vm.registers[reg2_idx];
+    let text = vm.format_value(vm.registers[reg_idx]);
+    write!(vm.output, "{}{}", text, end).map_err(|err| VmError::Output {
+        pc: vm.instruction_pc,
+        instruction,
+        message: err.to_string(),
+    })
 }
 
-fn execute_mov(vm: &mut VM) {
-    let dest_reg = match vm.get_register() {
-        Some(reg) => reg,
-        None => return,
-    };
-    let src_reg = match vm.get_register() {
-        Some(reg) => reg,
-        None => return,
+/// The array an operand refers to
+fn array_operand(vm: &VM, instruction: InstructionSet, value: Value) -> Result<ObjRef, VmError> {
+    match value {
+        Value::Array(obj) => Ok(obj),
+        other => Err(type_error(vm, instruction, &[other])),
+    }
+}
+
+/// Position of `index` in an array of length `len`, checked against it
+fn array_index(vm: &VM, instruction: InstructionSet, index: Value, len: usize) -> Result<usize, VmError> {
+    let Some(n) = index.as_integer() else {
+        return Err(type_error(vm, instruction, &[index]));
     };
+    usize::try_from(n).ok().filter(|&i| i < len).ok_or(VmError::IndexOutOfBounds {
+        pc: vm.instruction_pc,
+        instruction,
+        index: n,
+        len,
+    })
+}
 
-    println!("MOV R{}, R{}", dest_reg, src_reg);
+fn execute_new_array(vm: &mut VM) -> Result<(), VmError> {
+    let reg_idx = vm.get_register()?;
 
-    if dest_reg >= NUM_REGISTERS || src_reg >= NUM_REGISTERS {
-        eprintln!("Error: Invalid register index");
-        vm.running = false;
-        return;
-    }
+    let array = vm.alloc(Object::Array(Vec::new()))?;
+    vm.set_register(reg_idx, Value::Array(array));
+    Ok(())
+}
+
+fn execute_array_get(vm: &mut VM) -> Result<(), VmError> {
+    let dest_reg = vm.get_register()?;
+    let array_reg = vm.get_register()?;
+    let index_reg = vm.get_register()?;
 
-    vm.registers[dest_reg] = vm.registers[src_reg];
+    let array = array_operand(vm, InstructionSet::ArrayGet, vm.registers[array_reg])?;
+    let len = vm.heap.array(array).len();
+    let index = array_index(vm, InstructionSet::ArrayGet, vm.registers[index_reg], len)?;
+    vm.set_register(dest_reg, vm.heap.array(array)[index]);
+    Ok(())
 }
 
-fn execute_print_reg(vm: &mut VM) {
-    let reg_idx = match vm.get_register() {
-        Some(reg) => reg,
-        None => return,
-    };
+fn execute_array_set(vm: &mut VM) -> Result<(), VmError> {
+    let array_reg = vm.get_register()?;
+    let index_reg = vm.get_register()?;
+    let value_reg = vm.get_register()?;
+
+    let array = array_operand(vm, InstructionSet::ArraySet, vm.registers[array_reg])?;
+    let len = vm.heap.array(array).len();
+    let index = array_index(vm, InstructionSet::ArraySet, vm.registers[index_reg], len)?;
+    vm.heap.array_mut(array)[index] = vm.registers[value_reg];
+    Ok(())
+}
 
-    println!("PRINT_REG R{}", reg_idx);
+fn execute_array_push(vm: &mut VM) -> Result<(), VmError> {
+    let array_reg = vm.get_register()?;
+    let value_reg = vm.get_register()?;
 
-    if reg_idx >= NUM_REGISTERS {
-        eprintln!("Error: Invalid register index");
-        vm.running = false;
-        return;
+    let array = array_operand(vm, InstructionSet::ArrayPush, vm.registers[array_reg])?;
+    vm.push_element(array, vm.registers[value_reg])
+}
+
+fn execute_array_pop(vm: &mut VM) -> Result<(), VmError> {
+    let dest_reg = vm.get_register()?;
+    let array_reg = vm.get_register()?;
+
+    let array = array_operand(vm, InstructionSet::ArrayPop, vm.registers[array_reg])?;
+    match vm.heap.pop(array) {
+        Some(value) => {
+            vm.set_register(dest_reg, value);
+            Ok(())
+        }
+        None => Err(VmError::EmptyArray { pc: vm.instruction_pc, instruction: InstructionSet::ArrayPop }),
     }
+}
 
-    println!("Register R{} = {}", reg_idx,  vm.registers[reg_idx]);
+/// ARRAY_LEN: the length as an i32
+fn execute_array_len(vm: &mut VM) -> Result<(), VmError> {
+    let dest_reg = vm.get_register()?;
+    let array_reg = vm.get_register()?;
+
+    let array = array_operand(vm, InstructionSet::ArrayLen, vm.registers[array_reg])?;
+    let len = vm.heap.array(array).len();
+    let len = i32::try_from(len).map_err(|_| VmError::IntegerOverflow {
+        pc: vm.instruction_pc,
+        instruction: InstructionSet::ArrayLen,
+        operands: vec![len as i128],
+    })?;
+    vm.set_register(dest_reg, Value::I32(len));
+    Ok(())
 }
 
-fn execute_jump(vm: &mut VM) {
-    let addr = match vm.get_immediate() {
-        Some(addr) => addr as usize,
-        None => return,
+/// ARRAY_SLICE dest, array, start, end: a new array of the elements from
+/// start up to, not including, end
+fn execute_array_slice(vm: &mut VM) -> Result<(), VmError> {
+    let dest_reg = vm.get_register()?;
+    let array_reg = vm.get_register()?;
+    let start_reg = vm.get_register()?;
+    let end_reg = vm.get_register()?;
+
+    let instruction = InstructionSet::ArraySlice;
+    let array = array_operand(vm, instruction, vm.registers[array_reg])?;
+    let (start, end) = (vm.registers[start_reg], vm.registers[end_reg]);
+    let (Some(start), Some(end)) = (start.as_integer(), end.as_integer()) else {
+        return Err(type_error(vm, instruction, &[start, end]));
     };
+    let elements = vm.heap.array(array);
+    if start < 0 || start > end || end > elements.len() as i128 {
+        return Err(VmError::SliceOutOfBounds { pc: vm.instruction_pc, instruction, start, end, len: elements.len() });
+    }
+    let slice = elements[start as usize..end as usize].to_vec();
+    let slice = vm.alloc(Object::Array(slice))?;
+    vm.set_register(dest_reg, Value::Array(slice));
+    Ok(())
+}
 
-    println!("JMP to address {}", addr);
+fn execute_jump(vm: &mut VM) -> Result<(), VmError> {
+    let addr = vm.get_jump_target()?;
 
-    if addr >= MAX_PROGRAM_SIZE {
-        eprintln!("Error: Jump address out of bounds");
-        vm.running = false;
-        return;
-    }
+    vm.jump(addr);
+    Ok(())
+}
+
+fn execute_jump_if_not_zero(vm: &mut VM) -> Result<(), VmError> {
+    let reg_idx = vm.get_register()?;
+    let addr = vm.get_jump_target()?;
 
-    vm.pc = addr;
+    if vm.registers[reg_idx].is_truthy() {
+        vm.jump(addr);
+    }
+    Ok(())
 }
 
-fn execute_jump_if_not_zero(vm: &mut VM) {
-    let reg_idx = match vm.get_register() {
-        Some(reg) => reg,
-        None => return,
-    };
-    let addr = match vm.get_immediate() {
-        Some(addr) => addr as usize,
-        None => return,
-    };
+fn execute_jump_if_zero(vm: &mut VM) -> Result<(), VmError> {
+    let reg_idx = vm.get_register()?;
+    let addr = vm.get_jump_target()?;
+
+    if !vm.registers[reg_idx].is_truthy() {
+        vm.jump(addr);
+    }
+    Ok(())
+}
 
-    println!("JUMP_IF_NOT_ZERO R{}, {}", reg_idx, addr);
+fn execute_jump_if_less(vm: &mut VM) -> Result<(), VmError> {
+    let reg1_idx = vm.get_register()?;
+    let reg2_idx = vm.get_register()?;
+    let addr = vm.get_jump_target()?;
 
-    if reg_idx >= NUM_REGISTERS {
-        eprintln!("Error: Invalid register index");
-        vm.running = false;
-        return;
+    let ordering = order(vm, InstructionSet::JumpIfLess, vm.registers[reg1_idx], vm.registers[reg2_idx])?;
+    if ordering == Some(Ordering::Less) {
+        vm.jump(addr);
     }
+    Ok(())
+}
+
+/// CALL dest, target: the callee's R0.. are a fresh window; arguments are
+/// passed on the stack
+fn execute_call(vm: &mut VM) -> Result<(), VmError> {
+    let dest_reg = vm.get_register()?;
+    let addr = vm.get_jump_target()?;
+
+    vm.call(addr, dest_reg, None)
+}
 
-    if vm.registers[reg_idx] != 0 {
-        vm.pc = addr;
+fn execute_ret(vm: &mut VM) -> Result<(), VmError> {
+    let reg_idx = vm.get_register()?;
+
+    vm.ret(vm.registers[reg_idx])
+}
+
+fn closure_operand(vm: &VM, instruction: InstructionSet, value: Value) -> Result<ObjRef, VmError> {
+    match value {
+        Value::Closure(obj) => Ok(obj),
+        other => Err(type_error(vm, instruction, &[other])),
     }
 }
 
-fn execute_halt(vm: &mut VM) {
+/// CLOSURE dest, target: a closure for the code at target, with no upvalues
+/// until CAPTURE adds them
+fn execute_closure(vm: &mut VM) -> Result<(), VmError> {
+    let dest_reg = vm.get_register()?;
+    let addr = vm.get_jump_target()?;
+
+    let closure = vm.alloc(Object::Closure { function: addr, upvalues: Vec::new() })?;
+    vm.set_register(dest_reg, Value::Closure(closure));
+    Ok(())
+}
+
+fn execute_capture(vm: &mut VM) -> Result<(), VmError> {
+    let closure_reg = vm.get_register()?;
+    let reg_idx = vm.get_register()?;
+
+    let closure = closure_operand(vm, InstructionSet::Capture, vm.registers[closure_reg])?;
+    let upvalue = vm.capture(reg_idx)?;
+    vm.add_upvalue(closure, upvalue)
+}
+
+fn execute_capture_upvalue(vm: &mut VM) -> Result<(), VmError> {
+    let closure_reg = vm.get_register()?;
+    let index = vm.get_immediate()? as usize;
+
+    let closure = closure_operand(vm, InstructionSet::CaptureUpvalue, vm.registers[closure_reg])?;
+    let upvalue = vm.upvalue(index)?;
+    vm.add_upvalue(closure, upvalue)
+}
+
+fn execute_get_upvalue(vm: &mut VM) -> Result<(), VmError> {
+    let reg_idx = vm.get_register()?;
+    let index = vm.get_immediate()? as usize;
+
+    let upvalue = vm.upvalue(index)?;
+    vm.set_register(reg_idx, vm.read_upvalue(upvalue));
+    Ok(())
+}
+
+fn execute_set_upvalue(vm: &mut VM) -> Result<(), VmError> {
+    let reg_idx = vm.get_register()?;
+    let index = vm.get_immediate()? as usize;
+
+    let upvalue = vm.upvalue(index)?;
+    vm.write_upvalue(upvalue, vm.registers[reg_idx]);
+    Ok(())
+}
+
+fn execute_close_upvalue(vm: &mut VM) -> Result<(), VmError> {
+    let reg_idx = vm.get_register()?;
+
+    vm.close_upvalue(reg_idx);
+    Ok(())
+}
+
+/// CALL_CLOSURE dest, closure: like CALL, for the closure's code
+fn execute_call_closure(vm: &mut VM) -> Result<(), VmError> {
+    let dest_reg = vm.get_register()?;
+    let closure_reg = vm.get_register()?;
+
+    let closure = closure_operand(vm, InstructionSet::CallClosure, vm.registers[closure_reg])?;
+    let (addr, _) = vm.heap.closure(closure);
+    vm.call(addr, dest_reg, Some(closure))
+}
+
+fn execute_halt(vm: &mut VM) -> Result<(), VmError> {
     vm.running = false;
-    println!("HALT instruction encountered. Shutting down VM.");
+    Ok(())
 }
@@ -0,0 +1,341 @@
+use std::mem;
+
+use super::value::Value;
+
+/// The heap collects garbage once it has grown by this many bytes at first;
+/// after each collection, once it has doubled
+const INITIAL_GC_THRESHOLD: usize = 1024 * 1024;
+
+/// Reference to an object on the VM's heap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjRef(pub(crate) usize);
+
+impl ObjRef {
+    /// Slot of the object in its heap; slots of collected objects are reused
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// Bytes each array element is accounted for
+pub const ELEMENT_SIZE: usize = mem::size_of::<Value>();
+
+/// Bytes each upvalue of a closure is accounted for, besides the upvalue
+pub const UPVALUE_SIZE: usize = mem::size_of::<ObjRef>();
+
+/// A variable captured by a closure. It is open while the variable still
+/// lives in a register, given as an index into the whole register file, and
+/// closed over a copy of its value once the variable's scope has ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+/// A heap-allocated value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Object {
+    Str(String),
+    Array(Vec<Value>),
+    // Function code address and the upvalues it reads with GET_UPVALUE
+    Closure { function: usize, upvalues: Vec<ObjRef> },
+    Upvalue(Upvalue),
+}
+
+impl Object {
+    /// Bytes the object is accounted for against the heap limit
+    pub fn size(&self) -> usize {
+        mem::size_of::<Object>()
+            + match self {
+                Object::Str(s) => s.len(),
+                Object::Array(elements) => elements.len() * ELEMENT_SIZE,
+                Object::Closure { upvalues, .. } => upvalues.len() * UPVALUE_SIZE,
+                Object::Upvalue(_) => 0,
+            }
+    }
+
+    /// Push the objects this one refers to
+    fn trace(&self, gray: &mut Vec<ObjRef>) {
+        match self {
+            Object::Str(_) | Object::Upvalue(Upvalue::Open(_)) => {}
+            Object::Array(elements) => gray.extend(elements.iter().filter_map(|value| value.object())),
+            Object::Closure { upvalues, .. } => gray.extend(upvalues),
+            Object::Upvalue(Upvalue::Closed(value)) => gray.extend(value.object()),
+        }
+    }
+}
+
+/// Allocation statistics, as returned by `Heap::stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    pub objects: usize,         // live objects
+    pub bytes: usize,           // bytes held by live objects
+    pub peak_bytes: usize,      // most bytes held at any time
+    pub allocations: u64,       // objects allocated in total
+    pub collections: u64,       // garbage collections run
+    pub freed: u64,             // objects reclaimed by collections
+}
+
+/// Objects that registers, memory, the stack and other objects refer to
+/// through `ObjRef`s. Unreachable objects are reclaimed by a mark-and-sweep
+/// collector, which the VM runs when it needs room on the heap.
+#[derive(Debug)]
+pub struct Heap {
+    slots: Vec<Option<Object>>,
+    free: Vec<usize>,           // empty slots, reused before the heap grows
+    marks: Vec<bool>,           // reachability, per slot, during a collection
+    limit: usize,               // most bytes live objects may hold
+    next_gc: usize,             // collect before the heap grows past this
+    stats: HeapStats,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap::with_limit(usize::MAX)
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap::default()
+    }
+
+    /// A heap whose live objects may hold at most `limit` bytes
+    pub fn with_limit(limit: usize) -> Self {
+        Heap {
+            slots: Vec::new(),
+            free: Vec::new(),
+            marks: Vec::new(),
+            limit,
+            next_gc: INITIAL_GC_THRESHOLD,
+            stats: HeapStats::default(),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        self.stats
+    }
+
+    /// Whether allocating `size` more bytes should be preceded by a collection
+    pub fn needs_collection(&self, size: usize) -> bool {
+        self.stats.bytes.saturating_add(size) > self.next_gc
+    }
+
+    /// Whether allocating `size` more bytes would go over the limit
+    pub fn exceeds_limit(&self, size: usize) -> bool {
+        self.stats.bytes.saturating_add(size) > self.limit
+    }
+
+    /// Store an object. Neither the limit nor the collection threshold is
+    /// checked; `VM::alloc` does that.
+    pub fn alloc(&mut self, object: Object) -> ObjRef {
+        self.stats.bytes += object.size();
+        self.stats.peak_bytes = self.stats.peak_bytes.max(self.stats.bytes);
+        self.stats.objects += 1;
+        self.stats.allocations += 1;
+        match self.free.pop() {
+            Some(index) => {
+                self.slots[index] = Some(object);
+                ObjRef(index)
+            }
+            None => {
+                self.slots.push(Some(object));
+                ObjRef(self.slots.len() - 1)
+            }
+        }
+    }
+
+    /// The object `obj` refers to; it must not have been collected
+    pub fn get(&self, obj: ObjRef) -> &Object {
+        self.slots[obj.0].as_ref().expect("reference to a collected object")
+    }
+
+    /// Contents of a string object
+    pub fn string(&self, obj: ObjRef) -> &str {
+        match self.get(obj) {
+            Object::Str(s) => s,
+            _ => panic!("object #{} is not a string", obj.0),
+        }
+    }
+
+    /// Elements of an array object
+    pub fn array(&self, obj: ObjRef) -> &[Value] {
+        match self.get(obj) {
+            Object::Array(elements) => elements,
+            _ => panic!("object #{} is not an array", obj.0),
+        }
+    }
+
+    /// Elements of an array object, to be overwritten in place
+    pub fn array_mut(&mut self, obj: ObjRef) -> &mut [Value] {
+        self.elements(obj)
+    }
+
+    /// Append to an array; like `alloc`, the limit is not checked
+    pub fn push(&mut self, obj: ObjRef, value: Value) {
+        self.elements(obj).push(value);
+        self.stats.bytes += ELEMENT_SIZE;
+        self.stats.peak_bytes = self.stats.peak_bytes.max(self.stats.bytes);
+    }
+
+    /// Remove the last element of an array
+    pub fn pop(&mut self, obj: ObjRef) -> Option<Value> {
+        let value = self.elements(obj).pop()?;
+        self.stats.bytes -= ELEMENT_SIZE;
+        Some(value)
+    }
+
+    /// Code address and upvalues of a closure object
+    pub fn closure(&self, obj: ObjRef) -> (usize, &[ObjRef]) {
+        match self.get(obj) {
+            Object::Closure { function, upvalues } => (*function, upvalues),
+            _ => panic!("object #{} is not a closure", obj.0),
+        }
+    }
+
+    /// Give a closure another upvalue; like `alloc`, the limit is not checked
+    pub fn add_upvalue(&mut self, closure: ObjRef, upvalue: ObjRef) {
+        match self.slots[closure.0].as_mut() {
+            Some(Object::Closure { upvalues, .. }) => upvalues.push(upvalue),
+            _ => panic!("object #{} is not a closure", closure.0),
+        }
+        self.stats.bytes += UPVALUE_SIZE;
+        self.stats.peak_bytes = self.stats.peak_bytes.max(self.stats.bytes);
+    }
+
+    pub fn upvalue(&self, obj: ObjRef) -> Upvalue {
+        match self.get(obj) {
+            Object::Upvalue(upvalue) => *upvalue,
+            _ => panic!("object #{} is not an upvalue", obj.0),
+        }
+    }
+
+    pub fn set_upvalue(&mut self, obj: ObjRef, upvalue: Upvalue) {
+        match self.slots[obj.0].as_mut() {
+            Some(Object::Upvalue(slot)) => *slot = upvalue,
+            _ => panic!("object #{} is not an upvalue", obj.0),
+        }
+    }
+
+    fn elements(&mut self, obj: ObjRef) -> &mut Vec<Value> {
+        match self.slots[obj.0].as_mut() {
+            Some(Object::Array(elements)) => elements,
+            _ => panic!("object #{} is not an array", obj.0),
+        }
+    }
+
+    /// Number of live objects
+    pub fn len(&self) -> usize {
+        self.stats.objects
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stats.objects == 0
+    }
+
+    /// Mark every object reachable from `roots` and free the rest
+    pub fn collect(&mut self, roots: impl IntoIterator<Item = ObjRef>) {
+        self.marks.clear();
+        self.marks.resize(self.slots.len(), false);
+        let mut gray: Vec<ObjRef> = roots.into_iter().collect();
+        while let Some(obj) = gray.pop() {
+            if self.marks[obj.0] {
+                continue;
+            }
+            self.marks[obj.0] = true;
+            self.get(obj).trace(&mut gray);
+        }
+
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if self.marks[index] {
+                continue;
+            }
+            if let Some(object) = slot.take() {
+                self.stats.bytes -= object.size();
+                self.stats.objects -= 1;
+                self.stats.freed += 1;
+                self.free.push(index);
+            }
+        }
+        self.stats.collections += 1;
+        self.next_gc = (self.stats.bytes * 2).max(INITIAL_GC_THRESHOLD);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::codegen::tests::compile;
+    use crate::vm::assembler::assemble;
+    use crate::vm::error::{ExitStatus, VmError};
+    use crate::vm::instruction::InstructionSet;
+    use crate::vm::machine::{VmConfig, VM};
+
+    fn string(text: &str) -> Object {
+        Object::Str(text.to_string())
+    }
+
+    #[test]
+    fn collection_frees_unreachable_objects() {
+        let mut heap = Heap::new();
+        let element = heap.alloc(string("kept"));
+        let array = heap.alloc(Object::Array(vec![Value::Str(element), Value::I32(1)]));
+        let garbage = heap.alloc(string("garbage"));
+        let captured = heap.alloc(string("captured"));
+        let upvalue = heap.alloc(Object::Upvalue(Upvalue::Closed(Value::Str(captured))));
+        let closure = heap.alloc(Object::Closure { function: 0, upvalues: vec![upvalue] });
+        // An array holding itself is garbage once nothing else refers to it
+        let cycle = heap.alloc(Object::Array(Vec::new()));
+        heap.push(cycle, Value::Array(cycle));
+        let bytes = heap.stats().bytes;
+
+        heap.collect([array, closure]);
+        let stats = heap.stats();
+        assert_eq!((stats.objects, stats.freed, stats.collections), (5, 2, 1));
+        assert_eq!(stats.bytes, bytes - string("garbage").size() - Object::Array(vec![Value::Nil]).size());
+        assert_eq!(heap.string(element), "kept");
+        assert_eq!(heap.upvalue(upvalue), Upvalue::Closed(Value::Str(captured)));
+        assert_eq!(heap.string(captured), "captured");
+
+        // Freed slots are reused before the heap grows
+        let reused = heap.alloc(string("new"));
+        assert!(reused == garbage || reused == cycle);
+
+        heap.collect([]);
+        assert!(heap.is_empty());
+        assert_eq!(heap.stats().bytes, 0);
+        assert_eq!(heap.stats().peak_bytes, bytes);
+    }
+
+    #[test]
+    fn heap_limit_counts_live_objects_only() {
+        // Each iteration's array is garbage by the next one
+        let source = "mut n = 0\nfor i in 0..20000:\n    s = [i, i, i, i]\n    n = n + s.len()\nprint(n)\n";
+        let config = VmConfig { heap_limit: 100_000, ..VmConfig::default() };
+        let module = assemble(&format!("{}\nHALT", compile(source, config).unwrap())).unwrap();
+        let mut vm = VM::with_config(config);
+        vm.set_output_callback(|_| {});
+        vm.load_module(&module);
+        assert_eq!(vm.run(), Ok(ExitStatus::Halted));
+        let stats = vm.heap.stats();
+        assert_eq!(stats.allocations, 20000);
+        assert!(stats.collections > 0 && stats.peak_bytes <= 100_000, "{:?}", stats);
+
+        // An array that keeps growing is live to the end and goes over it
+        let source = "mut a: [i32] = []\nfor i in 0..100000:\n    a.push(i)\nprint(a.len())\n";
+        let module = assemble(&format!("{}\nHALT", compile(source, config).unwrap())).unwrap();
+        let mut vm = VM::with_config(config);
+        vm.load_module(&module);
+        match vm.run() {
+            Err(VmError::HeapLimitExceeded { instruction, limit, .. }) => {
+                assert_eq!((instruction, limit), (InstructionSet::ArrayPush, 100_000));
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(vm.heap.stats().bytes <= 100_000);
+    }
+}
@@ -1,16 +1,167 @@
-/// Define the instruction set for the virtual machine
+/// How an instruction uses a register operand
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// What an operand word of an instruction refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperandKind {
+    Register(Access),   // register index, written R<n>
+    Immediate,      // literal value or data memory address
+    Address,        // program address, written as a label
+    Constant,       // constant pool index, written as the constant's value
+}
+
+/// Metadata for one opcode: the single source of truth for encoding and decoding
 #[derive(Debug, Clone, Copy)]
-pub enum InstructionSet {
-    LoadConst = 0,          // LOAD_CONST <reg> <value>
-    Mov = 1,                // MOV <dest_reg> <src_reg>
-    Add = 2,                // ADD <reg1> <reg2>
-    Sub = 3,                // SUB <reg1> <reg2>
-    Mul = 4,                // MUL <reg1> <reg2>
-    Mod = 5,                // MOD <reg1> <reg2>
-    Div = 6,                // DIV <reg1> <reg2>
-    PrintReg = 7,           // PRINT_REG <reg>
-    Halt = 8,               // HALT
-    Jump = 9,                // JMP <addr>
-    JumpIfNotZero = 10,      // JMP_IF_NOT_ZERO <reg> <addr>
+pub struct OpcodeInfo {
+    pub instruction: InstructionSet,
+    pub mnemonic: &'static str,
+    pub operands: &'static [OperandKind],
+}
+
+// Declares `InstructionSet` and `OPCODES` from one table. Opcodes must be
+// listed in order starting at 0, since `OPCODES` is indexed by opcode.
+macro_rules! instruction_set {
+    ($($variant:ident = $opcode:literal, $mnemonic:literal, [$($kind:ident $(($access:ident))?),*];)*) => {
+        /// Define the instruction set for the virtual machine
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum InstructionSet {
+            $($variant = $opcode,)*
+        }
+
+        /// Every instruction, indexed by opcode
+        pub const OPCODES: &[OpcodeInfo] = &[
+            $(OpcodeInfo {
+                instruction: InstructionSet::$variant,
+                mnemonic: $mnemonic,
+                operands: &[$(OperandKind::$kind $((Access::$access))?),*],
+            },)*
+        ];
+    };
+}
+
+instruction_set! {
+    LoadConst = 0,      "LOAD_CONST",       [Register(Write), Immediate];
+    Mov = 1,            "MOV",              [Register(Write), Register(Read)];
+    Add = 2,            "ADD",              [Register(ReadWrite), Register(Read)];
+    Sub = 3,            "SUB",              [Register(ReadWrite), Register(Read)];
+    Mul = 4,            "MUL",              [Register(ReadWrite), Register(Read)];
+    Mod = 5,            "MOD",              [Register(ReadWrite), Register(Read)];
+    Div = 6,            "DIV",              [Register(ReadWrite), Register(Read)];
+    PrintReg = 7,       "PRINT_REG",        [Register(Read)];
+    Halt = 8,           "HALT",             [];
+    Jump = 9,           "JMP",              [Address];
+    JumpIfNotZero = 10, "JMP_IF_NOT_ZERO",  [Register(Read), Address];
+    JumpIfLess = 11,    "JMP_IF_LESS",      [Register(Read), Register(Read), Address];
+    JumpIfZero = 12,    "JMP_IF_ZERO",      [Register(Read), Address];
+    Eq = 13,            "EQ",               [Register(ReadWrite), Register(Read)];
+    Ne = 14,            "NE",               [Register(ReadWrite), Register(Read)];
+    Lt = 15,            "LT",               [Register(ReadWrite), Register(Read)];
+    Le = 16,            "LE",               [Register(ReadWrite), Register(Read)];
+    Gt = 17,            "GT",               [Register(ReadWrite), Register(Read)];
+    Ge = 18,            "GE",               [Register(ReadWrite), Register(Read)];
+    Neg = 19,           "NEG",              [Register(ReadWrite)];
+    Not = 20,           "NOT",              [Register(ReadWrite)];
+    Load = 21,          "LOAD",             [Register(Write), Immediate];
+    Store = 22,         "STORE",            [Register(Read), Immediate];
+    Push = 23,          "PUSH",             [Register(Read)];
+    Pop = 24,           "POP",              [Register(Write)];
+    LoadK = 25,         "LOAD_K",           [Register(Write), Constant];
+    // Arithmetic and comparison on two operands of one type; ADD, LT etc.
+    // accept any numeric type
+    AddI32 = 26,        "ADD_I32",          [Register(ReadWrite), Register(Read)];
+    SubI32 = 27,        "SUB_I32",          [Register(ReadWrite), Register(Read)];
+    MulI32 = 28,        "MUL_I32",          [Register(ReadWrite), Register(Read)];
+    DivI32 = 29,        "DIV_I32",          [Register(ReadWrite), Register(Read)];
+    ModI32 = 30,        "MOD_I32",          [Register(ReadWrite), Register(Read)];
+    AddI64 = 31,        "ADD_I64",          [Register(ReadWrite), Register(Read)];
+    SubI64 = 32,        "SUB_I64",          [Register(ReadWrite), Register(Read)];
+    MulI64 = 33,        "MUL_I64",          [Register(ReadWrite), Register(Read)];
+    DivI64 = 34,        "DIV_I64",          [Register(ReadWrite), Register(Read)];
+    ModI64 = 35,        "MOD_I64",          [Register(ReadWrite), Register(Read)];
+    AddU32 = 36,        "ADD_U32",          [Register(ReadWrite), Register(Read)];
+    SubU32 = 37,        "SUB_U32",          [Register(ReadWrite), Register(Read)];
+    MulU32 = 38,        "MUL_U32",          [Register(ReadWrite), Register(Read)];
+    DivU32 = 39,        "DIV_U32",          [Register(ReadWrite), Register(Read)];
+    ModU32 = 40,        "MOD_U32",          [Register(ReadWrite), Register(Read)];
+    AddU64 = 41,        "ADD_U64",          [Register(ReadWrite), Register(Read)];
+    SubU64 = 42,        "SUB_U64",          [Register(ReadWrite), Register(Read)];
+    MulU64 = 43,        "MUL_U64",          [Register(ReadWrite), Register(Read)];
+    DivU64 = 44,        "DIV_U64",          [Register(ReadWrite), Register(Read)];
+    ModU64 = 45,        "MOD_U64",          [Register(ReadWrite), Register(Read)];
+    AddF64 = 46,        "ADD_F64",          [Register(ReadWrite), Register(Read)];
+    SubF64 = 47,        "SUB_F64",          [Register(ReadWrite), Register(Read)];
+    MulF64 = 48,        "MUL_F64",          [Register(ReadWrite), Register(Read)];
+    DivF64 = 49,        "DIV_F64",          [Register(ReadWrite), Register(Read)];
+    ModF64 = 50,        "MOD_F64",          [Register(ReadWrite), Register(Read)];
+    EqF64 = 51,         "EQ_F64",           [Register(ReadWrite), Register(Read)];
+    NeF64 = 52,         "NE_F64",           [Register(ReadWrite), Register(Read)];
+    LtF64 = 53,         "LT_F64",           [Register(ReadWrite), Register(Read)];
+    LeF64 = 54,         "LE_F64",           [Register(ReadWrite), Register(Read)];
+    GtF64 = 55,         "GT_F64",           [Register(ReadWrite), Register(Read)];
+    GeF64 = 56,         "GE_F64",           [Register(ReadWrite), Register(Read)];
+    // Convert a number in place to another numeric type
+    ToI32 = 57,         "TO_I32",           [Register(ReadWrite)];
+    ToI64 = 58,         "TO_I64",           [Register(ReadWrite)];
+    ToU32 = 59,         "TO_U32",           [Register(ReadWrite)];
+    ToU64 = 60,         "TO_U64",           [Register(ReadWrite)];
+    ToF64 = 61,         "TO_F64",           [Register(ReadWrite)];
+    // Arrays live on the heap; indices are checked against their length
+    NewArray = 62,      "NEW_ARRAY",        [Register(Write)];
+    ArrayGet = 63,      "ARRAY_GET",        [Register(Write), Register(Read), Register(Read)];
+    ArraySet = 64,      "ARRAY_SET",        [Register(Read), Register(Read), Register(Read)];
+    ArrayPush = 65,     "ARRAY_PUSH",       [Register(Read), Register(Read)];
+    ArrayPop = 66,      "ARRAY_POP",        [Register(Write), Register(Read)];
+    ArrayLen = 67,      "ARRAY_LEN",        [Register(Write), Register(Read)];
+    ArraySlice = 68,    "ARRAY_SLICE",      [Register(Write), Register(Read), Register(Read), Register(Read)];
+    // CALL gives the callee a fresh register window; RET copies a value from
+    // it into the caller's destination register and returns after the CALL
+    Call = 69,          "CALL",             [Register(Write), Address];
+    Ret = 70,           "RET",              [Register(Read)];
+    // Closures: CLOSURE makes one for the code at a label, CAPTURE gives it
+    // an upvalue for a register of the current call and CAPTURE_UPVALUE one
+    // of the running closure's own upvalues, by index
+    Closure = 71,       "CLOSURE",          [Register(Write), Address];
+    Capture = 72,       "CAPTURE",          [Register(Read), Register(Read)];
+    CaptureUpvalue = 73, "CAPTURE_UPVALUE", [Register(Read), Immediate];
+    GetUpvalue = 74,    "GET_UPVALUE",      [Register(Write), Immediate];
+    SetUpvalue = 75,    "SET_UPVALUE",      [Register(Read), Immediate];
+    CloseUpvalue = 76,  "CLOSE_UPVALUE",    [Register(Read)];
+    CallClosure = 77,   "CALL_CLOSURE",     [Register(Write), Register(Read)];
+    // Like PRINT_REG, but followed by a space instead of the end of the line
+    PrintPart = 78,     "PRINT_PART",       [Register(Read)];
+}
+
+impl InstructionSet {
+    pub fn from_i32(value: i32) -> Option<Self> {
+        usize::try_from(value).ok().and_then(|i| OPCODES.get(i)).map(|info| info.instruction)
+    }
+
+    /// Look up an instruction by its assembly mnemonic
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Self> {
+        OPCODES.iter().find(|info| info.mnemonic == mnemonic).map(|info| info.instruction)
+    }
+
+    pub fn info(self) -> &'static OpcodeInfo {
+        &OPCODES[self as usize]
+    }
+
+    /// Assembly mnemonic
+    pub fn mnemonic(self) -> &'static str {
+        self.info().mnemonic
+    }
+
+    /// Kinds of the operand words following the opcode, in order
+    pub fn operands(self) -> &'static [OperandKind] {
+        self.info().operands
+    }
 
+    /// Number of operand words following the opcode
+    pub fn operand_count(self) -> usize {
+        self.operands().len()
+    }
 }
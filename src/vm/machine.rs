@@ -1,135 +1,758 @@
-use std::time::Instant;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::{self, Write};
+
 use super::instruction::InstructionSet;
+use super::bytecode::{Constant, Module};
+use super::assembler::quote_string;
+use super::heap::{Heap, ObjRef, Object, Upvalue, ELEMENT_SIZE, UPVALUE_SIZE};
+use super::value::Value;
 use super::executor::*;
+use super::error::{ExitStatus, VmError};
+use super::tracer::{NoopTracer, Tracer};
+
+pub const DEFAULT_NUM_REGISTERS: usize = 256;
+pub const DEFAULT_MEMORY_SIZE: usize = 1024;
+pub const DEFAULT_STACK_SIZE: usize = 1024;
+pub const DEFAULT_HEAP_LIMIT: usize = 64 * 1024 * 1024;
+pub const DEFAULT_CALL_DEPTH: usize = 1024;
 
-pub const NUM_REGISTERS: usize = 4;
-pub const MAX_PROGRAM_SIZE: usize = 256;
+/// Sizes of the register file, memory segments and heap, the instruction
+/// budget of a run and the integer overflow behavior, fixed when the VM is built
+#[derive(Debug, Clone, Copy)]
+pub struct VmConfig {
+    pub num_registers: usize,       // registers in each call's window
+    pub memory_size: usize,         // data memory words, addressed by LOAD/STORE
+    pub stack_size: usize,          // stack words, used by PUSH/POP
+    pub heap_limit: usize,          // bytes live heap objects may hold before HeapLimitExceeded
+    pub call_depth: usize,          // calls that may be active at once before CALL overflows
+    pub instruction_limit: Option<u64>, // instructions a run may execute before IterationLimit; None for no limit
+    pub wrapping_arithmetic: bool,  // integer overflow wraps around instead of raising IntegerOverflow
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        VmConfig {
+            num_registers: DEFAULT_NUM_REGISTERS,
+            memory_size: DEFAULT_MEMORY_SIZE,
+            stack_size: DEFAULT_STACK_SIZE,
+            heap_limit: DEFAULT_HEAP_LIMIT,
+            call_depth: DEFAULT_CALL_DEPTH,
+            instruction_limit: None,
+            wrapping_arithmetic: false,
+        }
+    }
+}
+
+/// An active call, pushed by CALL and popped by RET
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub call_pc: usize,     // address of the CALL instruction
+    pub return_pc: usize,   // where execution continues after RET
+    pub dest: usize,        // caller's register that receives the return value
+    pub closure: Option<ObjRef>,    // closure being called, whose upvalues the callee reads
+}
 
 // Virtual Machine structure
-#[derive(Debug)]
 pub struct VM {
-    pub registers: [i32; NUM_REGISTERS],      // Array for registers
+    pub registers: Vec<Value>,                // register file: one window of num_registers per active call
+    num_registers: usize,
+    pub frames: Vec<Frame>,                   // active calls, innermost last
+    call_depth: usize,
+    instruction_limit: Option<u64>,
     pub pc: usize,                            // program counter
-    pub program: [i32; MAX_PROGRAM_SIZE],     // program memory
+    pub instruction_pc: usize,                // address of the instruction being executed
+    pub program: Vec<i32>,                    // code segment, sized to the loaded program
+    pub constants: Vec<Value>,                // constant pool of the loaded module, read by LOAD_K
+    pub memory: Vec<Value>,                   // data memory segment
+    pub stack: Vec<Value>,                    // stack segment, grows up to stack_size
+    pub stack_size: usize,
+    pub heap: Heap,                           // garbage-collected strings, arrays and closures
+    open_upvalues: Vec<ObjRef>,               // upvalues still pointing at registers
+    pub wrapping_arithmetic: bool,           // see VmConfig::wrapping_arithmetic
     pub running: bool,                        // Running status
     pub instruction_count: u64,
+    breakpoints: BTreeSet<usize>,             // addresses to pause at before executing
+    watchpoints: BTreeSet<usize>,             // registers to pause after when their value changes
+    watch_hit: Option<(usize, Value, Value)>, // first watched write of the current instruction
+    paused_at: Option<usize>,                 // breakpoint the VM is paused on, skipped when resuming
+    tracer: Box<dyn Tracer>,                  // execution hooks, no-op by default
+    pub(crate) output: Box<dyn Write>,        // program output sink, stdout by default
 }
 
-// Implement methods for the InstructionSet enum
-impl InstructionSet {
-    pub fn from_i32(value: i32) -> Option<Self> {
-        match value {
-            0 => Some(InstructionSet::LoadConst),
-            1 => Some(InstructionSet::Mov),
-            2 => Some(InstructionSet::Add),
-            3 => Some(InstructionSet::Sub),
-            4 => Some(InstructionSet::Mul),
-            5 => Some(InstructionSet::Mod),
-            6 => Some(InstructionSet::Div),
-            7 => Some(InstructionSet::PrintReg),
-            8 => Some(InstructionSet::Halt),
-            9 => Some(InstructionSet::Jump),
-            10 => Some(InstructionSet::JumpIfNotZero),
-            _ => None,
+/// Adapts a line callback to `Write` for `VM::set_output_callback`
+struct CallbackWriter<F: FnMut(&str)> {
+    callback: F,
+    buffer: Vec<u8>,
+}
+
+impl<F: FnMut(&str)> Write for CallbackWriter<F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            (self.callback)(&String::from_utf8_lossy(&line[..end]));
         }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl fmt::Debug for VM {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VM")
+            .field("registers", &self.registers)
+            .field("pc", &self.pc)
+            .field("running", &self.running)
+            .field("instruction_count", &self.instruction_count)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 // Implement methods for the VM
 impl VM {
     pub fn new() -> Self {
+        Self::with_config(VmConfig::default())
+    }
+
+    pub fn with_config(config: VmConfig) -> Self {
         VM {
-            registers: [0; NUM_REGISTERS],
+            registers: vec![Value::Nil; config.num_registers],
+            num_registers: config.num_registers,
+            frames: Vec::new(),
+            call_depth: config.call_depth,
+            instruction_limit: config.instruction_limit,
             pc: 0,
-            program: [0; MAX_PROGRAM_SIZE],
+            instruction_pc: 0,
+            program: Vec::new(),
+            constants: Vec::new(),
+            memory: vec![Value::Nil; config.memory_size],
+            stack: Vec::with_capacity(config.stack_size),
+            stack_size: config.stack_size,
+            heap: Heap::with_limit(config.heap_limit),
+            open_upvalues: Vec::new(),
+            wrapping_arithmetic: config.wrapping_arithmetic,
             running: true,
             instruction_count: 0,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeSet::new(),
+            watch_hit: None,
+            paused_at: None,
+            tracer: Box::new(NoopTracer),
+            output: Box::new(io::stdout()),
+        }
+    }
+
+    /// The sizes this VM was built with
+    pub fn config(&self) -> VmConfig {
+        VmConfig {
+            num_registers: self.num_registers,
+            memory_size: self.memory.len(),
+            stack_size: self.stack_size,
+            heap_limit: self.heap.limit(),
+            call_depth: self.call_depth,
+            instruction_limit: self.instruction_limit,
+            wrapping_arithmetic: self.wrapping_arithmetic,
+        }
+    }
+
+    /// Send program output (PRINT instructions) to `output` instead of stdout
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    /// Call `callback` with each line of program output instead of printing it
+    pub fn set_output_callback(&mut self, callback: impl FnMut(&str) + 'static) {
+        self.output = Box::new(CallbackWriter { callback, buffer: Vec::new() });
+    }
+
+    /// Install a tracer; it replaces the current one
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /// Pause `run` and `run_until` before the instruction at `pc` executes
+    pub fn add_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.insert(pc);
+    }
+
+    /// Returns whether a breakpoint was set at `pc`
+    pub fn remove_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Pause after any instruction that changes the value of `register`
+    pub fn add_watchpoint(&mut self, register: usize) {
+        self.watchpoints.insert(register);
+    }
+
+    /// Returns whether `register` was watched
+    pub fn remove_watchpoint(&mut self, register: usize) -> bool {
+        self.watchpoints.remove(&register)
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.watchpoints.iter().copied()
+    }
+
+    /// Index in `registers` of the current call's R0; the outermost code
+    /// runs in the first window
+    pub fn frame_base(&self) -> usize {
+        self.frames.len() * self.num_registers
+    }
+
+    /// Value of a register, or None if the index is out of range. The index
+    /// is into the whole register file, not the current window.
+    pub fn register(&self, reg_idx: usize) -> Option<Value> {
+        self.registers.get(reg_idx).copied()
+    }
+
+    /// Write a register, reporting the change to the tracer and watchpoints
+    pub fn set_register(&mut self, reg_idx: usize, value: Value) {
+        let old = self.registers[reg_idx];
+        self.registers[reg_idx] = value;
+        self.tracer.on_register_write(reg_idx, old, value);
+        if old != value && self.watch_hit.is_none() && self.watchpoints.contains(&reg_idx) {
+            self.watch_hit = Some((reg_idx, old, value));
         }
     }
 
+    /// Transfer control to `addr`, reporting the jump to the tracer
+    pub fn jump(&mut self, addr: usize) {
+        self.tracer.on_jump(self.instruction_pc, addr);
+        self.pc = addr;
+    }
+
+    /// Call the code at `addr` in a new register window, as `closure` if it
+    /// is one. RET writes the return value to register `dest`, an index into
+    /// the whole file.
+    pub fn call(&mut self, addr: usize, dest: usize, closure: Option<ObjRef>) -> Result<(), VmError> {
+        if self.frames.len() >= self.call_depth {
+            return Err(VmError::StackOverflow { pc: self.instruction_pc, instruction: self.current_instruction() });
+        }
+        self.frames.push(Frame { call_pc: self.instruction_pc, return_pc: self.pc, dest, closure });
+        self.registers.resize(self.frame_base() + self.num_registers, Value::Nil);
+        self.tracer.on_call(self.instruction_pc, addr);
+        self.jump(addr);
+        Ok(())
+    }
+
+    /// Return `value` from the current call, dropping its register window
+    /// once the upvalues pointing into it are closed
+    pub fn ret(&mut self, value: Value) -> Result<(), VmError> {
+        if self.frames.is_empty() {
+            return Err(VmError::StackUnderflow { pc: self.instruction_pc, instruction: InstructionSet::Ret });
+        }
+        self.close_upvalues(self.frame_base());
+        let frame = self.frames.pop().expect("frames checked above");
+        self.registers.truncate(self.frame_base() + self.num_registers);
+        self.tracer.on_return(self.instruction_pc, frame.return_pc, value);
+        self.set_register(frame.dest, value);
+        self.jump(frame.return_pc);
+        Ok(())
+    }
+
+    /// Upvalue `index` of the closure the current call runs
+    pub fn upvalue(&self, index: usize) -> Result<ObjRef, VmError> {
+        let closure = self.frames.last().and_then(|frame| frame.closure);
+        closure
+            .and_then(|closure| self.heap.closure(closure).1.get(index).copied())
+            .ok_or_else(|| VmError::UpvalueOutOfBounds {
+                pc: self.instruction_pc,
+                instruction: self.current_instruction(),
+                index,
+            })
+    }
+
+    /// The upvalue for register `reg_idx`, an index into the whole file.
+    /// Closures capturing the same register share one upvalue while it is
+    /// open, so they see each other's writes.
+    pub fn capture(&mut self, reg_idx: usize) -> Result<ObjRef, VmError> {
+        let open = self.open_upvalues.iter()
+            .find(|&&upvalue| self.heap.upvalue(upvalue) == Upvalue::Open(reg_idx));
+        if let Some(&upvalue) = open {
+            return Ok(upvalue);
+        }
+        let upvalue = self.alloc(Object::Upvalue(Upvalue::Open(reg_idx)))?;
+        self.open_upvalues.push(upvalue);
+        Ok(upvalue)
+    }
+
+    /// Add an upvalue to a closure for the current instruction; see `reserve`
+    pub fn add_upvalue(&mut self, closure: ObjRef, upvalue: ObjRef) -> Result<(), VmError> {
+        self.reserve(UPVALUE_SIZE)?;
+        self.heap.add_upvalue(closure, upvalue);
+        Ok(())
+    }
+
+    pub fn read_upvalue(&self, upvalue: ObjRef) -> Value {
+        match self.heap.upvalue(upvalue) {
+            Upvalue::Open(reg_idx) => self.registers[reg_idx],
+            Upvalue::Closed(value) => value,
+        }
+    }
+
+    /// Write through an upvalue; an open one writes its register
+    pub fn write_upvalue(&mut self, upvalue: ObjRef, value: Value) {
+        match self.heap.upvalue(upvalue) {
+            Upvalue::Open(reg_idx) => self.set_register(reg_idx, value),
+            Upvalue::Closed(_) => self.heap.set_upvalue(upvalue, Upvalue::Closed(value)),
+        }
+    }
+
+    /// Close the open upvalue of register `reg_idx`, if there is one: it
+    /// keeps the register's current value, and the register can be reused
+    pub fn close_upvalue(&mut self, reg_idx: usize) {
+        self.close_upvalues_where(|reg| reg == reg_idx);
+    }
+
+    /// Close the open upvalues of registers `from` and up
+    fn close_upvalues(&mut self, from: usize) {
+        self.close_upvalues_where(|reg| reg >= from);
+    }
+
+    fn close_upvalues_where(&mut self, closes: impl Fn(usize) -> bool) {
+        let (heap, registers) = (&mut self.heap, &self.registers);
+        self.open_upvalues.retain(|&upvalue| match heap.upvalue(upvalue) {
+            Upvalue::Open(reg_idx) if closes(reg_idx) => {
+                heap.set_upvalue(upvalue, Upvalue::Closed(registers[reg_idx]));
+                false
+            }
+            _ => true,
+        });
+    }
+
+    /// Load a program and start it from the beginning. Registers, memory and
+    /// the stack keep their contents, so programs can build on earlier ones.
     pub fn load_program(&mut self, prog: &[i32]) {
-        if prog.len() > MAX_PROGRAM_SIZE {
-            eprintln!("Error: Program size {} exceeds maximum memory {}", prog.len(), MAX_PROGRAM_SIZE);
-            self.running = false;
-            return;
+        self.program = prog.to_vec();
+        self.constants.clear();
+        self.restart();
+    }
+
+    /// Load a compiled module, its code segment and constant pool, like
+    /// `load_program`. String constants are allocated on the heap; they
+    /// count toward its size but are not checked against the limit.
+    pub fn load_module(&mut self, module: &Module) {
+        self.program = module.code.clone();
+        self.constants = module.constants.iter()
+            .map(|constant| match constant {
+                Constant::I32(n) => Value::I32(*n),
+                Constant::I64(n) => Value::I64(*n),
+                Constant::U32(n) => Value::U32(*n),
+                Constant::U64(n) => Value::U64(*n),
+                Constant::F64(x) => Value::F64(*x),
+                Constant::Bool(b) => Value::Bool(*b),
+                Constant::Nil => Value::Nil,
+                Constant::Str(s) => Value::Str(self.heap.alloc(Object::Str(s.clone()))),
+            })
+            .collect();
+        self.restart();
+    }
+
+    /// Make room on the heap for `size` more bytes for the current
+    /// instruction. Garbage is collected first once the heap has grown
+    /// enough or would go over its limit; if the live objects would still go
+    /// over it, the instruction fails.
+    fn reserve(&mut self, size: usize) -> Result<(), VmError> {
+        if self.heap.needs_collection(size) || self.heap.exceeds_limit(size) {
+            self.collect_garbage();
+        }
+        if self.heap.exceeds_limit(size) {
+            return Err(VmError::HeapLimitExceeded {
+                pc: self.instruction_pc,
+                instruction: self.current_instruction(),
+                limit: self.heap.limit(),
+            });
         }
-        self.program[..prog.len()].copy_from_slice(prog);
+        Ok(())
     }
 
-    pub fn get_register(&mut self) -> Option<usize> {
-        if self.pc >= MAX_PROGRAM_SIZE {
-            eprintln!("Error: Program counter out of bounds: {}", self.pc);
-            self.running = false;
-            return None;
+    /// Allocate an object for the current instruction; see `reserve`
+    pub fn alloc(&mut self, object: Object) -> Result<ObjRef, VmError> {
+        self.reserve(object.size())?;
+        Ok(self.heap.alloc(object))
+    }
+
+    /// Append to an array for the current instruction; see `reserve`
+    pub fn push_element(&mut self, array: ObjRef, value: Value) -> Result<(), VmError> {
+        self.reserve(ELEMENT_SIZE)?;
+        self.heap.push(array, value);
+        Ok(())
+    }
+
+    /// Free every heap object that is no longer reachable from registers of
+    /// any active call, the closures being called, open upvalues, memory, the
+    /// stack or the constant pool
+    pub fn collect_garbage(&mut self) {
+        let roots = self.registers.iter()
+            .chain(&self.memory)
+            .chain(&self.stack)
+            .chain(&self.constants)
+            .filter_map(|value| value.object())
+            .chain(self.frames.iter().filter_map(|frame| frame.closure))
+            .chain(self.open_upvalues.iter().copied());
+        self.heap.collect(roots);
+    }
+
+    /// A value as PRINT_REG shows it, with strings and arrays read from the
+    /// heap. Strings inside arrays are quoted.
+    pub fn format_value(&self, value: Value) -> String {
+        match value {
+            Value::Str(obj) => self.heap.string(obj).to_string(),
+            other => self.format_element(other, &mut Vec::new()),
         }
-        let reg_idx = self.program[self.pc] as usize;
-        self.pc += 1;
-        Some(reg_idx)
     }
 
-    pub fn get_immediate(&mut self) -> Option<i32> {
-        if self.pc >= MAX_PROGRAM_SIZE {
-            eprintln!("Error: Program counter out of bounds: {}", self.pc);
-            self.running = false;
-            return None;
+    /// `open` holds the arrays being formatted, so that an array containing
+    /// itself is shown as `[...]` there
+    fn format_element(&self, value: Value, open: &mut Vec<ObjRef>) -> String {
+        match value {
+            Value::Str(obj) => quote_string(self.heap.string(obj)),
+            Value::Array(obj) if open.contains(&obj) => "[...]".to_string(),
+            Value::Array(obj) => {
+                open.push(obj);
+                let elements: Vec<String> = self.heap.array(obj).iter()
+                    .map(|element| self.format_element(*element, open))
+                    .collect();
+                open.pop();
+                format!("[{}]", elements.join(", "))
+            }
+            other => other.to_string(),
+        }
+    }
+
+    fn restart(&mut self) {
+        // A program that failed inside a call leaves its frames behind
+        self.close_upvalues(self.num_registers);
+        self.frames.clear();
+        self.registers.truncate(self.num_registers);
+        self.pc = 0;
+        self.instruction_pc = 0;
+        self.running = true;
+        self.instruction_count = 0;
+        self.paused_at = None;
+    }
+
+    /// The instruction currently being executed; only valid while one is
+    fn current_instruction(&self) -> InstructionSet {
+        InstructionSet::from_i32(self.program[self.instruction_pc]).expect("current instruction was decoded")
+    }
+
+    /// Fetch the next word of the current instruction
+    fn fetch_operand(&mut self) -> Result<i32, VmError> {
+        if self.pc >= self.program.len() {
+            return Err(VmError::PcOutOfBounds { pc: self.instruction_pc, instruction: self.current_instruction() });
         }
         let value = self.program[self.pc];
         self.pc += 1;
-        Some(value)
+        Ok(value)
+    }
+
+    /// Fetch a register operand, checked against the register window, and
+    /// return its index in the whole register file
+    pub fn get_register(&mut self) -> Result<usize, VmError> {
+        let reg_idx = self.fetch_operand()? as usize;
+        if reg_idx >= self.num_registers {
+            return Err(VmError::InvalidRegister {
+                pc: self.instruction_pc,
+                instruction: self.current_instruction(),
+                register: reg_idx,
+            });
+        }
+        Ok(self.frame_base() + reg_idx)
+    }
+
+    pub fn get_immediate(&mut self) -> Result<i32, VmError> {
+        self.fetch_operand()
+    }
+
+    /// Fetch a jump target, checked against the loaded program. The end of
+    /// the program is a target too, as the loader accepts: execution ends
+    /// there as it does after the last instruction.
+    pub fn get_jump_target(&mut self) -> Result<usize, VmError> {
+        let addr = self.fetch_operand()? as usize;
+        if addr > self.program.len() {
+            return Err(VmError::JumpOutOfBounds {
+                pc: self.instruction_pc,
+                instruction: self.current_instruction(),
+                target: addr,
+            });
+        }
+        Ok(addr)
+    }
+
+    /// Fetch a data memory address, checked against the memory segment
+    pub fn get_memory_address(&mut self) -> Result<usize, VmError> {
+        let addr = self.fetch_operand()? as usize;
+        if addr >= self.memory.len() {
+            return Err(VmError::MemoryOutOfBounds {
+                pc: self.instruction_pc,
+                instruction: self.current_instruction(),
+                address: addr,
+            });
+        }
+        Ok(addr)
+    }
+
+    /// Fetch a constant pool index, checked against the loaded pool
+    pub fn get_constant(&mut self) -> Result<Value, VmError> {
+        let index = self.fetch_operand()? as usize;
+        self.constants.get(index).copied().ok_or_else(|| VmError::ConstantOutOfBounds {
+            pc: self.instruction_pc,
+            instruction: self.current_instruction(),
+            index,
+        })
     }
 
     fn fetch(&self) -> i32 {
         self.program[self.pc]
     }
 
-    fn execute(&mut self) {
-        if self.pc >= MAX_PROGRAM_SIZE {
-            eprintln!("Error: Program counter out of bounds: {}", self.pc);
+    /// Execute a single instruction. Breakpoints are not checked; a watched
+    /// register change is reported as `ExitStatus::Watchpoint`.
+    pub fn step(&mut self) -> Result<ExitStatus, VmError> {
+        self.paused_at = None;
+        if !self.running {
+            return Ok(ExitStatus::Halted);
+        }
+        if self.pc >= self.program.len() {
             self.running = false;
-            return;
+            return Ok(ExitStatus::EndOfProgram);
         }
 
         let instruction = match InstructionSet::from_i32(self.fetch()) {
             Some(i) => i,
             None => {
-                eprintln!("Error: Unknown instruction {} at PC={}", self.fetch(), self.pc);
                 self.running = false;
-                return;
+                return Err(VmError::UnknownOpcode { pc: self.pc, opcode: self.fetch() });
             }
         };
+        self.instruction_pc = self.pc;
+        let operands_end = (self.pc + 1 + instruction.operand_count()).min(self.program.len());
+        self.tracer.on_instruction(self.pc, instruction, &self.program[self.pc + 1..operands_end]);
         self.pc += 1;
         self.instruction_count += 1;
 
-        execute_instruction(self, instruction);
-    }
-
-    /// Run the virtual machine iteratively to avoid recursion
-    pub fn run(&mut self) {
-        println!("--- VM Start ---");
-        let start = Instant::now(); // Start timing
-        
-        // Use a loop instead of recursion
-        const MAX_ITERATIONS: usize = 1_000_000; // Prevent infinite loops
-        
-        while self.running && self.pc < MAX_PROGRAM_SIZE && self.instruction_count < MAX_ITERATIONS as u64 {
-            self.execute();
-        }
-        
-        if self.instruction_count >= MAX_ITERATIONS as u64 {
-            eprintln!("VM stopped: Reached maximum iteration limit");
-        }
-        
-        let elapsed = start.elapsed(); // Get elapsed time
-        println!("--- VM End ---");
-        println!("Execution time: {:.6} seconds", elapsed.as_secs_f64());
-        println!("Total instructions executed: {}", self.instruction_count);
-        if elapsed.as_secs_f64() > 0.0 {
-            println!(
-                "Instructions per second: {:.0}",
-                self.instruction_count as f64 / elapsed.as_secs_f64()
-            );
+        self.watch_hit = None;
+        if let Err(err) = execute_instruction(self, instruction) {
+            self.running = false;
+            return Err(err);
+        }
+
+        if !self.running {
+            Ok(ExitStatus::Halted)
+        } else if let Some((register, old, new)) = self.watch_hit.take() {
+            Ok(ExitStatus::Watchpoint { register, old, new })
         } else {
-            println!("Execution time too short to calculate instructions per second");
+            Ok(ExitStatus::Running)
+        }
+    }
+
+    /// Run the virtual machine iteratively to avoid recursion. Stops at
+    /// breakpoints and watchpoints; calling it again resumes.
+    pub fn run(&mut self) -> Result<ExitStatus, VmError> {
+        self.run_to(None)
+    }
+
+    /// Like `run`, but also pauses before the instruction at `pc`
+    pub fn run_until(&mut self, pc: usize) -> Result<ExitStatus, VmError> {
+        self.run_to(Some(pc))
+    }
+
+    fn run_to(&mut self, stop: Option<usize>) -> Result<ExitStatus, VmError> {
+        // Resuming from a breakpoint must not stop on it again
+        let mut resume_from = self.paused_at.take();
+        loop {
+            let at_stop = stop == Some(self.pc) || self.breakpoints.contains(&self.pc);
+            if self.running && at_stop && resume_from.take() != Some(self.pc) {
+                self.paused_at = Some(self.pc);
+                return Ok(ExitStatus::Breakpoint);
+            }
+            resume_from = None;
+            match (self.step(), self.instruction_limit) {
+                (Ok(ExitStatus::Running), Some(limit)) if self.instruction_count >= limit => {
+                    self.running = false;
+                    return Err(VmError::IterationLimit { pc: self.instruction_pc, instruction: self.current_instruction(), limit });
+                }
+                (Ok(ExitStatus::Running), _) => continue,
+                (other, _) => return other,
+            }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::assembler::assemble;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn vm(asm: &str) -> VM {
+        let mut vm = VM::new();
+        vm.set_output(Box::new(io::sink()));
+        vm.load_module(&assemble(asm).expect("test program assembles"));
+        vm
+    }
+
+    #[test]
+    fn step_through_a_program() {
+        let mut vm = vm("LOAD_CONST R0, 2\nLOAD_CONST R1, 3\nADD R0, R1\nHALT");
+        assert_eq!(vm.pc, 0);
+
+        assert_eq!(vm.step(), Ok(ExitStatus::Running));
+        assert_eq!((vm.instruction_pc, vm.pc), (0, 3));
+        assert_eq!(vm.register(0), Some(Value::I32(2)));
+        assert_eq!(vm.register(1), Some(Value::Nil));
+
+        assert_eq!(vm.step(), Ok(ExitStatus::Running));
+        assert_eq!(vm.step(), Ok(ExitStatus::Running));
+        assert_eq!((vm.instruction_pc, vm.pc), (6, 9));
+        assert_eq!(vm.register(0), Some(Value::I32(5)));
+        assert_eq!(vm.register(1), Some(Value::I32(3)));
+
+        assert_eq!(vm.step(), Ok(ExitStatus::Halted));
+        assert_eq!(vm.step(), Ok(ExitStatus::Halted));
+        assert_eq!(vm.instruction_count, 4);
+    }
+
+    #[test]
+    fn step_past_the_last_instruction() {
+        let mut vm = vm("LOAD_CONST R0, 1");
+        assert_eq!(vm.step(), Ok(ExitStatus::Running));
+        assert_eq!(vm.step(), Ok(ExitStatus::EndOfProgram));
+    }
+
+    #[test]
+    fn jumps_move_the_program_counter() {
+        let mut vm = vm("LOAD_CONST R0, 0\nJMP_IF_ZERO R0, skip\nLOAD_CONST R1, 1\nskip:\nHALT");
+        vm.step().unwrap();
+        assert_eq!(vm.step(), Ok(ExitStatus::Running));
+        assert_eq!(vm.pc, 9);
+        assert_eq!(vm.register(1), Some(Value::Nil));
+    }
+
+    #[test]
+    fn jump_to_the_end_of_the_program() {
+        let module = assemble("JMP end\nLOAD_CONST R0, 1\nend:").unwrap();
+        let module = Module::from_bytes(&module.to_bytes()).expect("loader accepts the jump");
+        let mut vm = VM::new();
+        vm.load_module(&module);
+        assert_eq!(vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert_eq!(vm.register(0), Some(Value::Nil));
+    }
+
+    #[test]
+    fn instruction_limit_stops_at_the_instruction_that_reached_it() {
+        let module = assemble("loop:\nLOAD_CONST R0, 1\nJMP loop").unwrap();
+        let mut vm = VM::with_config(VmConfig { instruction_limit: Some(5), ..VmConfig::default() });
+        vm.load_module(&module);
+        assert_eq!(vm.run(), Err(VmError::IterationLimit { pc: 0, instruction: InstructionSet::LoadConst, limit: 5 }));
+        assert_eq!(vm.pc, 3);
+
+        // Without a limit, a run goes on as long as the program does
+        let mut vm = VM::new();
+        vm.load_module(&assemble("LOAD_CONST R0, 0\nLOAD_CONST R1, 1\nLOAD_CONST R2, 600000\nloop:\nADD R0, R1\nJMP_IF_LESS R0, R2, loop").unwrap());
+        assert_eq!(vm.run(), Ok(ExitStatus::EndOfProgram));
+        assert!(vm.instruction_count > 1_000_000);
+    }
+
+    #[test]
+    fn load_and_store_move_values_through_memory() {
+        let mut vm = vm("LOAD_CONST R0, 42\nSTORE R0, 5\nLOAD R1, 5\nLOAD R2, 6\nHALT");
+        assert_eq!(vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(vm.memory[5], Value::I32(42));
+        assert_eq!(vm.register(1), Some(Value::I32(42)));
+        assert_eq!(vm.register(2), Some(Value::Nil));
+
+        let module = assemble("LOAD_CONST R0, 1\nSTORE R0, 3\nSTORE R0, 4\nHALT").unwrap();
+        let mut vm = VM::with_config(VmConfig { memory_size: 4, ..VmConfig::default() });
+        vm.load_module(&module);
+        assert_eq!(vm.run(), Err(VmError::MemoryOutOfBounds { pc: 6, instruction: InstructionSet::Store, address: 4 }));
+        assert_eq!(vm.memory[3], Value::I32(1));
+    }
+
+    #[test]
+    fn registers_beyond_a_configured_window_are_invalid() {
+        let config = VmConfig { num_registers: 8, ..VmConfig::default() };
+        let module = assemble("JMP main\nf:\nLOAD_CONST R7, 9\nRET R7\nmain:\nCALL R7, f\nLOAD_CONST R8, 1\nHALT").unwrap();
+        let mut vm = VM::with_config(config);
+        vm.load_module(&module);
+        assert_eq!(vm.run(), Err(VmError::InvalidRegister { pc: 10, instruction: InstructionSet::LoadConst, register: 8 }));
+        assert_eq!(vm.register(7), Some(Value::I32(9)));
+        assert_eq!(vm.registers.len(), 8);
+
+        // A call's window starts where the caller's ends
+        vm.load_module(&module);
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.frame_base(), 8);
+        assert_eq!(vm.registers.len(), 16);
+    }
+
+    #[test]
+    fn calls_get_a_register_window_of_their_own() {
+        let mut vm = vm("JMP main\nf:\nLOAD_CONST R0, 7\nRET R0\nmain:\nLOAD_CONST R0, 1\nCALL R1, f\nHALT");
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.step(), Ok(ExitStatus::Running));
+        assert_eq!(vm.pc, 2);
+        assert_eq!(vm.frames.len(), 1);
+        assert_eq!(vm.frame_base(), vm.num_registers);
+
+        vm.step().unwrap();
+        assert_eq!(vm.register(vm.frame_base()), Some(Value::I32(7)));
+        assert_eq!(vm.register(0), Some(Value::I32(1)));
+
+        vm.step().unwrap();
+        assert_eq!(vm.pc, 13);
+        assert!(vm.frames.is_empty());
+        assert_eq!(vm.register(1), Some(Value::I32(7)));
+        assert_eq!(vm.registers.len(), vm.num_registers);
+    }
+
+    #[test]
+    fn output_callback_receives_each_printed_line() {
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::new();
+        let sink = Rc::clone(&lines);
+        vm.set_output_callback(move |line| sink.borrow_mut().push(line.to_string()));
+        vm.load_module(&assemble("LOAD_K R0, \"sum\"\nLOAD_CONST R1, 2\nLOAD_CONST R2, 3\nADD R1, R2\nPRINT_PART R0\nPRINT_REG R1\nLOAD_K R0, \"a\\nb\"\nPRINT_REG R0\nHALT").unwrap());
+        assert_eq!(vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(*lines.borrow(), ["sum 5", "a", "b"]);
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints_pause_run() {
+        let mut vm = vm("LOAD_CONST R0, 1\nLOAD_CONST R1, 2\nLOAD_CONST R1, 3\nHALT");
+        vm.add_breakpoint(3);
+        assert_eq!(vm.run(), Ok(ExitStatus::Breakpoint));
+        assert_eq!(vm.pc, 3);
+        assert_eq!(vm.register(0), Some(Value::I32(1)));
+
+        vm.add_watchpoint(1);
+        let status = vm.run();
+        assert_eq!(status, Ok(ExitStatus::Watchpoint { register: 1, old: Value::Nil, new: Value::I32(2) }));
+        assert_eq!(vm.pc, 6);
+
+        vm.remove_watchpoint(1);
+        assert_eq!(vm.run_until(9), Ok(ExitStatus::Breakpoint));
+        assert_eq!(vm.pc, 9);
+        assert_eq!(vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(vm.register(1), Some(Value::I32(3)));
+    }
+}
@@ -2,3 +2,9 @@ pub mod instruction;
 pub mod machine;
 pub mod assembler;
 pub mod executor;
+pub mod error;
+pub mod tracer;
+pub mod bytecode;
+pub mod disassembler;
+pub mod value;
+pub mod heap;
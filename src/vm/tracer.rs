@@ -0,0 +1,142 @@
+use std::io::{self, Write};
+
+use super::instruction::InstructionSet;
+use super::value::Value;
+
+/// Hooks called by the VM while it executes. Every method defaults to doing
+/// nothing, so implementations only override what they need.
+pub trait Tracer {
+    /// Called before an instruction executes, with its operand words
+    fn on_instruction(&mut self, _pc: usize, _instruction: InstructionSet, _operands: &[i32]) {}
+
+    /// Called after a register is written
+    fn on_register_write(&mut self, _register: usize, _old: Value, _new: Value) {}
+
+    /// Called when a jump is taken
+    fn on_jump(&mut self, _from: usize, _to: usize) {}
+
+    /// Called when a call at `from` enters the code at `to`, before the jump
+    fn on_call(&mut self, _from: usize, _to: usize) {}
+
+    /// Called when a RET at `from` returns `value` to the caller at `to`,
+    /// before the value is written and the jump taken
+    fn on_return(&mut self, _from: usize, _to: usize, _value: Value) {}
+}
+
+/// The default tracer: ignores everything
+pub struct NoopTracer;
+
+impl Tracer for NoopTracer {}
+
+/// Writes a human-readable execution trace, one line per event
+pub struct PrettyTracer<W: Write> {
+    out: W,
+}
+
+impl PrettyTracer<io::Stderr> {
+    pub fn stderr() -> Self {
+        PrettyTracer { out: io::stderr() }
+    }
+}
+
+impl<W: Write> PrettyTracer<W> {
+    pub fn new(out: W) -> Self {
+        PrettyTracer { out }
+    }
+}
+
+// Trace output is best-effort: a failed write must not stop the program
+impl<W: Write> Tracer for PrettyTracer<W> {
+    fn on_instruction(&mut self, pc: usize, instruction: InstructionSet, operands: &[i32]) {
+        let operands: Vec<String> = operands.iter().map(|op| op.to_string()).collect();
+        let _ = writeln!(self.out, "[{:04}] {} {}", pc, instruction.mnemonic(), operands.join(", "));
+    }
+
+    fn on_register_write(&mut self, register: usize, old: Value, new: Value) {
+        let _ = writeln!(self.out, "       R{}: {} -> {}", register, old, new);
+    }
+
+    fn on_jump(&mut self, from: usize, to: usize) {
+        let _ = writeln!(self.out, "       jump {} -> {}", from, to);
+    }
+
+    fn on_call(&mut self, from: usize, to: usize) {
+        let _ = writeln!(self.out, "       call {} -> {}", from, to);
+    }
+
+    fn on_return(&mut self, from: usize, to: usize, value: Value) {
+        let _ = writeln!(self.out, "       return {} from {} -> {}", value, from, to);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::vm::assembler::assemble;
+    use crate::vm::error::ExitStatus;
+    use crate::vm::machine::VM;
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Instruction(usize, InstructionSet, Vec<i32>),
+        RegisterWrite(usize, Value, Value),
+        Jump(usize, usize),
+        Call(usize, usize),
+        Return(usize, usize, Value),
+    }
+
+    /// Records every event into a log the test keeps a handle to
+    struct Recorder(Rc<RefCell<Vec<Event>>>);
+
+    impl Tracer for Recorder {
+        fn on_instruction(&mut self, pc: usize, instruction: InstructionSet, operands: &[i32]) {
+            self.0.borrow_mut().push(Event::Instruction(pc, instruction, operands.to_vec()));
+        }
+
+        fn on_register_write(&mut self, register: usize, old: Value, new: Value) {
+            self.0.borrow_mut().push(Event::RegisterWrite(register, old, new));
+        }
+
+        fn on_jump(&mut self, from: usize, to: usize) {
+            self.0.borrow_mut().push(Event::Jump(from, to));
+        }
+
+        fn on_call(&mut self, from: usize, to: usize) {
+            self.0.borrow_mut().push(Event::Call(from, to));
+        }
+
+        fn on_return(&mut self, from: usize, to: usize, value: Value) {
+            self.0.borrow_mut().push(Event::Return(from, to, value));
+        }
+    }
+
+    #[test]
+    fn tracer_sees_instructions_calls_and_returns_in_order() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::new();
+        vm.set_tracer(Box::new(Recorder(Rc::clone(&events))));
+        vm.load_module(&assemble("JMP main\nf:\nLOAD_CONST R0, 7\nRET R0\nmain:\nCALL R1, f\nHALT").unwrap());
+        assert_eq!(vm.run(), Ok(ExitStatus::Halted));
+
+        let callee_r0 = vm.config().num_registers;
+        assert_eq!(
+            *events.borrow(),
+            [
+                Event::Instruction(0, InstructionSet::Jump, vec![7]),
+                Event::Jump(0, 7),
+                Event::Instruction(7, InstructionSet::Call, vec![1, 2]),
+                Event::Call(7, 2),
+                Event::Jump(7, 2),
+                Event::Instruction(2, InstructionSet::LoadConst, vec![0, 7]),
+                Event::RegisterWrite(callee_r0, Value::Nil, Value::I32(7)),
+                Event::Instruction(5, InstructionSet::Ret, vec![0]),
+                Event::Return(5, 10, Value::I32(7)),
+                Event::RegisterWrite(1, Value::Nil, Value::I32(7)),
+                Event::Jump(5, 10),
+                Event::Instruction(10, InstructionSet::Halt, vec![]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use std::fmt;
+
+use super::heap::ObjRef;
+
+/// A register, memory or stack value. Strings, arrays and closures live on
+/// the VM's heap and are held by reference.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Value {
+    I32(i32),
+    I64(i64),
+    U32(u32),
+    U64(u64),
+    F64(f64),
+    Bool(bool),
+    #[default]
+    Nil,
+    Str(ObjRef),
+    Array(ObjRef),
+    Closure(ObjRef),
+}
+
+/// Type of a value, as written in source code and error messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ValueType {
+    I32,
+    I64,
+    U32,
+    U64,
+    F64,
+    Bool,
+    Nil,
+    Str,
+    Array,
+    Function,
+}
+
+impl ValueType {
+    /// Every type name accepted in annotations and literal suffixes; array
+    /// and function types are written `[i32]` and `fn(i32) -> i32` instead
+    pub const ALL: [ValueType; 8] = [
+        ValueType::I32,
+        ValueType::I64,
+        ValueType::U32,
+        ValueType::U64,
+        ValueType::F64,
+        ValueType::Bool,
+        ValueType::Nil,
+        ValueType::Str,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ValueType::I32 => "i32",
+            ValueType::I64 => "i64",
+            ValueType::U32 => "u32",
+            ValueType::U64 => "u64",
+            ValueType::F64 => "f64",
+            ValueType::Bool => "bool",
+            ValueType::Nil => "nil",
+            ValueType::Str => "string",
+            ValueType::Array => "array",
+            ValueType::Function => "function",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|ty| ty.name() == name)
+    }
+
+    pub fn is_integer(self) -> bool {
+        matches!(self, ValueType::I32 | ValueType::I64 | ValueType::U32 | ValueType::U64)
+    }
+
+    pub fn is_numeric(self) -> bool {
+        self.is_integer() || self == ValueType::F64
+    }
+
+    /// Integer value of this type, or None if it is out of range or the
+    /// type is not an integer type
+    pub fn integer(self, value: i128) -> Option<Value> {
+        match self {
+            ValueType::I32 => i32::try_from(value).ok().map(Value::I32),
+            ValueType::I64 => i64::try_from(value).ok().map(Value::I64),
+            ValueType::U32 => u32::try_from(value).ok().map(Value::U32),
+            ValueType::U64 => u64::try_from(value).ok().map(Value::U64),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl Value {
+    pub fn value_type(self) -> ValueType {
+        match self {
+            Value::I32(_) => ValueType::I32,
+            Value::I64(_) => ValueType::I64,
+            Value::U32(_) => ValueType::U32,
+            Value::U64(_) => ValueType::U64,
+            Value::F64(_) => ValueType::F64,
+            Value::Bool(_) => ValueType::Bool,
+            Value::Nil => ValueType::Nil,
+            Value::Str(_) => ValueType::Str,
+            Value::Array(_) => ValueType::Array,
+            Value::Closure(_) => ValueType::Function,
+        }
+    }
+
+    /// Type name used in error messages
+    pub fn type_name(self) -> &'static str {
+        self.value_type().name()
+    }
+
+    /// Value of an integer of any type
+    pub fn as_integer(self) -> Option<i128> {
+        match self {
+            Value::I32(n) => Some(n as i128),
+            Value::I64(n) => Some(n as i128),
+            Value::U32(n) => Some(n as i128),
+            Value::U64(n) => Some(n as i128),
+            _ => None,
+        }
+    }
+
+    /// The heap object a string, array or closure value refers to
+    pub fn object(self) -> Option<ObjRef> {
+        match self {
+            Value::Str(obj) | Value::Array(obj) | Value::Closure(obj) => Some(obj),
+            _ => None,
+        }
+    }
+
+    /// How conditional jumps and NOT see the value: false, nil and zero are
+    /// false; every string, array and closure is true
+    pub fn is_truthy(self) -> bool {
+        match self {
+            Value::I32(n) => n != 0,
+            Value::I64(n) => n != 0,
+            Value::U32(n) => n != 0,
+            Value::U64(n) => n != 0,
+            Value::F64(x) => x != 0.0,
+            Value::Bool(b) => b,
+            Value::Nil => false,
+            Value::Str(_) | Value::Array(_) | Value::Closure(_) => true,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::I32(n) => write!(f, "{}", n),
+            Value::I64(n) => write!(f, "{}", n),
+            Value::U32(n) => write!(f, "{}", n),
+            Value::U64(n) => write!(f, "{}", n),
+            Value::F64(x) if x.is_nan() => write!(f, "nan"),
+            // Very large and very small magnitudes use scientific notation
+            Value::F64(x) if x.is_finite() && *x != 0.0 && !(1e-5..1e16).contains(&x.abs()) => {
+                write!(f, "{:e}", x)
+            }
+            Value::F64(x) => write!(f, "{}", x),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Nil => write!(f, "nil"),
+            // The contents are on the heap; see `VM::format_value`
+            Value::Str(obj) => write!(f, "<string #{}>", obj.index()),
+            Value::Array(obj) => write!(f, "<array #{}>", obj.index()),
+            Value::Closure(obj) => write!(f, "<function #{}>", obj.index()),
+        }
+    }
+}
+
+impl From<i32> for Value {
+    fn from(n: i32) -> Self {
+        Value::I32(n)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::I64(n)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(n: u32) -> Self {
+        Value::U32(n)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(n: u64) -> Self {
+        Value::U64(n)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(x: f64) -> Self {
+        Value::F64(x)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}